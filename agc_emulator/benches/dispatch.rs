@@ -0,0 +1,33 @@
+// Compares the instruction rate of `execute` against the predecoded fast path.
+// Runs the program loaded in fixed memory from a fresh start each time, so results depend on the current
+// rope. Run with `cargo bench`, and with `cargo bench --features single-thread` for non-atomic memory
+use std::time::Instant;
+
+use agc_emulator::instructions::execute;
+use agc_emulator::memory::*;
+use agc_emulator::predecode::Predecoded;
+use agc_emulator::restart::fresh_start;
+
+const INSTRUCTIONS: u32 = 5_000_000;
+
+fn report(name: &str, seconds: f64) {
+    let mips = INSTRUCTIONS as f64 / seconds / 1e6;
+    println!("{:<12} {:>10.3} s {:>10.2} MIPS", name, seconds, mips);
+}
+
+fn main() {
+    fresh_start();
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        execute(MEMORY.read(MEMORY.read(Z)));
+    }
+    report("execute", start.elapsed().as_secs_f64());
+
+    fresh_start();
+    let mut fast = Box::new(Predecoded::new());
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        fast.step();
+    }
+    report("predecoded", start.elapsed().as_secs_f64());
+}
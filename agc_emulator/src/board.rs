@@ -0,0 +1,93 @@
+use crate::memory::{Address, ErasableAddress};
+
+// The board the rope runs on: the names it gives to erasable memory, and where each kind of memory is.
+// The assembler predefines these names and gives out erasable memory around them, and the emulator and
+// the frontends map their devices to them and show them by name
+
+// Central registers
+pub const ACC: ErasableAddress = 0;
+pub const L: ErasableAddress = 1;
+pub const Q: ErasableAddress = 2;
+pub const EB: ErasableAddress = 3;
+pub const FB: ErasableAddress = 4;
+pub const Z: ErasableAddress = 5;
+pub const BB: ErasableAddress = 6;
+pub const ZERO: ErasableAddress = 7;
+
+// Peripherals: the 8x8 LED matrix, a row a word, the buttons, the potentiometer and the three delays the
+// programs wait with
+pub const PANT: ErasableAddress = 256;
+pub const BTNUP: ErasableAddress = 264;
+pub const BTNRGT: ErasableAddress = 265;
+pub const BTNDWN: ErasableAddress = 266;
+pub const BTNLFT: ErasableAddress = 267;
+pub const BTN1: ErasableAddress = 268;
+pub const BTN2: ErasableAddress = 269;
+pub const POTE: ErasableAddress = 270;
+pub const CORTO: ErasableAddress = 271;
+pub const MEDIO: ErasableAddress = 272;
+pub const LARGO: ErasableAddress = 273;
+
+// Erasable memory after the registers and counters, the peripherals in it, and the words left for the
+// rope's variables, from RAM_START on
+pub const ERASABLE_START: ErasableAddress = 48;
+pub const PERIPHERALS: ErasableAddress = PANT;
+pub const RAM_START: ErasableAddress = LARGO + 1;
+pub const ERASABLE_END: ErasableAddress = 512;
+// Fixed memory starts with the fixed-fixed banks 2 and 3, where the program starts. FB switches in the
+// banks up to FIXED_BANKS
+pub const FIXED_START: Address = 2048;
+pub const FIXED_BANKS: u16 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Register,
+    Peripheral,
+}
+
+// A name of the board, `len` words from `address` on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Name {
+    pub name: &'static str,
+    pub address: ErasableAddress,
+    pub len: u16,
+    pub kind: Kind,
+}
+impl Name {
+    const fn register(name: &'static str, address: ErasableAddress) -> Self {
+        Self { name, address, len: 1, kind: Kind::Register }
+    }
+
+    const fn peripheral(name: &'static str, address: ErasableAddress, len: u16) -> Self {
+        Self { name, address, len, kind: Kind::Peripheral }
+    }
+}
+
+pub const NAMES: [Name; 19] = [
+    Name::register("ACC", ACC),
+    Name::register("L", L),
+    Name::register("Q", Q),
+    Name::register("EB", EB),
+    Name::register("FB", FB),
+    Name::register("Z", Z),
+    Name::register("BB", BB),
+    Name::register("ZERO", ZERO),
+    Name::peripheral("PANT", PANT, 8),
+    Name::peripheral("BTNUP", BTNUP, 1),
+    Name::peripheral("BTNRGT", BTNRGT, 1),
+    Name::peripheral("BTNDWN", BTNDWN, 1),
+    Name::peripheral("BTNLFT", BTNLFT, 1),
+    Name::peripheral("BTN1", BTN1, 1),
+    Name::peripheral("BTN2", BTN2, 1),
+    Name::peripheral("POTE", POTE, 1),
+    Name::peripheral("CORTO", CORTO, 1),
+    Name::peripheral("MEDIO", MEDIO, 1),
+    Name::peripheral("LARGO", LARGO, 1),
+];
+
+// The name the word at `address` is part of, and its place in it
+pub fn name_at(address: ErasableAddress) -> Option<(&'static Name, u16)> {
+    NAMES.iter()
+        .find(|name| (name.address..name.address + name.len).contains(&address))
+        .map(|name| (name, address - name.address))
+}
@@ -0,0 +1,185 @@
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use crate::memory::*;
+use crate::restart::time;
+
+// How many faults can be scheduled or active at the same time
+const FAULT_SLOTS: usize = 8;
+
+// Hardware faults that can be injected into a running machine, to see how programs and the
+// restart hardware cope with them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    // Flips one bit of a word as stored. Bits are numbered 1 to 16, 16 being the parity bit
+    FlipBit { address: Address, bit: u8 },
+    // The cell takes `value` and keeps it: every later write is lost
+    Stuck { address: Address, value: Word },
+    // The next `count` increments of a counter (TIME1 or TIME6) are lost
+    DropIncrements { counter: Address, count: u16 },
+}
+impl Fault {
+    const fn id(self) -> u8 {
+        match self {
+            Fault::FlipBit { .. } => FLIP_BIT,
+            Fault::Stuck { .. } => STUCK,
+            Fault::DropIncrements { .. } => DROP_INCREMENTS,
+        }
+    }
+
+    const fn address(self) -> Address {
+        match self {
+            Fault::FlipBit { address, .. } | Fault::Stuck { address, .. } => address & 0x0FFF,
+            Fault::DropIncrements { counter, .. } => counter & 0x0FFF,
+        }
+    }
+
+    const fn value(self) -> Word {
+        match self {
+            Fault::FlipBit { bit, .. } => 1 << (bit - 1),
+            Fault::Stuck { value, .. } => value,
+            Fault::DropIncrements { count, .. } => count,
+        }
+    }
+}
+
+// Fault kinds
+const FLIP_BIT: u8 = 1;
+const STUCK: u8 = 2;
+const DROP_INCREMENTS: u8 = 3;
+
+// Slot states
+const FREE: u8 = 0;
+const SCHEDULED: u8 = 1;
+const ACTIVE: u8 = 2;
+
+#[derive(Debug)]
+struct Slot {
+    state: AtomicU8,
+    kind: AtomicU8,
+    // Time the fault happens at
+    at: AtomicU32,
+    address: AtomicU16,
+    // Mask of the flipped bit, value of the stuck cell, or increments still to drop
+    value: AtomicU16,
+}
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(FREE), kind: AtomicU8::new(0), at: AtomicU32::new(0),
+            address: AtomicU16::new(0), value: AtomicU16::new(0),
+        }
+    }
+
+    fn is(&self, state: u8, kind: u8, address: Address) -> bool {
+        self.state.load(Ordering::Relaxed) == state
+            && self.kind.load(Ordering::Relaxed) == kind
+            && self.address.load(Ordering::Relaxed) == address
+    }
+
+    fn free(&self) {
+        self.state.store(FREE, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct Faults {
+    slots: [Slot; FAULT_SLOTS],
+    // Whether any slot is in use, so memory accesses don't have to look at them otherwise
+    any: AtomicBool,
+}
+
+static FAULTS: Faults = Faults {
+    slots: [const { Slot::new() }; FAULT_SLOTS],
+    any: AtomicBool::new(false),
+};
+
+// Schedules a fault for when the machine's time (see `time`) reaches `at`. Times already past
+// take effect after the next instruction.
+// Returns false if there's no room for another fault
+pub fn inject(at: u32, fault: Fault) -> bool {
+    if let Fault::FlipBit { bit, .. } = fault {
+        assert!((1..=16).contains(&bit), "Bits are numbered 1 to 16");
+    }
+    // ZERO is wired to zero and the editing registers aren't emulated, there's no cell to hurt
+    if let Fault::FlipBit { .. } | Fault::Stuck { .. } = fault {
+        assert!(!matches!(fault.address(), ZERO | 16..=19), "No cell at address {}", fault.address());
+    }
+    let Some(slot) = FAULTS.slots.iter().find(|s| s.state.load(Ordering::Relaxed) == FREE) else {
+        return false;
+    };
+    slot.kind.store(fault.id(), Ordering::Relaxed);
+    slot.at.store(at, Ordering::Relaxed);
+    slot.address.store(fault.address(), Ordering::Relaxed);
+    slot.value.store(fault.value(), Ordering::Relaxed);
+    slot.state.store(SCHEDULED, Ordering::Relaxed);
+    FAULTS.any.store(true, Ordering::Relaxed);
+    true
+}
+
+// Removes every fault, scheduled or active. Stuck cells keep their value until written again
+pub fn clear_faults() {
+    FAULTS.slots.iter().for_each(Slot::free);
+    FAULTS.any.store(false, Ordering::Relaxed);
+}
+
+// Makes the faults whose time has come take effect
+pub(crate) fn apply_due(now: u32) {
+    if !FAULTS.any.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut any = false;
+    for slot in &FAULTS.slots {
+        let state = slot.state.load(Ordering::Relaxed);
+        // Times are compared with wrapping, like the alarm timers
+        if state == SCHEDULED && now.wrapping_sub(slot.at.load(Ordering::Relaxed)) < u32::MAX / 2 {
+            let address = slot.address.load(Ordering::Relaxed);
+            let value = slot.value.load(Ordering::Relaxed);
+            match slot.kind.load(Ordering::Relaxed) {
+                FLIP_BIT => {
+                    MEMORY.flip_bits(address, value);
+                    slot.free();
+                }
+                STUCK => {
+                    MEMORY.force(address, value);
+                    slot.state.store(ACTIVE, Ordering::Relaxed);
+                }
+                _ => slot.state.store(ACTIVE, Ordering::Relaxed),
+            }
+        }
+        any |= slot.state.load(Ordering::Relaxed) != FREE;
+    }
+    FAULTS.any.store(any, Ordering::Relaxed);
+}
+
+// Whether writes to the cell are lost
+pub(crate) fn is_stuck(k: Address) -> bool {
+    FAULTS.any.load(Ordering::Relaxed)
+        && FAULTS.slots.iter().any(|s| s.is(ACTIVE, STUCK, k))
+}
+
+// Takes up to `pulses` increments of a counter, returns how many of them are lost
+pub(crate) fn dropped(counter: Address, pulses: u32) -> u32 {
+    if !FAULTS.any.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let Some(slot) = FAULTS.slots.iter().find(|s| s.is(ACTIVE, DROP_INCREMENTS, counter)) else {
+        return 0;
+    };
+    let left = slot.value.load(Ordering::Relaxed) as u32;
+    let lost = left.min(pulses);
+    if lost == left {
+        slot.free();
+    } else {
+        slot.value.store((left - lost) as u16, Ordering::Relaxed);
+    }
+    lost
+}
+
+// Convenience for frontends: schedules the fault right away
+pub fn inject_now(fault: Fault) -> bool {
+    inject(time(), fault)
+}
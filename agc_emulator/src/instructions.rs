@@ -1,4 +1,7 @@
 use crate::memory::*;
+use crate::predecode::*;
+use crate::restart::monitor;
+use crate::standby::*;
 
 pub struct Instruction(pub &'static str, pub Option<Address>);
 
@@ -36,17 +39,26 @@ pub const INHINT: u16 = 4;
 pub const RELINT: u16 = 3;
 pub const RETURN: u16 = 2;
 
+// Channel instructions, need EXTEND
+pub const READ: u16 =   0b000000000000000;
+pub const WRITE: u16 =  0b000001000000000;
+pub const RAND: u16 =   0b000010000000000;
+pub const WAND: u16 =   0b000011000000000;
+pub const ROR: u16 =    0b000100000000000;
+pub const WOR: u16 =    0b000101000000000;
+pub const RXOR: u16 =   0b000110000000000;
+
 // Named for convenience
 pub const COM: u16 =    0b100000000000000;
 pub const DCOM: u16 =   0b100000000000001;
-pub const DDOUBL: u16 = 0b001000000000001;
-pub const DOUBLE: u16 = 0b011000000000000;
-pub const DTCB: u16 =   0b010101000000110;
-pub const DTCF: u16 =   0b010101000000101;
-pub const OVSK: u16 =   0b010110000000000;
-pub const SQUARE: u16 = 0b011100000000000;
-pub const ZL: u16 =     0b001001000000111;
-pub const ZQ: u16 =     0b001001000000111;
+pub const DDOUBL: u16 = 0b010000000000001;
+pub const DOUBLE: u16 = 0b110000000000000;
+pub const DTCB: u16 =   0b101010000000110;
+pub const DTCF: u16 =   0b101010000000101;
+pub const OVSK: u16 =   0b101100000000000;
+pub const SQUARE: u16 = 0b111000000000000;
+pub const ZL: u16 =     0b010010000000111;
+pub const ZQ: u16 =     0b010010000000111;
 
 // UTILITY FUNCTIONS
 
@@ -115,6 +127,8 @@ pub fn decode(ins: Word) -> Instruction {
     let qc = (ins & 0x0C00) >> 10; // bits 12-11
     let er_address: ErasableAddress = ins & 0x03FF; // first 10 bits
     let address = ins & 0x0FFF; // first 12 bits
+    let pc = (ins & 0x0E00) >> 9; // bits 12-10, peripheral code of channel instructions
+    let channel: Channel = ins & 0x01FF; // first 9 bits
     let extracode = MEMORY.extracode();
 
     macro_rules! addr {
@@ -127,6 +141,11 @@ pub fn decode(ins: Word) -> Instruction {
             Instruction($name, Some(er_address))
         };
     }
+    macro_rules! chaddr {
+        ($name: literal) => {
+            Instruction($name, Some(channel))
+        };
+    }
 
     // Instruction decoding according to AGC's documentation
     if !extracode { 
@@ -174,6 +193,17 @@ pub fn decode(ins: Word) -> Instruction {
     } else { 
         // Extended instructions
         match opcode {
+            0 => match pc {
+                0 => chaddr!("READ"),
+                1 => chaddr!("WRITE"),
+                2 => chaddr!("RAND"),
+                3 => chaddr!("WAND"),
+                4 => chaddr!("ROR"),
+                5 => chaddr!("WOR"),
+                6 => chaddr!("RXOR"),
+                7 => unimplemented!(), // EDRUPT instruction, not implemented
+                _ => unreachable!(),
+            }
             1 => match qc {
                 0 => unimplemented!(), // DV instruction, not implemented
                 1 | 2 | 3 => addr!("BZF"),
@@ -201,90 +231,29 @@ pub fn decode(ins: Word) -> Instruction {
 }
 
 // EXTEND AND INDEX HAVE PROBLEMS
-pub fn execute(ins: Word) {
-    let ins = add_modified(ins, MEMORY.get_index());
+// Returns the duration of the instruction in MCTs
+pub fn execute(ins: Word) -> Mct {
+    if in_standby() {
+        return idle();
+    }
 
-    let opcode = (ins & 0x7000) >> 12; // bits 15-13
-    let qc = (ins & 0x0C00) >> 10; // bits 12-11
-    let er_address: ErasableAddress = ins & 0x03FF; // first 10 bits
-    let address: Address = ins & 0x0FFF; // first 12 bits
-    let extracode: bool = MEMORY.extracode();
     let index = MEMORY.get_index();
+    let ins = add_modified(ins, index);
+
+    let slot = predecode(ins);
+    let op = if MEMORY.extracode() { slot.extended } else { slot.basic };
+    let mct = op.mct(); // Depends on the state before execution
 
     MEMORY.write(Z, MEMORY.read(Z) + 1); // Increment program counter
     MEMORY.clear_index();
     MEMORY.clear_extracode();
 
-    // Instruction decoding according to AGC's documentation
-    if !extracode { 
-        // Basic instructions
-        match opcode {
-            0 => match address {
-                2 => MEMORY.write(Z, Q), // RETURN
-                3 => MEMORY.relint(), // RELINT
-                4 => MEMORY.inhint(), // INHINT
-                6 => MEMORY.set_extracode(), // EXTEND
-                _ => tc(address)
-            }
-            1 => match qc {
-                0 => ccs(er_address),
-                1 | 2 | 3 => tcf(address),
-                _ => unreachable!(),
-            }
-            2 => match qc {
-                0 => das(er_address),
-                1 => lxch(er_address),
-                2 => incr(er_address),
-                3 => ads(er_address),
-                _ => unreachable!()
-            }
-            3 => ca(address),
-            4 => cs(address),
-            5 => match qc {
-                0 => if address == 15 {
-                    unimplemented!() // should be RESUME
-                } else {
-                    MEMORY.set_index(index + MEMORY.read(address)); // INDEX
-                }
-                1 => dxch(er_address),
-                2 => ts(er_address),
-                3 => xch(er_address),
-                _ => unreachable!()
-            }
-            6 => ad(address),
-            7 => mask(address),
-            _ => unreachable!(),
-        }
-    } else { 
-        // Extended instructions
-        match opcode {
-            1 => match qc {
-                0 => unimplemented!(), // DV instruction, not implemented
-                1 | 2 | 3 => bzf(address),
-                _ => unreachable!(),
-            }
-            2 => match qc {
-                0 => msu(er_address),
-                1 => qxch(er_address),
-                2 => aug(er_address),
-                3 => dim(er_address),
-                _ => unreachable!(),
-            }
-            3 => dca(address),
-            4 => dcs(address),
-            5 => {
-                MEMORY.set_index(MEMORY.read(address)); // INDEX
-                MEMORY.set_extracode(); // Keep extracode flag
-                } 
-            6 => match qc {
-                0 => su(er_address),
-                1 | 2 | 3 => bzmf(address),
-                _ => unreachable!(),
-            }
-            7 => mp(address),
-            _ => unreachable!()
-        }
+    match op {
+        Op::Index(k) => MEMORY.set_index(index + MEMORY.read(k)), // INDEX
+        _ => dispatch(op),
     }
+    monitor(op, mct);
+    mct
 }
 
 // How the AGC's ALU added
@@ -639,4 +608,58 @@ pub(crate) fn tc(k: Address) {
 // Transfer control to fixed (does not set up return)
 pub(crate) fn tcf(k: FixedAddress) {
     MEMORY.write(Z, k);
-}
\ No newline at end of file
+}
+
+// Channels hold 15-bit values, except Q (channel 2) which is the full register
+fn channel_to_acc(ch: Channel) -> Word {
+    if ch == Q {
+        MEMORY.read_channel(ch)
+    } else {
+        sign_extend(MEMORY.read_channel(ch))
+    }
+}
+
+fn acc_to_channel(ch: Channel, acc: Word) {
+    if ch == Q {
+        MEMORY.write_channel(ch, acc);
+    } else {
+        MEMORY.write_channel(ch, correct(acc));
+    }
+}
+
+// Read channel
+pub(crate) fn read(ch: Channel) {
+    MEMORY.write(ACC, channel_to_acc(ch));
+}
+
+// Write channel
+pub(crate) fn write(ch: Channel) {
+    acc_to_channel(ch, MEMORY.read(ACC));
+}
+
+// Read and mask
+pub(crate) fn rand(ch: Channel) {
+    MEMORY.write(ACC, MEMORY.read(ACC) & channel_to_acc(ch));
+}
+
+// Write and mask
+pub(crate) fn wand(ch: Channel) {
+    rand(ch);
+    write(ch);
+}
+
+// Read and superimpose (OR)
+pub(crate) fn ror(ch: Channel) {
+    MEMORY.write(ACC, MEMORY.read(ACC) | channel_to_acc(ch));
+}
+
+// Write and superimpose (OR)
+pub(crate) fn wor(ch: Channel) {
+    ror(ch);
+    write(ch);
+}
+
+// Read and exclusive OR
+pub(crate) fn rxor(ch: Channel) {
+    MEMORY.write(ACC, MEMORY.read(ACC) ^ channel_to_acc(ch));
+}
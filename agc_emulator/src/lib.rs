@@ -1,5 +1,16 @@
 #![no_std]
+// Each thread keeps its own memory with the single-thread feature
+#[cfg(feature = "single-thread")]
+extern crate std;
+pub mod board;
+pub mod faults;
 pub mod instructions;
 pub mod memory;
+pub mod pacing;
+pub mod predecode;
+pub mod restart;
+pub mod rope;
+pub mod standby;
+pub mod timers;
 #[cfg(test)]
 mod tests;
\ No newline at end of file
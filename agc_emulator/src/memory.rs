@@ -1,23 +1,43 @@
+#[cfg(feature = "single-thread")]
+use core::cell::Cell;
+#[cfg(not(feature = "single-thread"))]
 use core::sync::atomic::AtomicU16;
+#[cfg(not(feature = "single-thread"))]
 use core::sync::atomic::Ordering;
+use crate::board::{name_at, ERASABLE_END, ERASABLE_START, FIXED_START};
+use crate::faults::is_stuck;
+use crate::restart::{flag, Alarm, MONITOR};
+use crate::standby::*;
 
 // Constant for memory initialization
 const MEMLOC_INITIALIZE: Memloc = Memloc::new(0);
+// Initial values of erasable memory from address 48 on, given by the assembler
+pub(crate) const ERASABLE_INITIAL: [Word; (ERASABLE_END - ERASABLE_START) as usize] = include!("../memory/erasable.in");
+// Fixed memory from bank 2 on, bank after bank, given by the assembler. It holds as many banks as the
+// program uses
+const FIXED_INITIAL: &[Word] = &include!("../memory/fixed.in");
+// Fixed banks the FB register can switch in
+const FIXED_BANKS: usize = crate::board::FIXED_BANKS as usize;
 // Useful values named for readability
 pub const NEG_ONE: u16 = 0xFFFE; // Negative one represented in one's complement, bit s2 set
 pub const NEG_ZERO: u16 = 0xFFFF; // Negative zero in one's complement
 pub const ZERO_BIT16: u16 = 0x7FFF; // Mask to zero the bit 16
-// Registers
+// Registers. The central ones are the board's
+pub use crate::board::{ACC, BB, EB, FB, L, Q, Z, ZERO};
 macro_rules! register {
     ($name:ident, $value:literal) => {
         pub const $name: ErasableAddress = $value;
     };
 }
-register!(ZERO, 7);
-register!(ACC, 0);
-register!(L, 1);
-register!(Q, 2);
-register!(Z, 5);
+register!(TIME2, 20); // Centisecond clock, TIME2 holds the high-order part
+register!(TIME1, 21);
+register!(TIME6, 25); // Fine timer, counts down in 1/1600 s steps
+register!(NEWJOB, 55); // 67 octal, the night watchman checks it's accessed regularly
+
+// Where the program starts after power-on or any restart, 4000 octal
+pub const RESTART_ADDRESS: Address = FIXED_START;
+// Restart monitor channel (77 octal), holds the cause of the last hardware restart
+pub const RESTART_CHANNEL: Channel = 63;
 
 // Denotes an AGC word
 pub type Word = u16;
@@ -31,9 +51,67 @@ pub type FixedAddress = u16;
 // Denotes a 10-bit address that referenciates erasable memory
 pub type ErasableAddress = u16;
 
+// Denotes a 9-bit I/O channel number
+pub type Channel = u16;
+
+// Denotes a duration measured in memory cycle times (MCT). One MCT lasts 11.71875 µs
+pub type Mct = u16;
+
 
 // The memory object
+#[cfg(not(feature = "single-thread"))]
 pub static MEMORY: Memory = Memory::new();
+// With the single-thread feature memory is plain cells, which can't be shared, so every thread that uses
+// MEMORY gets a machine of its own. What it derefs to can't be sent to another thread
+#[cfg(feature = "single-thread")]
+pub static MEMORY: ThreadMemory = ThreadMemory;
+
+#[cfg(feature = "single-thread")]
+pub struct ThreadMemory;
+#[cfg(feature = "single-thread")]
+impl core::ops::Deref for ThreadMemory {
+    type Target = Memory;
+
+    fn deref(&self) -> &Memory {
+        std::thread_local! {
+            static LOCAL: &'static Memory = std::boxed::Box::leak(std::boxed::Box::new(Memory::new()));
+        }
+        LOCAL.with(|memory| *memory)
+    }
+}
+
+// Parity bit that makes the word have an odd number of ones, placed in bit 16
+pub fn parity_bit(val: Word) -> Word {
+    if (val & ZERO_BIT16).count_ones().is_multiple_of(2) { 0x8000 } else { 0 }
+}
+
+// Sum of the words of a fixed bank, the way the rope check adds them: in one's complement, with an overflow
+// wrapped around instead of kept. A good bank, with its bugger word, adds up to plus or minus its number
+pub fn bank_sum(words: impl IntoIterator<Item = Word>) -> Word {
+    let signed = |val: Word| if val & 0x4000 == 0 { val as i32 } else { -((!val & 0x3FFF) as i32) };
+    let sum = words.into_iter().fold(0, |sum, val| match sum + signed(val & ZERO_BIT16) {
+        s if s > 0x3FFF => s - 0x3FFF,
+        s if s < -0x3FFF => s + 0x3FFF,
+        s => s,
+    });
+    if sum < 0 { !(-sum as Word) & ZERO_BIT16 } else { sum as Word }
+}
+
+// Whether a bank that adds up to `sum` passes the rope check
+pub fn bank_sum_ok(bank: Word, sum: Word) -> bool {
+    sum == bank || sum == !bank & ZERO_BIT16
+}
+
+// How erasable and fixed words get their parity bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    // No parity bits, nothing is checked
+    Off,
+    // Parity is computed now for every erasable and fixed word
+    Generated,
+    // The rope was assembled with parity bits, only erasable parity is computed now
+    FromRope,
+}
 
 pub fn is_16bit(k: Address) -> bool {
     match k {
@@ -47,26 +125,40 @@ pub struct Memory {
     central_registers: CentralRegisters,
     erasable: ErasableMemory,
     fixed: FixedMemory,
+    counters: Counters,
+    channels: Channels,
     // 16 bit value. Bit 1 is extracode flag. Bit 2 enables interrups
     extra: Memloc,
     // Indexing value added to the next instruction's address 
     index: Memloc,
+    // 1 when erasable and fixed words carry a parity bit in bit 16, checked on every read
+    parity: Memloc,
+    // Changes whenever fixed memory is modified, so decoded copies of it know they're stale
+    fixed_generation: Memloc,
 }
 impl Memory {
     const fn new() -> Self{
         Self {
             central_registers: CentralRegisters::new(), erasable: ErasableMemory::new(), 
-            fixed: FixedMemory::new(), extra: Memloc::new(0), index: Memloc::new(0)
+            fixed: FixedMemory::new(), counters: Counters::new(), channels: Channels::new(), extra: Memloc::new(0), index: Memloc::new(0),
+            parity: Memloc::new(0), fixed_generation: Memloc::new(0),
         }
     }
 
     pub fn write(&self, k: Address, val: u16) {
         let k: Address = k & 0x0FFF; // Extract address
+        if k == NEWJOB {
+            MONITOR.newjob_accessed();
+        }
+        if is_stuck(k) {
+            return;
+        }
         let val15: Word = val & ZERO_BIT16; // Ensures we never write 16 bit values into 15-bit registers
         match k {
             0 ..= 7 => self.central_registers.write(k, val),
-            8 ..= 47 => unimplemented!(), // Contains the especially handled memory locations
-            48 ..= 2047 => self.erasable.write(k, val15),
+            8 ..= 15 | 20 ..= 47 => self.counters.write(k, val15),
+            16 ..= 19 => unimplemented!(), // Editing registers
+            48 ..= 2047 => self.erasable.write(k, self.with_parity(val15)),
             2048 ..= 4095 => panic!("Tried to write to fixed memory"), // Cannot write fixed memory
             _ => unreachable!(),
         }
@@ -74,17 +166,164 @@ impl Memory {
 
     pub fn read(&self, k: Address) -> Word{
         let k = k & 0x0FFF; // Extract 12-bit address
+        if k == NEWJOB {
+            MONITOR.newjob_accessed();
+        }
         match k {
             0 ..= 7 => self.central_registers.read(k),
-            8 ..= 47 => unimplemented!(), // Contains the especially handled memory locations
-            48 ..= 1023 => self.erasable.read(k),
-            1024 ..= 4095 => self.fixed.read(k),
+            8 ..= 15 | 20 ..= 47 => self.counters.read(k),
+            16 ..= 19 => unimplemented!(), // Editing registers
+            48 ..= 1023 => self.checked(self.erasable.read(k), Alarm::ErasableParity),
+            1024 ..= 4095 => self.checked(self.fixed.read(k, self.read(FB)), Alarm::FixedParity),
             _ => unreachable!()
         }
     }
     
+    // Channels 1 and 2 are the L and Q registers
+    pub fn read_channel(&self, ch: Channel) -> Word {
+        match ch {
+            L | Q => self.central_registers.read(ch),
+            PRO_CHANNEL if !pro_held() => self.channels.read(ch) | PRO_KEY, // Inverted input
+            _ => self.channels.read(ch),
+        }
+    }
+
+    pub fn write_channel(&self, ch: Channel, val: Word) {
+        match ch {
+            L | Q => self.central_registers.write(ch, val),
+            RESTART_CHANNEL => self.channels.write(ch, 0), // Any write resets the restart monitor
+            _ => self.channels.write(ch, val & ZERO_BIT16),
+        }
+    }
+
+    // Sets bits of a channel without going through the program, as the hardware does
+    pub(crate) fn set_channel_bits(&self, ch: Channel, bits: Word) {
+        self.channels.write(ch, self.channels.read(ch) | bits)
+    }
+
+    // Just for debug and testing purposes, not accessible to the "programmer"
+    pub(crate) fn write_fixed(&self, k: FixedAddress, val: Word) {
+        self.fixed.write(k, self.read(FB), self.with_parity(val & ZERO_BIT16));
+        self.fixed_changed();
+    }
+
+    pub(crate) fn fixed_generation(&self) -> Word {
+        self.fixed_generation.read()
+    }
+
+    fn fixed_changed(&self) {
+        self.fixed_generation.write(self.fixed_generation.read().wrapping_add(1));
+    }
+
+    pub fn set_parity(&self, parity: Parity) {
+        let with_parity = |val: Word| (val & ZERO_BIT16) | parity_bit(val);
+        match parity {
+            Parity::Off => self.parity.write(0),
+            Parity::Generated => {
+                self.erasable.update(with_parity);
+                self.fixed.update(with_parity);
+                self.fixed_changed();
+                self.parity.write(1);
+            }
+            Parity::FromRope => {
+                self.erasable.update(with_parity);
+                self.parity.write(1);
+            }
+        }
+    }
+
+    pub fn parity(&self) -> bool {
+        self.parity.read() != 0
+    }
+
+    fn with_parity(&self, val15: Word) -> Word {
+        if self.parity() { val15 | parity_bit(val15) } else { val15 }
+    }
+
+    // Removes the parity bit of a word read from memory, flagging the parity alarm if it's wrong
+    fn checked(&self, word: Word, alarm: Alarm) -> Word {
+        if self.parity() && word.count_ones().is_multiple_of(2) {
+            flag(alarm);
+        }
+        word & ZERO_BIT16
+    }
+
+    // Whether the word at k passes the parity check, without raising any alarm
+    pub(crate) fn parity_ok(&self, k: Address) -> bool {
+        let word = match k & 0x0FFF {
+            48 ..= 1023 => self.erasable.read(k),
+            1024 ..= 4095 => self.fixed.read(k, self.read(FB)),
+            _ => return true,
+        };
+        !self.parity() || !word.count_ones().is_multiple_of(2)
+    }
+
+    // Flips bits of a word as stored, parity bit included, for fault injection
+    pub(crate) fn flip_bits(&self, k: Address, mask: Word) {
+        let k = k & 0x0FFF;
+        match k {
+            0 ..= 47 => self.write(k, self.read(k) ^ mask),
+            48 ..= 1023 => self.erasable.write(k, self.erasable.read(k) ^ mask),
+            1024 ..= 4095 => {
+                self.fixed.write(k, self.read(FB), self.fixed.read(k, self.read(FB)) ^ mask);
+                self.fixed_changed();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Stores a value anywhere, fixed memory included, with the right parity, for fault injection
+    pub(crate) fn force(&self, k: Address, val: Word) {
+        let k = k & 0x0FFF;
+        match k {
+            0 ..= 47 => self.write(k, val),
+            48 ..= 1023 => self.erasable.write(k, self.with_parity(val & ZERO_BIT16)),
+            1024 ..= 4095 => self.write_fixed(k, val),
+            _ => unreachable!(),
+        }
+    }
+
+    // Clears the channels and the central registers and puts the initial values back in erasable memory,
+    // as if the AGC had never run
+    pub(crate) fn clear(&self) {
+        self.central_registers.clear();
+        self.erasable.reset(|word| self.with_parity(word));
+        self.counters.clear();
+        self.channels.clear();
+        self.extra.write(0);
+        self.index.write(0);
+    }
+
+    // Replaces the rope: fixed memory from bank 2 on gets `words`, and the rest of it zeros
+    pub fn load_fixed(&self, words: impl IntoIterator<Item = Word>) {
+        let mut words = words.into_iter();
+        for m in &self.fixed.banks[2048..] {
+            m.write(words.next().unwrap_or(0));
+        }
+        self.fixed_changed();
+    }
+
+    // Replaces the initial values of erasable memory from address 48 on, the ones it has now and after
+    // every fresh start
+    pub fn load_erasable(&self, words: impl IntoIterator<Item = Word>) {
+        let memlocs = self.erasable.erasable_bank0.iter().chain(self.erasable.erasable_bank1.iter());
+        for ((m, initial), word) in memlocs.zip(&self.erasable.initial).zip(words) {
+            initial.write(word & ZERO_BIT16);
+            m.write(self.with_parity(word & ZERO_BIT16));
+        }
+    }
+
+    // Sum of every word of fixed bank `bank`, without the parity bits, for the rope check
+    pub fn bank_sum(&self, bank: usize) -> Word {
+        bank_sum(self.fixed.banks[bank * 1024..(bank + 1) * 1024].iter().map(Memloc::read))
+    }
+
+    // Name of the word at addr: the board's for its registers and peripherals, the rope's for the rest
     pub fn get_address_name(&self, addr: Address) -> &'static str {
-        include!("../memory/names.in")
+        match name_at(addr) {
+            Some((name, _)) if name.len == 1 => name.name,
+            _ => include!("../memory/names.in"),
+        }
     }
 
     pub fn set_extracode(&self) {
@@ -122,45 +361,146 @@ impl Memory {
 
 #[derive(Debug)]
 struct ErasableMemory {
-    // The first 49 values are the central registers or special memory locations
+    // Bank 0 without its first 48 addresses, the central registers and special memory locations
+    erasable_bank0: [Memloc; 208],
     erasable_bank1: [Memloc; 256], 
+    // What a fresh start puts back
+    initial: [Memloc; 464],
 }
 impl ErasableMemory {
     const fn new() -> Self {
-        Self {erasable_bank1: [MEMLOC_INITIALIZE; 256]}
+        let mut memory = Self {
+            erasable_bank0: [MEMLOC_INITIALIZE; 208], erasable_bank1: [MEMLOC_INITIALIZE; 256], initial: [MEMLOC_INITIALIZE; 464],
+        };
+        let mut i = 0;
+        while i < 464 {
+            memory.initial[i] = Memloc::new(ERASABLE_INITIAL[i]);
+            i += 1;
+        }
+        i = 0;
+        while i < 208 {
+            memory.erasable_bank0[i] = Memloc::new(ERASABLE_INITIAL[i]);
+            i += 1;
+        }
+        while i < 464 {
+            memory.erasable_bank1[i - 208] = Memloc::new(ERASABLE_INITIAL[i]);
+            i += 1;
+        }
+        memory
     }
 
     fn read(&self, k: ErasableAddress) -> Word {
-        if k < 255 || k >= 512 {unimplemented!()} // We've only implemented 1 memory bank
-        return self.erasable_bank1[(k - 256) as usize].read()
+        match k {
+            48 ..= 255 => self.erasable_bank0[(k - 48) as usize].read(),
+            256 ..= 511 => self.erasable_bank1[(k - 256) as usize].read(),
+            _ => unimplemented!(), // We've only implemented 2 memory banks
+        }
     }
 
     fn write(&self, k: ErasableAddress, val: Word) {
-        if k < 255 || k >= 512 {unimplemented!()} // We've only implemented 1 memory bank
-        self.erasable_bank1[(k - 256) as usize].write(val);
+        match k {
+            48 ..= 255 => self.erasable_bank0[(k - 48) as usize].write(val),
+            256 ..= 511 => self.erasable_bank1[(k - 256) as usize].write(val),
+            _ => unimplemented!(), // We've only implemented 2 memory banks
+        }
+    }
+
+    // Replaces every word by f(word)
+    fn update(&self, f: impl Fn(Word) -> Word) {
+        self.erasable_bank0.iter().chain(self.erasable_bank1.iter()).for_each(|m| m.write(f(m.read())));
+    }
+
+    // Puts back the initial values, passed through f
+    fn reset(&self, f: impl Fn(Word) -> Word) {
+        let memlocs = self.erasable_bank0.iter().chain(self.erasable_bank1.iter());
+        memlocs.zip(&self.initial).for_each(|(m, initial)| m.write(f(initial.read())));
     }
 }
 
+// Interrupt storage and counter registers, everything between the central registers and erasable
+// memory except the editing registers
+#[derive(Debug)]
+struct Counters {
+    counters: [Memloc; 40],
+}
+impl Counters {
+    const fn new() -> Self {
+        Self {counters: [MEMLOC_INITIALIZE; 40]}
+    }
+
+    fn read(&self, k: ErasableAddress) -> Word {
+        self.counters[(k - 8) as usize].read()
+    }
+
+    fn write(&self, k: ErasableAddress, val: Word) {
+        self.counters[(k - 8) as usize].write(val)
+    }
+
+    fn clear(&self) {
+        self.counters.iter().for_each(|m| m.write(0));
+    }
+}
+
+#[derive(Debug)]
+struct Channels {
+    // Every channel addressable by the 9-bit channel field. 1 and 2 are never used, they're L and Q
+    channels: [Memloc; 512],
+}
+impl Channels {
+    const fn new() -> Self {
+        Self {channels: [MEMLOC_INITIALIZE; 512]}
+    }
+
+    fn read(&self, ch: Channel) -> Word {
+        self.channels[(ch & 0x01FF) as usize].read()
+    }
+
+    fn write(&self, ch: Channel, val: Word) {
+        self.channels[(ch & 0x01FF) as usize].write(val)
+    }
+
+    fn clear(&self) {
+        self.channels.iter().for_each(|m| m.write(0));
+    }
+}
+
+// Every fixed bank, one after the other. Addresses 1024 to 2047 show the bank in FB, and 2048 on are
+// banks 2 and 3, the fixed-fixed ones
 #[derive(Debug)]
 struct FixedMemory {
-    fixed_bank1: [Memloc; 1024]
+    banks: [Memloc; FIXED_BANKS * 1024]
 }
 impl FixedMemory {
     const fn new() -> Self {
-        Self {
-            fixed_bank1: include!("../memory/fixed.in"),
+        let mut banks = [MEMLOC_INITIALIZE; FIXED_BANKS * 1024];
+        let mut i = 0;
+        while i < FIXED_INITIAL.len() {
+            banks[2048 + i] = Memloc::new(FIXED_INITIAL[i]);
+            i += 1;
+        }
+        Self { banks }
+    }
+
+    // Where the word at k is, with the bank that's in FB
+    fn location(k: FixedAddress, fb: Word) -> usize {
+        match k {
+            1024 ..= 2047 => (fb as usize >> 10) * 1024 + (k as usize - 1024),
+            _ => k as usize,
         }
     }
 
-    fn read(&self, k: FixedAddress) -> Word {
-        if k < 2048 || k >= 3072 {unimplemented!()} // We've only implemented memory bank 0
-        return self.fixed_bank1[(k - 2048) as usize].read()
+    fn read(&self, k: FixedAddress, fb: Word) -> Word {
+        self.banks[Self::location(k, fb)].read()
     }
 
     // Just for debug and testing purposes, not accessible to the "programmer"
-    pub(crate) fn write(&self, k: FixedAddress, val: Word) {
-        if k < 2048 || k >= 3072 {unimplemented!()} // We've only implemented memory bank 0
-        self.fixed_bank1[(k - 2048) as usize].write(val & ZERO_BIT16);
+    pub(crate) fn write(&self, k: FixedAddress, fb: Word, val: Word) {
+        self.banks[Self::location(k, fb)].write(val);
+    }
+
+    // Replaces every word by f(word)
+    fn update(&self, f: impl Fn(Word) -> Word) {
+        self.banks.iter().for_each(|m| m.write(f(m.read())));
     }
 }
 
@@ -176,7 +516,13 @@ impl CentralRegisters {
     const fn new() -> Self {
         Self {
             acc: Memloc::new(0), l: Memloc::new(0), q: Memloc::new(0), 
-            bb: Memloc::new(0), z: Memloc::new(2048)
+            bb: Memloc::new(0), z: Memloc::new(RESTART_ADDRESS)
+        }
+    }
+
+    fn clear(&self) {
+        for m in [&self.acc, &self.l, &self.q, &self.bb] {
+            m.write(0);
         }
     }
 
@@ -211,10 +557,12 @@ impl CentralRegisters {
 }
 
 // Wrapper for managing atomic values
+#[cfg(not(feature = "single-thread"))]
 #[derive(Debug)]
 struct Memloc {
     val: AtomicU16
 }
+#[cfg(not(feature = "single-thread"))]
 impl Memloc {
     const fn new(n: u16) -> Self {
         Self {val: AtomicU16::new(n)}
@@ -227,4 +575,26 @@ impl Memloc {
     fn write(&self, val: Word) {
         self.val.store(val, Ordering::Relaxed);
     }
+}
+
+// With the single-thread feature memory is plain cells, for when one thread owns the machine, like the
+// host running batches of programs. It isn't Sync, so MEMORY is kept per thread then
+#[cfg(feature = "single-thread")]
+#[derive(Debug)]
+struct Memloc {
+    val: Cell<u16>
+}
+#[cfg(feature = "single-thread")]
+impl Memloc {
+    const fn new(n: u16) -> Self {
+        Self {val: Cell::new(n)}
+    }
+
+    fn read(&self) -> Word {
+        self.val.get()
+    }
+
+    fn write(&self, val: Word) {
+        self.val.set(val);
+    }
 }
\ No newline at end of file
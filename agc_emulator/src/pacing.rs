@@ -0,0 +1,102 @@
+use crate::memory::Mct;
+
+// The AGC's 1.024 MHz clock gives 12 pulses per MCT, so one MCT lasts 11.71875 µs (375/32 µs)
+pub const MCT_PER_SECOND: u64 = 1_024_000 / 12;
+const MCT_US_NUMERATOR: u64 = 375;
+const MCT_US_DENOMINATOR: u64 = 32;
+
+// The host clock is only checked after this many MCTs (about 1 ms), reading it on every instruction
+// would cost more than the instruction itself
+const CHECK_INTERVAL: u64 = 85;
+// Sleeps shorter than this aren't worth it, the host can't sleep that precisely anyway
+const MIN_SLEEP_US: u64 = 1_000;
+// If the emulation falls behind by more than this (slow display, debugger, busy host) it stops trying
+// to catch up, otherwise it would run in a burst afterwards
+const MAX_LAG_US: u64 = 100_000;
+
+// Time source of the host running the emulator
+pub trait Clock {
+    // Monotonic time in microseconds, from any starting point
+    fn now_us(&mut self) -> u64;
+
+    fn sleep_us(&mut self, us: u64);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    // 85.3 kMCT/s, like the real AGC
+    Authentic,
+    // Percentage of the authentic speed: 200 runs twice as fast, 50 at half speed
+    Scaled(u32),
+    // As fast as the host allows
+    Unthrottled,
+}
+
+// Keeps the emulated time in step with the host's time.
+// Call `pace` with the duration of every executed instruction. Since the deadline is computed from the
+// start of the run instead of instruction by instruction, sleeping too long or too short is corrected
+// on the next check and doesn't accumulate.
+pub struct Pacer<C: Clock> {
+    clock: C,
+    speed: Speed,
+    // Host time the run started at
+    start_us: u64,
+    // MCTs executed since the run started
+    elapsed: u64,
+    // Value of `elapsed` the last time the host clock was checked
+    checked: u64,
+}
+impl<C: Clock> Pacer<C> {
+    pub fn new(mut clock: C, speed: Speed) -> Self {
+        let start_us = clock.now_us();
+        Self { clock, speed, start_us, elapsed: 0, checked: 0 }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.restart();
+    }
+
+    // Starts counting from now. Call it after the emulation was paused, so the pause isn't made up for
+    pub fn restart(&mut self) {
+        self.start_us = self.clock.now_us();
+        self.elapsed = 0;
+        self.checked = 0;
+    }
+
+    // Host time that `mct` emulated MCTs should take at the current speed
+    fn host_us(&self, mct: u64) -> Option<u64> {
+        match self.speed {
+            Speed::Authentic => Some(mct * MCT_US_NUMERATOR / MCT_US_DENOMINATOR),
+            Speed::Scaled(percent) => {
+                let percent = percent.max(1) as u64;
+                Some(mct * MCT_US_NUMERATOR * 100 / (MCT_US_DENOMINATOR * percent))
+            }
+            Speed::Unthrottled => None,
+        }
+    }
+
+    // Accounts for an executed instruction, sleeping if the emulation got ahead of the host
+    pub fn pace(&mut self, mct: Mct) {
+        self.elapsed += mct as u64;
+        if self.elapsed - self.checked < CHECK_INTERVAL {
+            return;
+        }
+        self.checked = self.elapsed;
+
+        let Some(target) = self.host_us(self.elapsed) else {
+            return;
+        };
+        let now = self.clock.now_us().saturating_sub(self.start_us);
+
+        if target >= now + MIN_SLEEP_US {
+            self.clock.sleep_us(target - now);
+        } else if now > target + MAX_LAG_US {
+            self.restart();
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::instructions::*;
+use crate::memory::*;
+use crate::restart::monitor;
+use crate::standby::*;
+
+// Number of words reachable through the fixed address space: the switchable window (1024-2047)
+// followed by the fixed-fixed region (2048-4095)
+const FIXED_WORDS: usize = 3072;
+const FIXED_WINDOW_START: Address = 1024;
+const FIXED_FIXED_START: Address = 2048;
+
+// A decoded instruction, ready to be dispatched without looking at its bits again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    // Basic instructions
+    Tc(Address),
+    Return,
+    Relint,
+    Inhint,
+    Extend,
+    Ccs(ErasableAddress),
+    Tcf(FixedAddress),
+    Das(ErasableAddress),
+    Lxch(ErasableAddress),
+    Incr(ErasableAddress),
+    Ads(ErasableAddress),
+    Ca(Address),
+    Cs(Address),
+    Index(Address),
+    Dxch(ErasableAddress),
+    Ts(ErasableAddress),
+    Xch(ErasableAddress),
+    Ad(Address),
+    Mask(Address),
+    // Extended instructions
+    Bzf(FixedAddress),
+    Msu(ErasableAddress),
+    Qxch(ErasableAddress),
+    Aug(ErasableAddress),
+    Dim(ErasableAddress),
+    Dca(Address),
+    Dcs(Address),
+    IndexExtended(Address),
+    Su(ErasableAddress),
+    Bzmf(FixedAddress),
+    Mp(Address),
+    Read(Channel),
+    Write(Channel),
+    Rand(Channel),
+    Wand(Channel),
+    Ror(Channel),
+    Wor(Channel),
+    Rxor(Channel),
+    // Words the emulator can't execute yet (RESUME, DV, EDRUPT)
+    // They only panic when dispatched, so a rope containing them can still be predecoded
+    Unimplemented,
+}
+impl Op {
+    // Same names `decode` returns, useful for frontends that display the fast path
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Tc(_) => "TC",
+            Op::Return => "RETURN",
+            Op::Relint => "RELINT",
+            Op::Inhint => "INHINT",
+            Op::Extend => "EXTEND",
+            Op::Ccs(_) => "CCS",
+            Op::Tcf(_) => "TCF",
+            Op::Das(_) => "DAS",
+            Op::Lxch(_) => "LXCH",
+            Op::Incr(_) => "INCR",
+            Op::Ads(_) => "ADS",
+            Op::Ca(_) => "CA",
+            Op::Cs(0) => "COM",
+            Op::Cs(_) => "CS",
+            Op::Index(_) | Op::IndexExtended(_) => "INDEX",
+            Op::Dxch(_) => "DXCH",
+            Op::Ts(_) => "TS",
+            Op::Xch(_) => "XCH",
+            Op::Ad(_) => "AD",
+            Op::Mask(_) => "MASK",
+            Op::Bzf(_) => "BZF",
+            Op::Msu(_) => "MSU",
+            Op::Qxch(_) => "QXCH",
+            Op::Aug(_) => "AUG",
+            Op::Dim(_) => "DIM",
+            Op::Dca(_) => "DCA",
+            Op::Dcs(_) => "DCS",
+            Op::Su(_) => "SU",
+            Op::Bzmf(_) => "BZMF",
+            Op::Mp(_) => "MP",
+            Op::Read(_) => "READ",
+            Op::Write(_) => "WRITE",
+            Op::Rand(_) => "RAND",
+            Op::Wand(_) => "WAND",
+            Op::Ror(_) => "ROR",
+            Op::Wor(_) => "WOR",
+            Op::Rxor(_) => "RXOR",
+            Op::Unimplemented => "",
+        }
+    }
+
+    // Duration in MCTs. Branches take one MCT less when they jump, so this must be called
+    // before the instruction runs
+    pub fn mct(&self) -> Mct {
+        match self {
+            Op::Tc(_) | Op::Return | Op::Relint | Op::Inhint | Op::Extend | Op::Tcf(_) => 1,
+            Op::Das(_) | Op::Dxch(_) | Op::Dca(_) | Op::Dcs(_) | Op::Mp(_) => 3,
+            Op::Bzf(_) => {
+                let acc = MEMORY.read(ACC);
+                if acc == 0 || acc == NEG_ZERO { 1 } else { 2 }
+            }
+            Op::Bzmf(_) => {
+                let acc = MEMORY.read(ACC);
+                if acc == 0 || bit16(acc) == 1 { 1 } else { 2 }
+            }
+            Op::Unimplemented => 0,
+            _ => 2,
+        }
+    }
+}
+
+// Both meanings of a word, since whether it runs as an extracode is only known at execution time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    pub basic: Op,
+    pub extended: Op,
+}
+
+// Decodes a word into its basic and extracode meanings, according to AGC's documentation
+pub fn predecode(ins: Word) -> Slot {
+    let opcode = (ins & 0x7000) >> 12; // bits 15-13
+    let qc = (ins & 0x0C00) >> 10; // bits 12-11
+    let er_address: ErasableAddress = ins & 0x03FF; // first 10 bits
+    let address: Address = ins & 0x0FFF; // first 12 bits
+    let pc = (ins & 0x0E00) >> 9; // bits 12-10, peripheral code of channel instructions
+    let channel: Channel = ins & 0x01FF; // first 9 bits
+
+    let basic = match opcode {
+        0 => match address {
+            2 => Op::Return,
+            3 => Op::Relint,
+            4 => Op::Inhint,
+            6 => Op::Extend,
+            _ => Op::Tc(address),
+        }
+        1 => match qc {
+            0 => Op::Ccs(er_address),
+            _ => Op::Tcf(address),
+        }
+        2 => match qc {
+            0 => Op::Das(er_address),
+            1 => Op::Lxch(er_address),
+            2 => Op::Incr(er_address),
+            _ => Op::Ads(er_address),
+        }
+        3 => Op::Ca(address),
+        4 => Op::Cs(address),
+        5 => match qc {
+            0 => if address == 15 {
+                Op::Unimplemented // RESUME
+            } else {
+                Op::Index(address)
+            }
+            1 => Op::Dxch(er_address),
+            2 => Op::Ts(er_address),
+            _ => Op::Xch(er_address),
+        }
+        6 => Op::Ad(address),
+        _ => Op::Mask(address),
+    };
+
+    let extended = match opcode {
+        0 => match pc {
+            0 => Op::Read(channel),
+            1 => Op::Write(channel),
+            2 => Op::Rand(channel),
+            3 => Op::Wand(channel),
+            4 => Op::Ror(channel),
+            5 => Op::Wor(channel),
+            6 => Op::Rxor(channel),
+            _ => Op::Unimplemented, // EDRUPT
+        }
+        1 => match qc {
+            0 => Op::Unimplemented, // DV
+            _ => Op::Bzf(address),
+        }
+        2 => match qc {
+            0 => Op::Msu(er_address),
+            1 => Op::Qxch(er_address),
+            2 => Op::Aug(er_address),
+            _ => Op::Dim(er_address),
+        }
+        3 => Op::Dca(address),
+        4 => Op::Dcs(address),
+        5 => Op::IndexExtended(address),
+        6 => match qc {
+            0 => Op::Su(er_address),
+            _ => Op::Bzmf(address),
+        }
+        _ => Op::Mp(address),
+    };
+
+    Slot { basic, extended }
+}
+
+// Runs a predecoded instruction. The program counter, index and extracode flag must already be updated
+pub(crate) fn dispatch(op: Op) {
+    match op {
+        Op::Tc(k) => tc(k),
+        Op::Return => MEMORY.write(Z, Q),
+        Op::Relint => MEMORY.relint(),
+        Op::Inhint => MEMORY.inhint(),
+        Op::Extend => MEMORY.set_extracode(),
+        Op::Ccs(k) => ccs(k),
+        Op::Tcf(k) => tcf(k),
+        Op::Das(k) => das(k),
+        Op::Lxch(k) => lxch(k),
+        Op::Incr(k) => incr(k),
+        Op::Ads(k) => ads(k),
+        Op::Ca(k) => ca(k),
+        Op::Cs(k) => cs(k),
+        Op::Index(k) => MEMORY.set_index(MEMORY.read(k)),
+        Op::Dxch(k) => dxch(k),
+        Op::Ts(k) => ts(k),
+        Op::Xch(k) => xch(k),
+        Op::Ad(k) => ad(k),
+        Op::Mask(k) => mask(k),
+        Op::Bzf(k) => bzf(k),
+        Op::Msu(k) => msu(k),
+        Op::Qxch(k) => qxch(k),
+        Op::Aug(k) => aug(k),
+        Op::Dim(k) => dim(k),
+        Op::Dca(k) => dca(k),
+        Op::Dcs(k) => dcs(k),
+        Op::IndexExtended(k) => {
+            MEMORY.set_index(MEMORY.read(k));
+            MEMORY.set_extracode(); // Keep extracode flag
+        }
+        Op::Su(k) => su(k),
+        Op::Bzmf(k) => bzmf(k),
+        Op::Mp(k) => mp(k),
+        Op::Read(ch) => read(ch),
+        Op::Write(ch) => write(ch),
+        Op::Rand(ch) => rand(ch),
+        Op::Wand(ch) => wand(ch),
+        Op::Ror(ch) => ror(ch),
+        Op::Wor(ch) => wor(ch),
+        Op::Rxor(ch) => rxor(ch),
+        Op::Unimplemented => unimplemented!(),
+    }
+}
+
+// Fast path for `execute`. Fixed memory words are decoded the first time they're reached and kept in
+// a dispatch table, so later executions skip the decoding entirely.
+// The table is plain (non-atomic) memory: it belongs to whoever drives the machine and is only
+// touched through `&mut self`. Keep one per thread that steps the emulator.
+pub struct Predecoded {
+    slots: [Option<Slot>; FIXED_WORDS],
+    // FB register value the switchable window was decoded with
+    bank: Word,
+    // Version of fixed memory the table was decoded from
+    generation: Word,
+}
+impl Predecoded {
+    pub const fn new() -> Self {
+        Self { slots: [None; FIXED_WORDS], bank: 0, generation: 0 }
+    }
+
+    // Forgets every decoded word. Writes to fixed memory through the emulator are noticed on their own
+    pub fn invalidate(&mut self) {
+        self.slots = [None; FIXED_WORDS];
+    }
+
+    // Forgets the switchable window if the fixed bank changed since it was decoded, and everything
+    // if fixed memory itself changed
+    fn sync(&mut self) {
+        let generation = MEMORY.fixed_generation();
+        if generation != self.generation {
+            self.invalidate();
+            self.generation = generation;
+        }
+        let bank = MEMORY.read(FB);
+        if bank != self.bank {
+            let window = (FIXED_FIXED_START - FIXED_WINDOW_START) as usize;
+            self.slots[..window].fill(None);
+            self.bank = bank;
+        }
+    }
+
+    // Executes the instruction at Z, same as `execute(MEMORY.read(MEMORY.read(Z)))`
+    // Returns the duration of the instruction in MCTs
+    pub fn step(&mut self) -> Mct {
+        if in_standby() {
+            return idle();
+        }
+        let z = MEMORY.read(Z);
+
+        // Indexed instructions and code running from erasable memory change between executions,
+        // so they can't be cached
+        if z < FIXED_WINDOW_START || MEMORY.get_index() != 0 {
+            return execute(MEMORY.read(z));
+        }
+
+        self.sync();
+        let cached = &mut self.slots[(z - FIXED_WINDOW_START) as usize];
+        let slot = match cached {
+            Some(slot) => *slot,
+            None => {
+                let slot = predecode(MEMORY.read(z));
+                // A word that fails the parity check isn't cached, so the check runs on every fetch
+                if MEMORY.parity_ok(z) {
+                    *cached = Some(slot);
+                }
+                slot
+            }
+        };
+        let op = if MEMORY.extracode() { slot.extended } else { slot.basic };
+
+        let mct = op.mct();
+
+        MEMORY.write(Z, z + 1); // Increment program counter
+        MEMORY.clear_extracode();
+        dispatch(op);
+        monitor(op, mct);
+        mct
+    }
+}
+impl Default for Predecoded {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,269 @@
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use crate::faults::apply_due;
+use crate::memory::*;
+use crate::predecode::Op;
+use crate::standby::{check, leave};
+use crate::timers::count;
+
+// Alarm limits, in MCTs
+const TC_TRAP_LIMIT: u32 = 1280; // 15 ms
+const RUPT_LOCK_LIMIT: u32 = 11947; // 140 ms
+const NIGHT_WATCHMAN_PERIOD: u32 = 54613; // 0.64 s
+
+// Output channels GOJAM resets: 5, 6, 10, 11, 12, 13, 14, 34 and 35 octal
+const RESET_CHANNELS: [Channel; 9] = [5, 6, 8, 9, 10, 11, 12, 28, 29];
+
+// Hardware alarms that cause a restart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alarm {
+    // A word read from fixed memory failed the parity check
+    FixedParity,
+    // A word read from erasable memory failed the parity check
+    ErasableParity,
+    // Only TC/TCF instructions, or none at all, executed for 15 ms. Catches `TCF` to itself
+    TcTrap,
+    // An interrupt lasted too long, or there were no interrupts for 140 ms.
+    // The emulator doesn't generate interrupts yet, so once enabled this fires 140 ms after every restart
+    RuptLock,
+    // NEWJOB wasn't accessed for 0.64 s, the Executive is stuck
+    NightWatchman,
+}
+impl Alarm {
+    const ALL: [Alarm; 5] = [Alarm::FixedParity, Alarm::ErasableParity, Alarm::TcTrap, Alarm::RuptLock, Alarm::NightWatchman];
+
+    // Bits the alarm sets in the restart monitor channel
+    pub const fn code(self) -> Word {
+        match self {
+            Alarm::FixedParity => 0b1,
+            Alarm::ErasableParity => 0b11,
+            Alarm::TcTrap => 0o4,
+            Alarm::RuptLock => 0o10,
+            Alarm::NightWatchman => 0o20,
+        }
+    }
+
+    const fn id(self) -> u8 {
+        match self {
+            Alarm::FixedParity => 0,
+            Alarm::ErasableParity => 1,
+            Alarm::TcTrap => 2,
+            Alarm::RuptLock => 3,
+            Alarm::NightWatchman => 4,
+        }
+    }
+}
+
+// Cause of a GOJAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    PowerOn,
+    // Leaving standby
+    Standby,
+    // Asked for by hand, from a frontend's restart command or button
+    Manual,
+    Alarm(Alarm),
+}
+impl Restart {
+    // Encoded for storage in an atomic. 0 means there was no restart yet
+    const fn id(self) -> u8 {
+        match self {
+            Restart::PowerOn => 1,
+            Restart::Standby => 2,
+            Restart::Manual => 3,
+            Restart::Alarm(alarm) => 4 + alarm.id(),
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => None,
+            1 => Some(Restart::PowerOn),
+            2 => Some(Restart::Standby),
+            3 => Some(Restart::Manual),
+            _ => Alarm::ALL.iter().find(|a| a.id() == id - 4).map(|&a| Restart::Alarm(a)),
+        }
+    }
+}
+
+// State of the restart hardware: elapsed time, the alarm timers and the RESTART light
+#[derive(Debug)]
+pub(crate) struct Monitor {
+    // MCTs since power-on, wraps around after 14 hours
+    time: AtomicU32,
+    // Bit n enables the alarm with id n
+    enabled: AtomicU8,
+    light: AtomicBool,
+    last_restart: AtomicU8,
+    // Times of the last TC/TCF and of the last other instruction
+    last_tc: AtomicU32,
+    last_other: AtomicU32,
+    // Time of the last interrupt
+    last_rupt: AtomicU32,
+    // Start of the current night watchman period, and whether NEWJOB was accessed during it
+    watch_start: AtomicU32,
+    newjob: AtomicBool,
+    // Alarms detected in the middle of an instruction, raised once it finishes. Bit n is the alarm with id n
+    pending: AtomicU8,
+}
+impl Monitor {
+    const fn new() -> Self {
+        Self {
+            time: AtomicU32::new(0), enabled: AtomicU8::new(0), light: AtomicBool::new(false),
+            last_restart: AtomicU8::new(0), last_tc: AtomicU32::new(0), last_other: AtomicU32::new(0),
+            last_rupt: AtomicU32::new(0), watch_start: AtomicU32::new(0), newjob: AtomicBool::new(false),
+            pending: AtomicU8::new(0),
+        }
+    }
+
+    pub(crate) fn newjob_accessed(&self) {
+        self.newjob.store(true, Ordering::Relaxed);
+    }
+
+    fn enabled(&self, alarm: Alarm) -> bool {
+        self.enabled.load(Ordering::Relaxed) & (1 << alarm.id()) != 0
+    }
+
+    // Starts every alarm timer over from now
+    fn reset_timers(&self) {
+        let now = self.time.load(Ordering::Relaxed);
+        for timer in [&self.last_tc, &self.last_other, &self.last_rupt, &self.watch_start] {
+            timer.store(now, Ordering::Relaxed);
+        }
+        self.newjob.store(false, Ordering::Relaxed);
+        self.pending.store(0, Ordering::Relaxed);
+    }
+}
+
+// The restart hardware
+pub(crate) static MONITOR: Monitor = Monitor::new();
+
+// MCTs elapsed since power-on
+pub fn time() -> u32 {
+    MONITOR.time.load(Ordering::Relaxed)
+}
+
+// Hardware restart. The program starts over at 4000 octal with interrupts inhibited, the output
+// channels are reset and erasable memory is kept, so restart-protected code can pick up where it was.
+// Restarts caused by alarms record the cause in channel 77 and turn on the RESTART light
+pub fn gojam(cause: Restart) {
+    leave();
+    MEMORY.write(Z, RESTART_ADDRESS);
+    MEMORY.clear_index();
+    MEMORY.clear_extracode();
+    MEMORY.inhint();
+    for ch in RESET_CHANNELS {
+        MEMORY.write_channel(ch, 0);
+    }
+
+    match cause {
+        Restart::PowerOn => MEMORY.write_channel(RESTART_CHANNEL, 0),
+        Restart::Standby | Restart::Manual => (),
+        Restart::Alarm(alarm) => {
+            MEMORY.set_channel_bits(RESTART_CHANNEL, alarm.code());
+            MONITOR.light.store(true, Ordering::Relaxed);
+        }
+    }
+
+    MONITOR.last_restart.store(cause.id(), Ordering::Relaxed);
+    MONITOR.reset_timers();
+}
+
+pub fn power_on() {
+    gojam(Restart::PowerOn)
+}
+
+// Power-on with erasable memory, channels and registers cleared, for starting a program from scratch
+pub fn fresh_start() {
+    MEMORY.clear();
+    MONITOR.light.store(false, Ordering::Relaxed);
+    power_on()
+}
+
+// Restarts the AGC if the alarm is enabled
+pub fn raise(alarm: Alarm) {
+    if MONITOR.enabled(alarm) {
+        gojam(Restart::Alarm(alarm))
+    }
+}
+
+// Every alarm starts disabled: our programs don't run an Executive that keeps the night watchman
+// and the rupt lock satisfied
+pub fn enable_alarm(alarm: Alarm, enabled: bool) {
+    let mask = MONITOR.enabled.load(Ordering::Relaxed);
+    let bit = 1 << alarm.id();
+    MONITOR.enabled.store(if enabled { mask | bit } else { mask & !bit }, Ordering::Relaxed);
+}
+
+// Notes an alarm found while an instruction runs, like a parity failure. The instruction is allowed
+// to finish before the restart
+pub(crate) fn flag(alarm: Alarm) {
+    let pending = MONITOR.pending.load(Ordering::Relaxed);
+    MONITOR.pending.store(pending | 1 << alarm.id(), Ordering::Relaxed);
+}
+
+pub fn alarm_enabled(alarm: Alarm) -> bool {
+    MONITOR.enabled(alarm)
+}
+
+pub fn restart_light() -> bool {
+    MONITOR.light.load(Ordering::Relaxed)
+}
+
+// ERROR RESET key
+pub fn clear_restart_light() {
+    MONITOR.light.store(false, Ordering::Relaxed);
+}
+
+pub fn last_restart() -> Option<Restart> {
+    Restart::from_id(MONITOR.last_restart.load(Ordering::Relaxed))
+}
+
+// Lets time pass, returns the new time
+pub(crate) fn advance(mct: Mct) -> u32 {
+    let now = time().wrapping_add(mct as u32);
+    MONITOR.time.store(now, Ordering::Relaxed);
+    apply_due(now);
+    now
+}
+
+// Advances time by an executed instruction, counts the timers and checks standby and the alarms
+pub(crate) fn monitor(op: Op, mct: Mct) {
+    let now = advance(mct);
+    count(mct as u32);
+    check();
+
+    match op {
+        Op::Tc(_) | Op::Tcf(_) | Op::Return => MONITOR.last_tc.store(now, Ordering::Relaxed),
+        _ => MONITOR.last_other.store(now, Ordering::Relaxed),
+    }
+    let pending = MONITOR.pending.load(Ordering::Relaxed);
+    if pending != 0 {
+        MONITOR.pending.store(0, Ordering::Relaxed);
+        if let Some(&alarm) = Alarm::ALL.iter().find(|a| pending & 1 << a.id() != 0 && MONITOR.enabled(**a)) {
+            return raise(alarm);
+        }
+    }
+
+    let since = |timer: &AtomicU32| now.wrapping_sub(timer.load(Ordering::Relaxed));
+
+    let tc_trap = since(&MONITOR.last_tc) > TC_TRAP_LIMIT || since(&MONITOR.last_other) > TC_TRAP_LIMIT;
+    if tc_trap && MONITOR.enabled(Alarm::TcTrap) {
+        return raise(Alarm::TcTrap);
+    }
+
+    if since(&MONITOR.last_rupt) > RUPT_LOCK_LIMIT && MONITOR.enabled(Alarm::RuptLock) {
+        return raise(Alarm::RuptLock);
+    }
+
+    if since(&MONITOR.watch_start) >= NIGHT_WATCHMAN_PERIOD {
+        if !MONITOR.newjob.load(Ordering::Relaxed) && MONITOR.enabled(Alarm::NightWatchman) {
+            return raise(Alarm::NightWatchman);
+        }
+        MONITOR.watch_start.store(now, Ordering::Relaxed);
+        MONITOR.newjob.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::board::{ERASABLE_END, ERASABLE_START, FIXED_BANKS};
+use crate::memory::{Word, MEMORY};
+
+// A rope kept apart from the program, as the assembler writes it for the Pico in its HEX and UF2 outputs:
+// a header, the fixed words from bank 2 on and the erasable ones from ERASABLE_START on, two bytes each,
+// low byte first. The header has the magic, the version, the first bank, how many banks, where variables
+// end, the offsets of the fixed and of the erasable words, the checksum of the fixed words, the words
+// used in each of the 32 banks and the checksum of the erasable words
+
+// Where the rope goes in the Pico's flash, the last 64K of it, away from the program
+pub const FLASH_ADDRESS: u32 = 0x101F_0000;
+pub const FLASH_LEN: usize = 0x1_0000;
+pub const MAGIC: [u8; 8] = *b"AGCROPE\0";
+pub const VERSION: u16 = 2;
+pub const HEADER_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RopeError {
+    // There's no magic, nothing was written there
+    NoRope,
+    // Written by an assembler with another format
+    Version(u16),
+    // The header doesn't describe a rope that fits
+    Invalid,
+    // The fixed or erasable words aren't the ones the header has the checksum of
+    Checksum,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rope<'a> {
+    fixed: &'a [u8],
+    erasable: &'a [u8],
+}
+impl<'a> Rope<'a> {
+    // Checks the header and the checksum of the rope at the start of `bytes`
+    pub fn read(bytes: &'a [u8]) -> Result<Self, RopeError> {
+        if bytes.len() < HEADER_LEN || bytes[..8] != MAGIC {
+            return Err(RopeError::NoRope);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        if u16_at(8) != VERSION {
+            return Err(RopeError::Version(u16_at(8)));
+        }
+
+        let (first, banks) = (u16_at(10), u16_at(12));
+        let (fixed_at, erasable_at) = (u32_at(16) as usize, u32_at(20) as usize);
+        let fixed_len = banks as usize * 2048;
+        let erasable_len = (ERASABLE_END - ERASABLE_START) as usize * 2;
+        // The header may be anything, so nothing it gives can overflow
+        let fits = |at: usize, len: usize| at.checked_add(len).is_some_and(|end| end <= bytes.len());
+        if first != 2 || banks > FIXED_BANKS - first || !fits(fixed_at, fixed_len) || !fits(erasable_at, erasable_len) {
+            return Err(RopeError::Invalid);
+        }
+
+        let fixed = &bytes[fixed_at..fixed_at + fixed_len];
+        let erasable = &bytes[erasable_at..erasable_at + erasable_len];
+        if crc32(fixed) != u32_at(24) || crc32(erasable) != u32_at(92) {
+            return Err(RopeError::Checksum);
+        }
+        Ok(Self { fixed, erasable })
+    }
+
+    // Puts the rope in fixed memory, and its initial values in erasable memory for now and every fresh start
+    pub fn load(&self) {
+        MEMORY.load_fixed(words(self.fixed));
+        MEMORY.load_erasable(words(self.erasable));
+    }
+}
+
+fn words(bytes: &[u8]) -> impl Iterator<Item = Word> + '_ {
+    bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+}
+
+// CRC-32, the one zip uses, of the fixed or the erasable words as they're stored in the rope
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
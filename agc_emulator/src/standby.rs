@@ -0,0 +1,93 @@
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use crate::memory::*;
+use crate::restart::*;
+use crate::timers::count;
+
+// Channel 13 (octal), bit 11 allows the PRO key to put the AGC in standby
+pub const STANDBY_CHANNEL: Channel = 11;
+pub const STANDBY_ENABLE: Word = 0x0400;
+// Channel 32 (octal), bit 14 is the PRO key. Reads 0 while the key is pressed
+pub const PRO_CHANNEL: Channel = 26;
+pub const PRO_KEY: Word = 0x2000;
+
+// How long PRO must be held to go into standby, 0.64 s
+const PRO_HOLD: u32 = 54613;
+// Each `execute` in standby lets this much time pass, one centisecond
+const IDLE_MCT: Mct = 853;
+// While in standby the timers are only brought up to date every 1.28 s
+const STANDBY_UPDATE: u32 = 109227;
+
+#[derive(Debug)]
+struct Standby {
+    standby: AtomicBool,
+    pro: AtomicBool,
+    // Time PRO was pressed
+    pro_since: AtomicU32,
+    // PRO was released since going into standby, so the next press wakes the AGC up
+    released: AtomicBool,
+    // Time spent in standby that wasn't counted into the timers yet
+    pending: AtomicU32,
+}
+
+static STANDBY: Standby = Standby {
+    standby: AtomicBool::new(false), pro: AtomicBool::new(false), pro_since: AtomicU32::new(0),
+    released: AtomicBool::new(false), pending: AtomicU32::new(0),
+};
+
+// Call with the state of the PRO key, either on every change or continuously
+pub fn press_pro(held: bool) {
+    if held && !pro_held() {
+        STANDBY.pro_since.store(time(), Ordering::Relaxed);
+    }
+    if !held && in_standby() {
+        STANDBY.released.store(true, Ordering::Relaxed);
+    }
+    STANDBY.pro.store(held, Ordering::Relaxed);
+}
+
+pub fn pro_held() -> bool {
+    STANDBY.pro.load(Ordering::Relaxed)
+}
+
+// In standby no instructions run, only time passes
+pub fn in_standby() -> bool {
+    STANDBY.standby.load(Ordering::Relaxed)
+}
+
+// Goes into standby if it's allowed and PRO was held long enough
+pub(crate) fn check() {
+    let allowed = MEMORY.read_channel(STANDBY_CHANNEL) & STANDBY_ENABLE != 0;
+    let held = time().wrapping_sub(STANDBY.pro_since.load(Ordering::Relaxed));
+    if allowed && pro_held() && held >= PRO_HOLD {
+        STANDBY.standby.store(true, Ordering::Relaxed);
+        STANDBY.released.store(false, Ordering::Relaxed);
+        STANDBY.pending.store(0, Ordering::Relaxed);
+    }
+}
+
+// Takes the place of an instruction while in standby. Pressing PRO again wakes the AGC up through GOJAM
+pub(crate) fn idle() -> Mct {
+    advance(IDLE_MCT);
+    let pending = STANDBY.pending.load(Ordering::Relaxed) + IDLE_MCT as u32;
+    let wake = pro_held() && STANDBY.released.load(Ordering::Relaxed);
+
+    if pending >= STANDBY_UPDATE || wake {
+        count(pending);
+        STANDBY.pending.store(0, Ordering::Relaxed);
+    } else {
+        STANDBY.pending.store(pending, Ordering::Relaxed);
+    }
+
+    if wake {
+        gojam(Restart::Standby);
+    }
+    IDLE_MCT
+}
+
+// Any restart brings the AGC out of standby
+pub(crate) fn leave() {
+    STANDBY.standby.store(false, Ordering::Relaxed);
+}
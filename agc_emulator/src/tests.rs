@@ -1,4 +1,41 @@
+extern crate std;
+
+use crate::board::*;
+use crate::faults::*;
 use crate::instructions::*;
+use crate::memory::*;
+use crate::predecode::*;
+use crate::restart::*;
+use crate::rope::*;
+use crate::standby::*;
+use crate::timers::*;
+use std::format;
+use std::string::String;
+use std::sync::{Mutex, MutexGuard};
+use std::vec::Vec;
+
+// Tests that run programs share the global memory, they must hold this lock
+static MACHINE: Mutex<()> = Mutex::new(());
+
+fn machine() -> MutexGuard<'static, ()> {
+    MACHINE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Starts from scratch with the program placed at the restart address
+fn load(program: &[Word]) {
+    MEMORY.set_parity(Parity::Off);
+    clear_faults();
+    for (i, &word) in program.iter().enumerate() {
+        MEMORY.write_fixed(RESTART_ADDRESS + i as u16, word);
+    }
+    fresh_start();
+}
+
+fn run(instructions: usize) {
+    for _ in 0..instructions {
+        execute(MEMORY.read(MEMORY.read(Z)));
+    }
+}
 
 #[test]
 fn test_add_positive() { 
@@ -75,3 +112,609 @@ fn test_i32_conversion() {
     assert_eq!(as_i32(n5), as_i32(n6));
     assert_eq!(as_i32(n7), as_i32(n8));
 }
+
+#[test]
+fn test_predecode_matches_decode() {
+    use crate::predecode::*;
+    let _machine = machine();
+
+    // Index and extracode flag are clear, so decode gives the basic meaning of every word
+    for ins in 0..=0x7FFF {
+        let slot = predecode(ins);
+        if slot.basic == Op::Unimplemented {
+            continue;
+        }
+        let crate::instructions::Instruction(name, _) = decode(ins);
+        assert_eq!(slot.basic.name(), name, "word {:o}", ins);
+    }
+}
+
+#[test]
+fn test_predecode_extracodes() {
+    use crate::predecode::*;
+
+    assert_eq!(predecode(BZF + 2100).extended, Op::Bzf(2100));
+    assert_eq!(predecode(BZMF + 2100).extended, Op::Bzmf(2100));
+    assert_eq!(predecode(BZMF + 300).basic, Op::Ad(300));
+    assert_eq!(predecode(DV + 300).extended, Op::Unimplemented);
+    assert_eq!(predecode(INDEX + 15).basic, Op::Unimplemented); // RESUME
+    assert_eq!(predecode(INDEX + 300).extended, Op::IndexExtended(300));
+}
+
+// Clock that only advances when slept on or when told to
+struct FakeClock<'a> {
+    now: &'a core::cell::Cell<u64>,
+    slept: &'a core::cell::Cell<u64>,
+}
+impl crate::pacing::Clock for FakeClock<'_> {
+    fn now_us(&mut self) -> u64 {
+        self.now.get()
+    }
+
+    fn sleep_us(&mut self, us: u64) {
+        self.now.set(self.now.get() + us);
+        self.slept.set(self.slept.get() + us);
+    }
+}
+
+#[test]
+fn test_pacing_speeds() {
+    use crate::pacing::*;
+    use core::cell::Cell;
+
+    for (speed, expected) in [(Speed::Authentic, 1_000_000), (Speed::Scaled(200), 500_000), (Speed::Unthrottled, 0)] {
+        let (now, slept) = (Cell::new(0), Cell::new(0));
+        let mut pacer = Pacer::new(FakeClock { now: &now, slept: &slept }, speed);
+
+        // One emulated second, in 2 MCT instructions
+        for _ in 0..MCT_PER_SECOND / 2 {
+            pacer.pace(2);
+        }
+
+        let error = slept.get().abs_diff(expected);
+        assert!(error < 2_000, "{:?} slept {} µs", speed, slept.get());
+    }
+}
+
+#[test]
+fn test_pacing_drift() {
+    use crate::pacing::*;
+    use core::cell::Cell;
+
+    let (now, slept) = (Cell::new(0), Cell::new(0));
+    let mut pacer = Pacer::new(FakeClock { now: &now, slept: &slept }, Speed::Authentic);
+
+    // The host stalls for a second, the emulation must not try to make up for it
+    now.set(1_000_000);
+    for _ in 0..MCT_PER_SECOND / 2 {
+        pacer.pace(2);
+    }
+    assert!(now.get().abs_diff(2_000_000) < 2_000, "finished at {} µs", now.get());
+
+    // Overslept by a little: the next sleeps are shortened to compensate
+    let (now, slept) = (Cell::new(0), Cell::new(0));
+    let mut pacer = Pacer::new(FakeClock { now: &now, slept: &slept }, Speed::Authentic);
+    now.set(5_000);
+    for _ in 0..MCT_PER_SECOND / 2 {
+        pacer.pace(2);
+    }
+    assert!(now.get().abs_diff(1_000_000) < 2_000, "finished at {} µs", now.get());
+}
+
+#[test]
+fn test_gojam_preserves_erasable() {
+    let _machine = machine();
+    load(&[TCF + 2048]);
+
+    MEMORY.write(300, 1234);
+    MEMORY.write(Z, 2100);
+    gojam(Restart::Alarm(Alarm::TcTrap));
+
+    assert_eq!(MEMORY.read(Z), RESTART_ADDRESS);
+    assert_eq!(MEMORY.read(300), 1234);
+    assert_eq!(MEMORY.read_channel(RESTART_CHANNEL), Alarm::TcTrap.code());
+    assert!(restart_light());
+
+    // The program reads the cause and clears it
+    MEMORY.write_channel(RESTART_CHANNEL, 1);
+    assert_eq!(MEMORY.read_channel(RESTART_CHANNEL), 0);
+
+    fresh_start();
+    assert_eq!(MEMORY.read(300), 0);
+    assert!(!restart_light());
+    assert_eq!(last_restart(), Some(Restart::PowerOn));
+}
+
+#[test]
+fn test_restart_channel_bits() {
+    let _machine = machine();
+    // Channel 77: bit 1 parity fail, bit 2 erasable parity fail, then TC trap, RUPT lock and night watchman
+    let bits = [
+        (Alarm::FixedParity, 0o1),
+        (Alarm::ErasableParity, 0o3),
+        (Alarm::TcTrap, 0o4),
+        (Alarm::RuptLock, 0o10),
+        (Alarm::NightWatchman, 0o20),
+    ];
+    for (alarm, bits) in bits {
+        MEMORY.write_channel(RESTART_CHANNEL, 0);
+        gojam(Restart::Alarm(alarm));
+        assert_eq!(MEMORY.read_channel(RESTART_CHANNEL), bits, "{alarm:?}");
+    }
+
+    // A restart by hand keeps the cause of the last one
+    gojam(Restart::Manual);
+    assert_eq!(MEMORY.read_channel(RESTART_CHANNEL), 0o20);
+    assert_eq!(last_restart(), Some(Restart::Manual));
+}
+
+#[test]
+fn test_tc_trap() {
+    let _machine = machine();
+    load(&[CA + 300, TS + 301, TCF + 2050]); // Ends in a TCF to itself
+
+    enable_alarm(Alarm::TcTrap, true);
+    run(2000);
+    enable_alarm(Alarm::TcTrap, false);
+
+    assert_eq!(last_restart(), Some(Restart::Alarm(Alarm::TcTrap)));
+    assert_eq!(MEMORY.read_channel(RESTART_CHANNEL), Alarm::TcTrap.code());
+}
+
+#[test]
+fn test_night_watchman() {
+    let _machine = machine();
+
+    // Touches NEWJOB on every loop
+    load(&[CA + NEWJOB, TCF + 2048]);
+    enable_alarm(Alarm::NightWatchman, true);
+    run(100_000);
+    assert_eq!(last_restart(), Some(Restart::PowerOn));
+
+    load(&[CA + 300, TCF + 2048]);
+    run(100_000);
+    enable_alarm(Alarm::NightWatchman, false);
+    assert_eq!(last_restart(), Some(Restart::Alarm(Alarm::NightWatchman)));
+}
+
+#[test]
+fn test_channels() {
+    let _machine = machine();
+    load(&[CA + 300, EXTEND, WRITE + 8, CA + 301, EXTEND, ROR + 8, EXTEND, WAND + 9]);
+
+    MEMORY.write(300, 0b1010);
+    MEMORY.write(301, 0b0101);
+    MEMORY.write_channel(9, 0b0110);
+    run(8);
+
+    assert_eq!(MEMORY.read_channel(8), 0b1010);
+    assert_eq!(MEMORY.read_channel(9), 0b0110);
+    assert_eq!(MEMORY.read(ACC), 0b0110);
+}
+
+#[test]
+fn test_standby() {
+    let _machine = machine();
+    load(&[CA + 300, EXTEND, WRITE + STANDBY_CHANNEL, TCF + 2051]);
+    MEMORY.write(300, STANDBY_ENABLE);
+    MEMORY.write(301, 777);
+
+    // Holding PRO with standby allowed stops the program
+    run(10);
+    press_pro(true);
+    let start = time();
+    while !in_standby() {
+        run(1);
+    }
+    assert!(time() - start >= 54613); // 0.64 s
+    press_pro(false);
+
+    let time1 = MEMORY.read(TIME1);
+    run(1000); // 1000 centiseconds
+    assert_eq!(MEMORY.read(Z), 2051);
+
+    // Pressing it again restarts, and the time spent in standby was kept
+    press_pro(true);
+    run(1);
+    press_pro(false);
+    assert!(!in_standby());
+    assert_eq!(last_restart(), Some(Restart::Standby));
+    assert_eq!(MEMORY.read(Z), RESTART_ADDRESS);
+    assert_eq!(MEMORY.read(301), 777);
+    assert_eq!(MEMORY.read_channel(STANDBY_CHANNEL), 0);
+    assert!((MEMORY.read(TIME1) - time1).abs_diff(1000) <= 2, "{}", MEMORY.read(TIME1) - time1);
+}
+
+#[test]
+fn test_time6() {
+    let _machine = machine();
+    load(&[TCF + 2048]);
+    MEMORY.write(TIME6, 16);
+    MEMORY.write_channel(TIME6_CHANNEL, TIME6_ENABLE);
+
+    run(800); // Half a centisecond
+    assert_eq!(MEMORY.read(TIME6), 1);
+    run(100);
+    assert_eq!(MEMORY.read(TIME6), 0);
+    assert_eq!(MEMORY.read_channel(TIME6_CHANNEL), 0);
+}
+
+#[test]
+fn test_parity_alarms() {
+    let _machine = machine();
+    load(&[CA + 300, TS + 301, TCF + 2048]);
+    MEMORY.write(300, 5);
+    MEMORY.set_parity(Parity::Generated);
+    enable_alarm(Alarm::ErasableParity, true);
+    enable_alarm(Alarm::FixedParity, true);
+
+    // Correct words pass the check
+    run(30);
+    assert_eq!(last_restart(), Some(Restart::PowerOn));
+    assert_eq!(MEMORY.read(301), 5);
+
+    // A flipped data bit restarts after the instruction that read it
+    assert!(inject(time(), Fault::FlipBit { address: 300, bit: 2 }));
+    run(4);
+    assert_eq!(last_restart(), Some(Restart::Alarm(Alarm::ErasableParity)));
+    assert_eq!(MEMORY.read_channel(RESTART_CHANNEL), Alarm::ErasableParity.code());
+
+    // A flipped parity bit in the program is caught when the word is fetched
+    MEMORY.write_channel(RESTART_CHANNEL, 0);
+    MEMORY.write(300, 5);
+    assert!(inject(time(), Fault::FlipBit { address: 2049, bit: 16 }));
+    run(4);
+    enable_alarm(Alarm::ErasableParity, false);
+    enable_alarm(Alarm::FixedParity, false);
+    assert_eq!(last_restart(), Some(Restart::Alarm(Alarm::FixedParity)));
+    assert_eq!(MEMORY.read_channel(RESTART_CHANNEL), Alarm::FixedParity.code());
+}
+
+#[test]
+fn test_parity_predecoded() {
+    let _machine = machine();
+    load(&[CA + 300, TS + 301, TCF + 2048]);
+    MEMORY.set_parity(Parity::Generated);
+    enable_alarm(Alarm::FixedParity, true);
+    let mut predecoded = Predecoded::new();
+
+    for _ in 0..30 {
+        predecoded.step();
+    }
+    assert_eq!(last_restart(), Some(Restart::PowerOn));
+
+    // Already decoded words are still checked after the fault
+    assert!(inject(time(), Fault::FlipBit { address: 2048, bit: 16 }));
+    for _ in 0..4 {
+        predecoded.step();
+    }
+    enable_alarm(Alarm::FixedParity, false);
+    assert_eq!(last_restart(), Some(Restart::Alarm(Alarm::FixedParity)));
+}
+
+#[test]
+fn test_stuck_cell() {
+    let _machine = machine();
+    load(&[CA + 300, TS + 301, TCF + 2048]);
+    MEMORY.write(300, 7);
+
+    assert!(inject(time() + 100, Fault::Stuck { address: 301, value: 3 }));
+    run(3);
+    assert_eq!(MEMORY.read(301), 7);
+    run(100);
+    assert_eq!(MEMORY.read(301), 3);
+    clear_faults();
+    run(3);
+    assert_eq!(MEMORY.read(301), 7);
+}
+
+#[test]
+fn test_faults_need_a_cell() {
+    // ZERO and the editing registers are refused before anything is scheduled
+    for fault in [Fault::FlipBit { address: ZERO, bit: 1 }, Fault::Stuck { address: 17, value: 1 }] {
+        assert!(std::panic::catch_unwind(|| inject(0, fault)).is_err(), "{fault:?}");
+    }
+}
+
+#[test]
+fn test_dropped_increments() {
+    let _machine = machine();
+    load(&[TCF + 2048]);
+    MEMORY.write(TIME1, 0);
+
+    assert!(inject(0, Fault::DropIncrements { counter: TIME1, count: 3 }));
+    run(8534); // 10 centiseconds
+    assert_eq!(MEMORY.read(TIME1), 7);
+    run(8534);
+    assert_eq!(MEMORY.read(TIME1), 17);
+}
+
+// Address given to a symbol by the assembler
+fn address(image: &assembler::Image, name: &str) -> Address {
+    image.symbols.iter().find(|s| s.name == name).unwrap().address
+}
+
+#[test]
+fn test_assembled_program() {
+    let _machine = machine();
+    let image = assembler::assemble(&["
+.code
+START:
+    CA THREE
+    AD FOUR
+    TS SUM
+END:
+    TCF END
+.data
+THREE:
+    DEC 3
+FOUR:
+    DEC -4
+"]).unwrap();
+    load(&image.fixed);
+    run(4);
+
+    assert_eq!(MEMORY.read(Z), address(&image, "END"));
+    assert_eq!(MEMORY.read(address(&image, "SUM")), 0b111111111111110); // -1
+}
+
+#[test]
+fn test_far_call() {
+    let _machine = machine();
+    let image = assembler::assemble(&["
+.config
+    EXTERN TWICE
+.code
+START:
+    CA SEVEN
+    TC TWICE
+    TS RESULT
+END:
+    TCF END
+.data
+SEVEN:
+    DEC 7
+", "
+.config
+    EXPORT TWICE
+.code
+    BANK 5
+TWICE:
+    DOUBLE
+    RETURN
+"]).unwrap();
+    load(&image.fixed);
+    run(50);
+
+    // TWICE runs from bank 5 with A as it was, and the bank is back to the caller's after it returns
+    assert_eq!(MEMORY.read(Z), address(&image, "END"));
+    assert_eq!(MEMORY.read(address(&image, "RESULT")), 14);
+    assert_eq!(MEMORY.read(FB), 0);
+
+    // A routine reached through FARCALL can call another bank, and both return where they should
+    let image = assembler::assemble(&["
+.config
+    EXTERN TWICE
+.code
+START:
+    CA SEVEN
+    TC TWICE
+    TS RESULT
+END:
+    TCF END
+.data
+SEVEN:
+    DEC 7
+", "
+.config
+    EXPORT TWICE
+    EXTERN ADDONE
+.code
+    BANK 5
+TWICE:
+    LXCH Q
+    DOUBLE
+    TC ADDONE
+    LXCH Q
+    RETURN
+", "
+.config
+    EXPORT ADDONE
+.code
+    BANK 6
+ADDONE:
+    AD ONE
+    RETURN
+.data
+ONE:
+    DEC 1
+"]).unwrap();
+    load(&image.fixed);
+    run(100);
+
+    assert_eq!(MEMORY.read(Z), address(&image, "END"));
+    assert_eq!(MEMORY.read(address(&image, "RESULT")), 15);
+    assert_eq!(MEMORY.read(FB), 0);
+}
+
+#[test]
+fn test_bank_sums() {
+    let _machine = machine();
+    let options = assembler::Options { bugger_words: true, ..assembler::Options::default() };
+    let image = assembler::assemble_with(&[
+        ".code\nSTART:\n    CA NEGATIVE\n    TCF START\n.data\nNEGATIVE:\n    DEC -5000\n",
+        ".code\n    BANK 4\nFAR:\n    TCF FAR\n",
+    ], &options).unwrap();
+    load(&image.fixed);
+
+    // The rope check passes on every bank with a bugger word, with the parity bits or without them
+    assert!(bank_sum_ok(2, MEMORY.bank_sum(2)));
+    assert!(bank_sum_ok(4, MEMORY.bank_sum(4)));
+    MEMORY.set_parity(Parity::Generated);
+    assert!(bank_sum_ok(2, MEMORY.bank_sum(2)));
+    MEMORY.set_parity(Parity::Off);
+    assert!(!bank_sum_ok(3, MEMORY.bank_sum(3)));
+
+    assert_eq!(bank_sum([0o37777, 1]), 1);
+    assert_eq!(bank_sum([0o40000, 0o77776]), 0o77776);
+    assert!(bank_sum_ok(3, 0o77774));
+}
+
+#[test]
+fn test_board() {
+    // Names don't overlap, and variables go after the last one
+    for (i, name) in NAMES.iter().enumerate() {
+        assert!(NAMES[i + 1..].iter().all(|other| other.address >= name.address + name.len));
+    }
+    assert_eq!(NAMES.iter().map(|name| name.address + name.len).max(), Some(RAM_START));
+    assert_eq!(name_at(PANT + 3).map(|(name, i)| (name.name, i)), Some(("PANT", 3)));
+    assert_eq!(name_at(RAM_START), None);
+    assert_eq!(MEMORY.get_address_name(POTE), "POTE");
+
+    // The assembler knows them by the same names
+    let image = assembler::assemble(&[".code\nSTART:\n    CA POTE\n    TS PANT+2\n    TCF START\n"]).unwrap();
+    assert_eq!(image.fixed[..2], [CA + POTE, TS + PANT + 2]);
+}
+
+#[test]
+fn test_assembler_mnemonics() {
+    // The assembler and the emulator agree on the word of every instruction they both name
+    let named = [
+        ("EXTEND", EXTEND), ("INHINT", INHINT), ("RELINT", RELINT), ("RETURN", RETURN),
+        ("READ", READ), ("WRITE", WRITE), ("RAND", RAND), ("WAND", WAND), ("ROR", ROR), ("WOR", WOR), ("RXOR", RXOR),
+        ("COM", COM), ("DCOM", DCOM), ("DDOUBL", DDOUBL), ("DOUBLE", DOUBLE), ("DTCB", DTCB), ("DTCF", DTCF),
+        ("OVSK", OVSK), ("SQUARE", SQUARE), ("ZL", ZL), ("ZQ", ZQ),
+    ];
+    for (name, word) in named {
+        assert_eq!(assembler::constants::decode(name), word, "{name}");
+    }
+}
+
+#[test]
+fn test_rope() {
+    let _machine = machine();
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../programs/");
+    let manifest = std::fs::read_to_string(format!("{dir}manifest.txt")).unwrap();
+    let sources: Vec<String> = manifest.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|file| std::fs::read_to_string(format!("{dir}{file}")).unwrap())
+        .collect();
+    let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+    let image = assembler::assemble(&sources).unwrap();
+
+    // The menu keeps running, waiting for a button
+    load(&image.fixed);
+    MEMORY.write(address(&image, "MEDIO"), 3);
+    run(10_000);
+    assert_eq!(last_restart(), Some(Restart::PowerOn));
+    // It shows the first program, and holding the right button goes through them up to the last one
+    assert_eq!(MEMORY.read(PANT), 1);
+    MEMORY.write(BTNRGT, 1);
+    run(10_000);
+    MEMORY.write(BTNRGT, 0);
+    run(10_000);
+    assert_eq!(MEMORY.read(address(&image, "PRG")), 5);
+    assert_eq!(MEMORY.read(PANT), 63);
+}
+
+#[test]
+fn test_structured_control_flow() {
+    let _machine = machine();
+    let image = assembler::assemble(&["
+.code
+START:
+    FOR I FROM 4 DOWNTO 0
+    CA SUM
+    AD I
+    TS SUM
+    ENDFOR
+    CA =3
+    TS N
+    WHILE N
+    CA COUNT
+    AD =1
+    TS COUNT
+    CA N
+    AD =-1
+    TS N
+    ENDWHILE
+    IFZERO N
+    CA =1
+    ELSE
+    CA =2
+    ENDIF
+    TS ISZERO
+    IFNEG SUM
+    CA =1
+    ELSE
+    CA =2
+    ENDIF
+    TS ISNEG
+    IFNEG MINUS
+    CA =1
+    TS WASNEG
+    ENDIF
+END:
+    TCF END
+.data
+MINUS:
+    DEC -3
+"]).unwrap();
+    load(&image.fixed);
+    run(400);
+
+    assert_eq!(MEMORY.read(Z), address(&image, "END"));
+    assert_eq!(MEMORY.read(address(&image, "SUM")), 10);
+    assert_eq!(MEMORY.read(address(&image, "I")), 0);
+    assert_eq!(MEMORY.read(address(&image, "COUNT")), 3);
+    assert_eq!(MEMORY.read(address(&image, "ISZERO")), 1);
+    assert_eq!(MEMORY.read(address(&image, "ISNEG")), 2);
+    assert_eq!(MEMORY.read(address(&image, "WASNEG")), 1);
+}
+
+#[test]
+fn test_flash_rope() {
+    let _machine = machine();
+    let image = assembler::assemble(&["
+.vars
+    TALLY DEC 40
+.code
+START:
+    INCR TALLY
+    TCF START
+"]).unwrap();
+    let mut flash = image.to_pico();
+    flash.resize(FLASH_LEN, 0xFF);
+
+    load(&[]);
+    Rope::read(&flash).unwrap().load();
+    fresh_start();
+    run(6);
+    assert_eq!(MEMORY.read(address(&image, "TALLY")), 43);
+    // A fresh start goes back to the rope's initial values
+    fresh_start();
+    assert_eq!(MEMORY.read(address(&image, "TALLY")), 40);
+    MEMORY.load_erasable(crate::memory::ERASABLE_INITIAL);
+
+    // Erased flash has no rope, and a changed word fails the checksum, fixed or erasable
+    assert_eq!(Rope::read(&[0xFF; FLASH_LEN]).unwrap_err(), RopeError::NoRope);
+    let erasable_at = u32::from_le_bytes(flash[20..24].try_into().unwrap()) as usize;
+    let mut changed = flash.clone();
+    changed[HEADER_LEN] ^= 1;
+    assert_eq!(Rope::read(&changed).unwrap_err(), RopeError::Checksum);
+    let mut changed = flash.clone();
+    changed[erasable_at] ^= 1;
+    assert_eq!(Rope::read(&changed).unwrap_err(), RopeError::Checksum);
+
+    // A damaged header is refused, however big what it says is
+    let mut changed = flash.clone();
+    changed[12..14].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert_eq!(Rope::read(&changed).unwrap_err(), RopeError::Invalid);
+    for offset in [16, 20] {
+        let mut changed = flash.clone();
+        changed[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Rope::read(&changed).unwrap_err(), RopeError::Invalid);
+    }
+    flash[8] = 3;
+    assert_eq!(Rope::read(&flash).unwrap_err(), RopeError::Version(3));
+}
@@ -0,0 +1,68 @@
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use crate::faults::dropped;
+use crate::memory::*;
+
+// Scaler periods, in thirds of an MCT so they come out exact
+const TIME1_PERIOD: u32 = 2560; // 10 ms
+const TIME6_PERIOD: u32 = 160; // 1/1600 s
+
+// Channel 13 (octal), bit 15 enables TIME6
+pub const TIME6_CHANNEL: Channel = 11;
+pub const TIME6_ENABLE: Word = 0x4000;
+
+// TIME1 overflows into TIME2 when reaching 2^14
+const TIME1_OVERFLOW: Word = 0x4000;
+
+// Time accumulated towards the next pulse of each counter, in thirds of an MCT
+#[derive(Debug)]
+struct Scaler {
+    time1: AtomicU32,
+    time6: AtomicU32,
+}
+
+static SCALER: Scaler = Scaler { time1: AtomicU32::new(0), time6: AtomicU32::new(0) };
+
+// Adds time to a scaler stage, returns how many pulses it gave
+fn accumulate(stage: &AtomicU32, mct: u32, period: u32) -> u32 {
+    let total = stage.load(Ordering::Relaxed) + 3 * mct;
+    stage.store(total % period, Ordering::Relaxed);
+    total / period
+}
+
+// Counts `mct` MCTs worth of pulses into the timers. Several pulses at once are fine, standby
+// counts in batches
+pub(crate) fn count(mct: u32) {
+    let pulses = accumulate(&SCALER.time1, mct, TIME1_PERIOD);
+    for _ in dropped(TIME1, pulses)..pulses {
+        pinc_time1();
+    }
+
+    let pulses = accumulate(&SCALER.time6, mct, TIME6_PERIOD);
+    let pulses = pulses - dropped(TIME6, pulses);
+    if pulses > 0 && MEMORY.read_channel(TIME6_CHANNEL) & TIME6_ENABLE != 0 {
+        dinc_time6(pulses);
+    }
+}
+
+// TIME1 and TIME2 form a double-precision centisecond clock
+fn pinc_time1() {
+    let time1 = MEMORY.read(TIME1) + 1;
+    if time1 < TIME1_OVERFLOW {
+        MEMORY.write(TIME1, time1);
+    } else {
+        MEMORY.write(TIME1, 0);
+        MEMORY.write(TIME2, (MEMORY.read(TIME2) + 1) % TIME1_OVERFLOW);
+    }
+}
+
+// TIME6 counts down to zero and then stops itself, where the real AGC would request T6RUPT.
+// It's always loaded with positive values
+fn dinc_time6(pulses: u32) {
+    let time6 = MEMORY.read(TIME6).saturating_sub(pulses as u16);
+    MEMORY.write(TIME6, time6);
+    if time6 == 0 {
+        MEMORY.write_channel(TIME6_CHANNEL, MEMORY.read_channel(TIME6_CHANNEL) & !TIME6_ENABLE);
+    }
+}
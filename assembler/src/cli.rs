@@ -0,0 +1,180 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "\
+Usage: assembler [OPTIONS] [FILES]...
+
+Assembles the given source files, in order, into a single fixed memory image. Files ending in .o are
+objects made with --compile, they're linked with the rest without assembling them again.
+
+Options:
+  -m, --manifest <FILE>  Read the list of source files from FILE, one per line, relative to FILE
+  -y, --yayul <FILE>     Assemble FILE too, written in yaYUL's syntax, after the ones before it
+  -c, --compile          Write an object for each source file, NAME.o, to the output directory instead
+                         of linking them
+  -o, --out-dir <DIR>    Directory the output is written to [default: ../agc_emulator/memory]
+  -f, --format <FORMAT>  Output formats, separated by commas [default: rust]:
+                           rust   fixed.in, names.in and erasable.in for the emulator
+                           octal  rope.oct, one word per line, and erasable.oct
+                           bin    rope.bin, the rope as yaYUL writes it
+                           hex    rope.hex, the rope for the Pico's flash as Intel HEX
+                           uf2    rope.uf2, the same for copying to the Pico as a drive
+                           json   rope.json, the rope with its symbols and source map
+                         Every format has a memory map and checksum. Octal and bin can't, they go
+                         in rope.map
+  -l, --listing <FILE>   Write a listing to FILE: the words of every line, with their bank and address,
+                         how much of each bank is used and where every symbol is defined and used
+      --verify           Disassemble the result with the emulator's decoder and check it matches the source,
+                         and with -b that every bank adds up to its number
+  -b, --bugger-words     End every bank with a bugger word, that makes it add up to plus or minus its
+                         number for the rope check
+  -p, --parity           Give every fixed word its odd parity bit, in bit 16, for the emulator's
+                         Parity::FromRope
+  -s, --strict           Operands that aren't declared are errors instead of new variables
+  -D <NAME>=<VALUE>      Define a constant, for IF and for operands
+  -v, --verbose          Print what's being assembled, twice (-vv) for every symbol and word
+  -q, --quiet            Only print errors
+  -h, --help             Print this help";
+
+const DEFAULT_OUT_DIR: &str = "../agc_emulator/memory";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // Files included by the emulator's source
+    Rust,
+    // Octal words, one per line
+    Octal,
+    // yaYUL's binary rope
+    Bin,
+    // The rope for the Pico, at PICO_ROPE_ADDRESS
+    Hex,
+    Uf2,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+impl Verbosity {
+    fn louder(self) -> Self {
+        match self {
+            Verbosity::Quiet => Verbosity::Normal,
+            Verbosity::Normal => Verbosity::Verbose,
+            _ => Verbosity::Debug,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub files: Vec<PathBuf>,
+    // Position of the files in yaYUL's syntax, and of the objects
+    pub yayul: Vec<usize>,
+    pub objects: Vec<usize>,
+    pub compile: bool,
+    pub out_dir: PathBuf,
+    pub formats: Vec<Format>,
+    pub verbosity: Verbosity,
+    pub listing: Option<PathBuf>,
+    pub verify: bool,
+    pub bugger_words: bool,
+    pub parity: bool,
+    pub strict: bool,
+    pub defines: Vec<(String, i64)>,
+}
+
+// Reads the command line. Prints the help and exits when asked for it or when the arguments are wrong
+pub fn parse(args: impl Iterator<Item = String>) -> Options {
+    match try_parse(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            std::process::exit(0)
+        }
+        Err(err) => {
+            eprintln!("error: {err}\n\n{USAGE}");
+            std::process::exit(2)
+        }
+    }
+}
+
+fn try_parse(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut files = vec![];
+    let mut yayul = vec![];
+    let mut compile = false;
+    let mut out_dir = PathBuf::from(DEFAULT_OUT_DIR);
+    let mut formats = vec![Format::Rust];
+    let mut verbosity = Verbosity::Normal;
+    let mut listing = None;
+    let mut verify = false;
+    let mut bugger_words = false;
+    let mut parity = false;
+    let mut strict = false;
+    let mut defines = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-m" | "--manifest" => files.extend(read_manifest(Path::new(&value(&arg)?))?),
+            "-y" | "--yayul" => {
+                yayul.push(files.len());
+                files.push(value(&arg)?.into());
+            }
+            "-c" | "--compile" => compile = true,
+            "-o" | "--out-dir" => out_dir = value(&arg)?.into(),
+            "-f" | "--format" => formats = value(&arg)?.split(',').map(|name| match name {
+                "rust" => Ok(Format::Rust),
+                "octal" => Ok(Format::Octal),
+                "bin" => Ok(Format::Bin),
+                "hex" => Ok(Format::Hex),
+                "uf2" => Ok(Format::Uf2),
+                "json" => Ok(Format::Json),
+                other => Err(format!("unknown format '{other}'")),
+            }).collect::<Result<_, _>>()?,
+            "-l" | "--listing" => listing = Some(value(&arg)?.into()),
+            "--verify" => verify = true,
+            "-b" | "--bugger-words" => bugger_words = true,
+            "-p" | "--parity" => parity = true,
+            "-s" | "--strict" => strict = true,
+            "-D" => {
+                let define = value(&arg)?;
+                let (name, number) = define.split_once('=').ok_or(format!("-D needs NAME=VALUE, not '{define}'"))?;
+                let number = number.parse().map_err(|_| format!("invalid value for {name}: '{number}'"))?;
+                defines.push((name.to_string(), number));
+            }
+            "-q" | "--quiet" => verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => verbosity = verbosity.max(Verbosity::Normal).louder(),
+            "-vv" => verbosity = Verbosity::Debug,
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{arg}'")),
+            _ => files.push(arg.into()),
+        }
+    }
+
+    if files.is_empty() {
+        return Err("no source files given".to_string());
+    }
+    let objects = files.iter().enumerate().filter(|(_, path)| is_object(path)).map(|(i, _)| i).collect();
+    Ok(Some(Options { files, yayul, objects, compile, out_dir, formats, verbosity, listing, verify, bugger_words, parity, strict, defines }))
+}
+
+fn is_object(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "o")
+}
+
+// A manifest lists source files, one per line. Blank lines and lines starting with '#' are skipped
+fn read_manifest(path: &Path) -> Result<Vec<PathBuf>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("can't read manifest {}: {e}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    Ok(text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| dir.join(line))
+        .collect())
+}
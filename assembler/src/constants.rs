@@ -1,6 +1,7 @@
-use crate::DefinedSymbol;
+use crate::types::*;
+use agc_emulator::board::NAMES;
 
-pub const GENERAL: [&'static str; 9] = [
+pub const GENERAL: [&str; 9] = [
     "CA",
     "INDEX",
     "TC",
@@ -12,7 +13,8 @@ pub const GENERAL: [&'static str; 9] = [
     "MP",
 ];
 
-pub const ERASABLE: [&'static str; 14] = [
+// Instructions that only work on erasable memory. Some are extracodes, like DV, and are in EXTENDED too
+pub const ERASABLE: [&str; 14] = [
     "CCS",
     "TS",
     "DIM",
@@ -29,20 +31,93 @@ pub const ERASABLE: [&'static str; 14] = [
     "XCH",
 ];
 
-pub const FIXED: [&'static str; 3] = [
+pub const FIXED: [&str; 3] = [
     "TCF",
     "BZMF",
     "BZF"
 ];
 
-pub const IMPLIED: [&'static str; 3] = [
-    "EXTEND",
-    "RETURN",
-    "COM"
+// Instructions with a name of their own, that are a basic instruction on a given address. The ones that
+// are extracodes are in EXTENDED too
+pub const NAMED: [(&str, &str, u16); 17] = [
+    ("XXALQ", "TC", 0),
+    ("XLQ", "TC", 1),
+    ("RETURN", "TC", 2),
+    ("RELINT", "TC", 3),
+    ("INHINT", "TC", 4),
+    ("EXTEND", "TC", 6),
+    // INDEX BRUPT
+    ("RESUME", "INDEX", 0o17),
+    ("COM", "CS", 0),
+    ("DCOM", "DCS", 0),
+    ("DOUBLE", "AD", 0),
+    ("DDOUBL", "DAS", 0),
+    ("SQUARE", "MP", 0),
+    ("OVSK", "TS", 0),
+    ("ZL", "LXCH", 7),
+    ("ZQ", "QXCH", 7),
+    // DXCH FBANK and DXCH Z, jumps that change the bank too
+    ("DTCF", "DXCH", 4),
+    ("DTCB", "DXCH", 5),
 ];
 
-pub const EXTENDED: [&'static str; 11] = [
+// Words of the code that are the address of their operand instead of an instruction. GENADR and CADR are
+// the address, FCADR and ECADR the address in fixed and erasable memory, and BBCON the bank of a fixed one
+// as BB has it
+pub const ADDRESS_CONSTANTS: [&str; 5] = [
+    "GENADR",
+    "CADR",
+    "FCADR",
+    "ECADR",
+    "BBCON",
+];
+
+// Input and output channel instructions, extracodes on a 9 bit channel number
+pub const CHANNEL: [&str; 8] = [
+    "READ",
+    "WRITE",
+    "RAND",
+    "WAND",
+    "ROR",
+    "WOR",
+    "RXOR",
+    "EDRUPT",
+];
+
+// Instructions working on a pair of words, the operand and the one after it
+pub const DOUBLE: [&str; 4] = [
+    "DCA",
+    "DCS",
+    "DAS",
+    "DXCH",
+];
+
+// Instructions that write their operand. TS is the only one that doesn't read it too
+pub const WRITES: [&str; 10] = [
+    "TS",
+    "XCH",
+    "LXCH",
+    "QXCH",
+    "DXCH",
+    "INCR",
+    "AUG",
+    "DIM",
+    "ADS",
+    "DAS",
+];
+
+pub const EXTENDED: [&str; 21] = [
     "DV",
+    "SQUARE",
+    "ZQ",
+    "READ",
+    "WRITE",
+    "RAND",
+    "WAND",
+    "ROR",
+    "WOR",
+    "RXOR",
+    "EDRUPT",
     "BZF",
     "MSU",
     "QXCH",
@@ -55,28 +130,36 @@ pub const EXTENDED: [&'static str; 11] = [
     "MP",
 ];
 
-pub const ACC: DefinedSymbol = DefinedSymbol::new("ACC", crate::SymbolType::Variable, 0);
-pub const L: DefinedSymbol = DefinedSymbol::new("L", crate::SymbolType::Variable, 1);
-pub const Q: DefinedSymbol = DefinedSymbol::new("Q", crate::SymbolType::Variable, 2);
-pub const Z: DefinedSymbol = DefinedSymbol::new("Z", crate::SymbolType::Variable, 5);
-pub const BB: DefinedSymbol = DefinedSymbol::new("BB", crate::SymbolType::Variable, 6);
-pub const ZERO: DefinedSymbol = DefinedSymbol::new("ZERO", crate::SymbolType::Variable, 7);
-pub const PANT: DefinedSymbol = DefinedSymbol::new("PANT", crate::SymbolType::VariableTable(8), 256);
-pub const BTNUP: DefinedSymbol = DefinedSymbol::new("BTNUP", crate::SymbolType::Variable, 264);
-pub const BTNRGT: DefinedSymbol = DefinedSymbol::new("BTNRGT", crate::SymbolType::Variable, 265);
-pub const BTNDWN: DefinedSymbol = DefinedSymbol::new("BTNDWN", crate::SymbolType::Variable, 266);
-pub const BTNLFT: DefinedSymbol = DefinedSymbol::new("BTNLFT", crate::SymbolType::Variable, 267);
-pub const BTN1: DefinedSymbol = DefinedSymbol::new("BTN1", crate::SymbolType::Variable, 268);
-pub const BTN2: DefinedSymbol = DefinedSymbol::new("BTN2", crate::SymbolType::Variable, 269);
-pub const POTE: DefinedSymbol = DefinedSymbol::new("POTE", crate::SymbolType::Variable, 270);
-pub const CORTO: DefinedSymbol = DefinedSymbol::new("CORTO", crate::SymbolType::Variable, 271);
-pub const MEDIO: DefinedSymbol = DefinedSymbol::new("MEDIO", crate::SymbolType::Variable, 272);
-pub const LARGO: DefinedSymbol = DefinedSymbol::new("LARGO", crate::SymbolType::Variable, 273);
+// Registers and the board's peripherals, at fixed erasable addresses. The board also says where variables
+// and fixed memory go
+pub use agc_emulator::board::{ERASABLE_END, ERASABLE_START, FIXED_BANKS, FIXED_START, RAM_START};
 
-pub const RAM_START: u16 = 274;
-pub const FIXED_START: usize = 2048;
+pub fn predefined() -> Vec<DefinedSymbol> {
+    NAMES.iter().map(|name| {
+        let r#type = if name.len == 1 { SymbolType::Variable } else { SymbolType::VariableTable(name.len) };
+        DefinedSymbol::new(name.name, r#type, name.address)
+    }).collect()
+}
+
+// End of the fixed memory instructions can address without bank switching, fixed-fixed banks 2 and 3
+pub const FIXED_END: u16 = 4096;
+pub const BANK_SIZE: u16 = 1024;
+// Past the fixed-fixed banks a word is at bank * 1024 plus its place in the bank, what its CADR is. Banks
+// 0 and 1 would be at erasable addresses that way, nothing goes there
+pub const ROPE_END: u16 = FIXED_BANKS * BANK_SIZE;
+// Where the rope goes in the Pico's flash, which reads it from there
+pub use agc_emulator::rope::FLASH_ADDRESS as PICO_ROPE_ADDRESS;
+
+// The address instructions use for the word at `location`: the same one in fixed-fixed memory, and its
+// place in the window at 1024 for the switchable banks
+pub fn cpu_address(location: u16) -> u16 {
+    if location < FIXED_END { location } else { BANK_SIZE + location % BANK_SIZE }
+}
 
 pub fn decode(operation: &str) -> u16 {
+    if let Some(&(_, base, address)) = NAMED.iter().find(|(name, ..)| *name == operation) {
+        return decode(base) + address;
+    }
     match operation {
         "CA"=>     0b011000000000000,
         "INDEX"=>  0b101000000000000,
@@ -104,9 +187,14 @@ pub fn decode(operation: &str) -> u16 {
         "TCF"=>    0b001000000000000,
         "BZF"=>    0b001000000000000,
         "BZMF"=>   0b110000000000000,
-        "COM"=>    0b100000000000000,
-        "EXTEND"=> 6,
-        "RETURN"=> 2,
+        "READ"=>   0b000000000000000,
+        "WRITE"=>  0b000001000000000,
+        "RAND"=>   0b000010000000000,
+        "WAND"=>   0b000011000000000,
+        "ROR"=>    0b000100000000000,
+        "WOR"=>    0b000101000000000,
+        "RXOR"=>   0b000110000000000,
+        "EDRUPT"=> 0b000111000000000,
         _ => panic!("INVALID")
     }
 }
\ No newline at end of file
@@ -0,0 +1,254 @@
+use std::fmt;
+
+// Where something is in a source file. Lines and columns start at 1, columns count characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Self {
+        Self { line, column, len }
+    }
+
+    // Span of `token`, which must be a slice of `text`, the line `line` of the source
+    pub fn of(token: &str, text: &str, line: usize) -> Self {
+        let offset = token.as_ptr() as usize - text.as_ptr() as usize;
+        Self::new(line, text[..offset].chars().count() + 1, token.chars().count())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    InvalidSection(String),
+    // There can only be one section of each type, in the order config, erasable, code, data
+    SectionOrder,
+    DuplicatedLabel(String),
+    // Two files export the same label, or a file defines a label it also takes from another
+    LabelInMultipleFiles(String),
+    // A label of another file used without EXTERN, or that the file doesn't export
+    NotImported(String),
+    // EXTERN of a label no other file exports
+    NotExported(String),
+    UndefinedLabel(String),
+    // Only DEC, 2DEC, OCT and 2OCT can go in data
+    OnlyNumbersInData,
+    InvalidNumber(String),
+    NumberOutOfRange(String),
+    MissingOperand,
+    MissingName,
+    // A variable named like an instruction or a directive, or with a name that isn't one
+    InvalidName(String),
+    MissingLength,
+    ExtendedWithoutExtend(String),
+    InvalidInstruction(String),
+    InvalidOperand(String),
+    // Literals are words of fixed memory, for instructions that read one word anywhere
+    LiteralOperand(String),
+    // Addresses can't be added together, only numbers can be added to them
+    InvalidAddressArithmetic,
+    AddressOutOfRange(i64),
+    // The operand goes past the end of a table, or of a double word
+    OutsideOf(String, u16),
+    // Constants are numbers, not addresses
+    ConstantIsAddress(String),
+    // Constants can only use the constants defined before them
+    UndefinedConstant(String),
+    SymbolDefinedTwice(String),
+    // The operand is in fixed memory but the instruction works on erasable only
+    ExpectedErasable(String),
+    // The operand is in erasable memory but the instruction works on fixed only
+    ExpectedFixed(String),
+    // Two things placed on the same fixed address
+    Overlap(u16),
+    // Two things placed on the same erasable words
+    ErasableOverlap(String, String),
+    ErasableFull(String),
+    FixedFull,
+    BankFull(u16),
+    // A file that doesn't fit in fixed-fixed memory has to fit in a bank, with this many words
+    FileOverBank(u16),
+    // Banks go from 2 to 31
+    NoSuchBank(u16),
+    // The operand is in a switchable bank that isn't the one of the instruction, only TC gets there
+    OtherBank(String, u16),
+    // The stubs and FARCALL for the calls to other banks don't fit in fixed-fixed memory
+    NoRoomForFarCalls,
+    // Every word of the bank is used, there's none left for the bugger word
+    NoRoomForBugger(u16),
+    // A file given as an object that isn't one
+    InvalidObject,
+    // In strict mode every variable has to be declared
+    Undeclared(String),
+    MacroInMacro,
+    MacroArguments(String, usize),
+    MacroTooDeep(String),
+    // ELSE, ENDIF or ENDM without what they close
+    Unexpected(String),
+    // IF without ENDIF, or MACRO without ENDM
+    Unclosed(String),
+    // The emulator decodes the word as another instruction than the source has
+    DecodesAs(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarningKind {
+    // Text after the operand that isn't a comment
+    TrailingText,
+    // EXTEND followed by a basic instruction, which then runs as the extracode with its opcode
+    BasicAfterExtend(String),
+    // A label no instruction refers to
+    UnusedLabel(String),
+    WrittenNeverRead(String),
+    ReadNeverWritten(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    // Position of the file in the list given to `link`. Errors from `parse` don't know it
+    pub file: Option<usize>,
+    pub span: Span,
+}
+impl Error {
+    pub fn new(kind: ErrorKind, span: Span) -> Self {
+        Self { kind, file: None, span }
+    }
+
+    pub fn in_file(self, file: usize) -> Self {
+        Self { file: Some(file), ..self }
+    }
+
+    // Shows the error with the source line it points at, the way rustc does
+    pub fn render(&self, path: &str, source: &str) -> String {
+        render("error", &self.kind.to_string(), path, source, self.span)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    // Position of the file in the list given to `link`. Warnings from `parse` don't know it
+    pub file: Option<usize>,
+    pub span: Span,
+}
+impl Warning {
+    pub fn new(kind: WarningKind, span: Span) -> Self {
+        Self { kind, file: None, span }
+    }
+
+    pub fn in_file(self, file: usize) -> Self {
+        Self { file: Some(file), ..self }
+    }
+
+    pub fn render(&self, path: &str, source: &str) -> String {
+        render("warning", &self.kind.to_string(), path, source, self.span)
+    }
+}
+
+//  error: invalid instruction 'FOO'
+//   --> programs/if.agc:12:5
+//     |
+//  12 |     FOO A
+//     |     ^^^
+fn render(level: &str, message: &str, path: &str, source: &str, span: Span) -> String {
+    let text = source.lines().nth(span.line.wrapping_sub(1)).unwrap_or("");
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    // Tabs are kept so the carets line up with the text above them
+    let indent: String = text.chars().take(span.column.saturating_sub(1)).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+
+    format!(
+        "{level}: {message}\n{gutter}--> {path}:{}:{}\n{gutter} |\n{number} | {text}\n{gutter} | {indent}{}\n",
+        span.line, span.column, "^".repeat(span.len.max(1)),
+    )
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::InvalidSection(name) => write!(f, "invalid section name '{name}'"),
+            ErrorKind::SectionOrder => write!(f, "there can only be one section of each type, and should be in the order 'config', 'erasable', 'code', 'data'"),
+            ErrorKind::DuplicatedLabel(name) => write!(f, "duplicated label '{name}'"),
+            ErrorKind::LabelInMultipleFiles(name) => write!(f, "label '{name}' defined in multiple files"),
+            ErrorKind::NotImported(name) => write!(f, "label '{name}' belongs to another file, it has to be exported there and declared with EXTERN here"),
+            ErrorKind::NotExported(name) => write!(f, "no other file exports label '{name}'"),
+            ErrorKind::UndefinedLabel(name) => write!(f, "label '{name}' never defined"),
+            ErrorKind::OnlyNumbersInData => write!(f, "only DEC, 2DEC, OCT and 2OCT in data"),
+            ErrorKind::InvalidNumber(number) => write!(f, "invalid number '{number}'"),
+            ErrorKind::NumberOutOfRange(number) => write!(f, "number '{number}' doesn't fit"),
+            ErrorKind::MissingOperand => write!(f, "no operand"),
+            ErrorKind::MissingName => write!(f, "no name"),
+            ErrorKind::InvalidName(name) => write!(f, "'{name}' can't be the name of a variable"),
+            ErrorKind::MissingLength => write!(f, "no length"),
+            ErrorKind::ExtendedWithoutExtend(op) => write!(f, "extended instruction {op} not preceded by EXTEND"),
+            ErrorKind::InvalidInstruction(op) => write!(f, "invalid instruction '{op}'"),
+            ErrorKind::InvalidOperand(operand) => write!(f, "invalid operand '{operand}'"),
+            ErrorKind::LiteralOperand(op) => write!(f, "{op} can't take a literal, it needs a word in erasable memory or two words"),
+            ErrorKind::InvalidAddressArithmetic => write!(f, "addresses can't be added together or negated, only numbers can be added to them"),
+            ErrorKind::AddressOutOfRange(address) => write!(f, "address {address} is out of the instruction's range"),
+            ErrorKind::OutsideOf(name, len) => write!(f, "the operand is outside of '{name}', which is {len} words long"),
+            ErrorKind::ConstantIsAddress(name) => write!(f, "constant '{name}' is an address, constants must be numbers"),
+            ErrorKind::UndefinedConstant(name) => write!(f, "constant '{name}' isn't defined before it's used"),
+            ErrorKind::SymbolDefinedTwice(name) => write!(f, "'{name}' is defined more than once"),
+            ErrorKind::ExpectedErasable(op) => write!(f, "the operand is a position in fixed memory but {op} works on erasable only"),
+            ErrorKind::ExpectedFixed(op) => write!(f, "the operand is a position in erasable memory but {op} works on fixed only"),
+            ErrorKind::Overlap(address) => write!(f, "address {address} is already used"),
+            ErrorKind::ErasableOverlap(name, other) => write!(f, "'{name}' overlaps '{other}' in erasable memory"),
+            ErrorKind::ErasableFull(name) => write!(f, "no room left in erasable memory for '{name}'"),
+            ErrorKind::FixedFull => write!(f, "this goes past the end of fixed memory"),
+            ErrorKind::BankFull(bank) => write!(f, "bank {bank} is full"),
+            ErrorKind::FileOverBank(words) => write!(f, "this file has {words} words, more than a bank can hold"),
+            ErrorKind::NoSuchBank(bank) => write!(f, "there's no bank {bank}, fixed banks go from 2 to 31"),
+            ErrorKind::OtherBank(name, bank) => {
+                write!(f, "'{name}' is in bank {bank}, only a TC can reach it from another bank")
+            }
+            ErrorKind::NoRoomForFarCalls => write!(f, "no room left in fixed-fixed memory for the calls to other banks"),
+            ErrorKind::NoRoomForBugger(bank) => write!(f, "bank {bank} is full, there's no word left for its bugger word"),
+            ErrorKind::InvalidObject => write!(f, "this isn't an object the assembler wrote, or it's damaged"),
+            ErrorKind::Undeclared(name) => write!(f, "'{name}' is not declared, variables go in the erasable section"),
+            ErrorKind::MacroInMacro => write!(f, "macros can't be defined inside a macro"),
+            ErrorKind::MacroArguments(name, count) => write!(f, "macro '{name}' takes {count} argument(s)"),
+            ErrorKind::MacroTooDeep(name) => write!(f, "macro '{name}' is used inside itself too many times"),
+            ErrorKind::Unexpected(directive) => write!(f, "{directive} without what it closes"),
+            ErrorKind::Unclosed(directive) => write!(f, "{directive} is never closed"),
+            ErrorKind::DecodesAs(expected, found) => write!(f, "'{expected}' was assembled, but the emulator decodes it as '{found}'"),
+        }
+    }
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WarningKind::TrailingText => write!(f, "text after the operand is ignored, comments start with '#'"),
+            WarningKind::BasicAfterExtend(op) => write!(f, "{op} is not an extracode, after EXTEND it runs as a different instruction"),
+            WarningKind::UnusedLabel(name) => write!(f, "label '{name}' is never used"),
+            WarningKind::WrittenNeverRead(name) => write!(f, "variable '{name}' is written but never read"),
+            WarningKind::ReadNeverWritten(name) => write!(f, "variable '{name}' is read but never written"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.column, self.kind)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// What's returned when assembling fails: every error found, and the warnings found along with them
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Diagnostics {
+    pub errors: Vec<Error>,
+    pub warnings: Vec<Warning>,
+}
+impl Diagnostics {
+    pub fn in_file(self, file: usize) -> Self {
+        Self {
+            errors: self.errors.into_iter().map(|e| e.in_file(file)).collect(),
+            warnings: self.warnings.into_iter().map(|w| w.in_file(file)).collect(),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::error::ErrorKind;
+use crate::numbers::parse_integer;
+
+// Operand of an instruction, like `TABLE+WIDTH-1`. It's only evaluated when linking, once every symbol
+// has an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+}
+impl Expr {
+    // The symbol alone, when the expression is nothing else
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Expr::Symbol(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    // Every symbol the expression refers to
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => vec![],
+            Expr::Symbol(name) => vec![name],
+            Expr::Add(a, b) | Expr::Sub(a, b) => [a.symbols(), b.symbols()].concat(),
+            Expr::Neg(a) => a.symbols(),
+        }
+    }
+
+    // Replaces the symbols f gives a new name for
+    pub fn rename(&mut self, f: &impl Fn(&str) -> Option<String>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Symbol(name) => {
+                if let Some(new) = f(name) {
+                    *name = new;
+                }
+            }
+            Expr::Add(a, b) | Expr::Sub(a, b) => {
+                a.rename(f);
+                b.rename(f);
+            }
+            Expr::Neg(a) => a.rename(f),
+        }
+    }
+
+    // Computes the value given the value of each symbol
+    pub fn eval(&self, symbol: &impl Fn(&str) -> Result<Value, ErrorKind>) -> Result<Value, ErrorKind> {
+        let value = self.value(symbol)?;
+        if value.sign < 0 {
+            return Err(ErrorKind::InvalidAddressArithmetic);
+        }
+        Ok(value)
+    }
+
+    fn value(&self, symbol: &impl Fn(&str) -> Result<Value, ErrorKind>) -> Result<Value, ErrorKind> {
+        Ok(match self {
+            Expr::Number(n) => Value::number(*n),
+            Expr::Symbol(name) => symbol(name)?,
+            Expr::Add(a, b) => a.value(symbol)?.add(b.value(symbol)?)?,
+            Expr::Sub(a, b) => a.value(symbol)?.add(b.value(symbol)?.neg()?)?,
+            Expr::Neg(a) => a.value(symbol)?.neg()?,
+        })
+    }
+}
+
+// Result of an expression: a plain number, or an address with the symbol it's relative to.
+// Only numbers can be added to an address, and the difference of two addresses is a number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub value: i64,
+    // Symbol the address is relative to
+    pub base: Option<String>,
+    // How many times the address is counted: 1 for addresses, 0 for numbers, -1 for the negated
+    // addresses an expression can have along the way
+    sign: i64,
+}
+impl Value {
+    pub fn number(value: i64) -> Self {
+        Self { value, base: None, sign: 0 }
+    }
+
+    pub fn address(name: &str, value: i64) -> Self {
+        Self { value, base: Some(name.to_string()), sign: 1 }
+    }
+
+    pub fn is_address(&self) -> bool {
+        self.sign == 1
+    }
+
+    fn add(self, other: Value) -> Result<Value, ErrorKind> {
+        let sign = self.sign + other.sign;
+        if sign.abs() > 1 {
+            return Err(ErrorKind::InvalidAddressArithmetic);
+        }
+        let base = if self.sign == sign { self.base } else { other.base };
+        let value = self.value.checked_add(other.value)
+            .ok_or_else(|| ErrorKind::NumberOutOfRange(format!("{}+{}", self.value, other.value)))?;
+        Ok(Value { value, base: if sign == 0 { None } else { base }, sign })
+    }
+
+    fn neg(self) -> Result<Value, ErrorKind> {
+        let value = self.value.checked_neg().ok_or_else(|| ErrorKind::NumberOutOfRange(format!("-({})", self.value)))?;
+        Ok(Value { value, base: self.base, sign: -self.sign })
+    }
+}
+
+// Parses an operand. Names start with a letter, numbers with a digit, and they can be combined
+// with + - and parentheses, without spaces. `1b` and `1f` are the numeric label 1 before and after
+pub fn parse_expr(text: &str) -> Result<Expr, ErrorKind> {
+    let mut parser = Parser { text, pos: 0 };
+    let expr = parser.sum()?;
+    if parser.pos != text.len() {
+        return Err(ErrorKind::InvalidOperand(text.to_string()));
+    }
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn invalid(&self) -> ErrorKind {
+        ErrorKind::InvalidOperand(self.text.to_string())
+    }
+
+    // term (('+' | '-') term)*
+    fn sum(&mut self) -> Result<Expr, ErrorKind> {
+        let mut expr = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let rhs = Box::new(self.term()?);
+            expr = if op == '+' { Expr::Add(Box::new(expr), rhs) } else { Expr::Sub(Box::new(expr), rhs) };
+        }
+        Ok(expr)
+    }
+
+    // '-' term | '(' sum ')' | number | name
+    fn term(&mut self) -> Result<Expr, ErrorKind> {
+        match self.peek().ok_or(self.invalid())? {
+            '-' => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.term()?)))
+            }
+            '(' => {
+                self.pos += 1;
+                let expr = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err(self.invalid());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let len = self.text[self.pos..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(self.text.len() - self.pos);
+                let token = &self.text[self.pos..self.pos + len];
+                self.pos += len;
+                if is_numeric_reference(token) {
+                    Ok(Expr::Symbol(token.to_string()))
+                } else if c.is_ascii_digit() {
+                    let (_, n) = parse_integer(token).ok_or(self.invalid())?;
+                    Ok(Expr::Number(i64::try_from(n).map_err(|_| ErrorKind::NumberOutOfRange(token.to_string()))?))
+                } else {
+                    Ok(Expr::Symbol(token.to_string()))
+                }
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+}
+
+// `1b` or `1f`
+pub fn is_numeric_reference(token: &str) -> bool {
+    let Some(digits) = token.strip_suffix('b').or(token.strip_suffix('f')) else {
+        return false;
+    };
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
@@ -0,0 +1,85 @@
+use crate::constants::*;
+
+const Q: u16 = 2;
+const FB: u16 = 4;
+
+pub const FARCALL_LEN: u16 = 33;
+pub const STUB_LEN: u16 = 5;
+// Far calls that can be in progress at once, each one a routine reached through FARCALL calling another bank
+pub const FARCALL_DEPTH: u16 = 4;
+// Erasable words FARCALL keeps A, the return address and the stack pointer in, and then its stack with
+// the return address and the caller's bank of each call
+pub const SAVED_LEN: u16 = 3 + 2 * FARCALL_DEPTH;
+
+// Calls from a bank to a routine in another one. The TC goes to a stub for the routine in fixed-fixed
+// memory, which keeps A and hands FARCALL the return address and the routine's 2CADR:
+//
+//     XCH   SAVED
+//     CA    Q
+//     TC    FARCALL
+//     2CADR ROUTINE
+//
+// FARCALL pushes where to return and the caller's bank, switches to the routine's bank and calls it with A
+// as the caller left it. The routine returns to FARCALL, which pops them, switches the caller's bank back
+// and returns there with A as the routine left it. L isn't touched. The routine can call other banks too,
+// up to FARCALL_DEPTH calls deep. The stack wraps around, so going deeper loses the oldest calls but never
+// writes past it
+pub fn farcall(start: u16, saved: u16) -> Vec<u16> {
+    let (a, back, sp, stack) = (saved, saved + 1, saved + 2, saved + 3);
+    // Where the two constants go, after the code
+    let (two, wrap) = (start + FARCALL_LEN - 2, start + FARCALL_LEN - 1);
+    vec![
+        op("INDEX", sp),
+        op("XCH", stack),
+        op("CA", FB),
+        op("INDEX", sp),
+        op("XCH", stack + 1),
+        op("CA", sp),
+        op("AD", two),
+        op("MASK", wrap),
+        op("TS", sp),
+        // The BBCON, Q points at the 2CADR
+        op("INDEX", Q),
+        op("CA", 1),
+        op("XCH", FB),
+        // And the GENADR
+        op("INDEX", Q),
+        op("CA", 0),
+        op("XCH", a),
+        op("INDEX", a),
+        op("TC", 0),
+        // The routine returns here
+        op("XCH", a),
+        op("CA", sp),
+        op("AD", wrap),
+        op("MASK", wrap),
+        op("TS", sp),
+        op("INDEX", sp),
+        op("CA", stack + 1),
+        op("XCH", FB),
+        op("INDEX", sp),
+        op("CA", stack),
+        op("XCH", back),
+        op("CA", a),
+        op("INDEX", back),
+        op("TC", 0),
+        // Adding 2 moves up the stack and adding 2 * FARCALL_DEPTH - 2 moves down, with the mask wrapping
+        // both around
+        2,
+        2 * FARCALL_DEPTH - 2,
+    ]
+}
+
+pub fn stub(saved: u16, farcall: u16, routine: u16) -> Vec<u16> {
+    vec![
+        op("XCH", saved),
+        op("CA", Q),
+        op("TC", farcall),
+        cpu_address(routine),
+        routine / BANK_SIZE * BANK_SIZE,
+    ]
+}
+
+fn op(operation: &str, address: u16) -> u16 {
+    decode(operation) + address
+}
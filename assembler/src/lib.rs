@@ -0,0 +1,67 @@
+pub mod constants;
+pub mod error;
+pub mod expr;
+mod far;
+mod link;
+mod listing;
+mod macros;
+mod numbers;
+mod object;
+mod output;
+mod parse;
+pub mod types;
+mod verify;
+mod yayul;
+#[cfg(test)]
+mod tests;
+
+pub use error::{Diagnostics, Error, ErrorKind, Span, Warning, WarningKind};
+pub use link::link;
+pub use macros::{Definitions, Macro};
+pub use object::{object_source, read_object};
+pub use parse::{parse, parse_with};
+pub use types::{Ast, Image, Options};
+pub use yayul::parse_yayul;
+
+// Parses and links the sources, in order. Linking only happens if every file parsed
+pub fn assemble(sources: &[&str]) -> Result<Image, Diagnostics> {
+    assemble_with(sources, &Options::default())
+}
+
+// Macros and constants of a file can be used by the files after it
+pub fn assemble_with(sources: &[&str], options: &Options) -> Result<Image, Diagnostics> {
+    link(&parse_files(sources, options)?, options)
+}
+
+// Parses the sources, in order, or reads them if they're objects. Macros and constants of a file can be used
+// by the files after it
+pub fn parse_files(sources: &[&str], options: &Options) -> Result<Vec<Ast>, Diagnostics> {
+    let mut asts = vec![];
+    let mut failed = Diagnostics::default();
+    let mut definitions = Definitions::new(&options.defines);
+    for (i, source) in sources.iter().enumerate() {
+        let ast = if options.objects.contains(&i) {
+            let ast = read_object(source).map_err(|error| Diagnostics { errors: vec![error], warnings: vec![] });
+            if let Ok(ast) = &ast {
+                definitions.add(ast);
+            }
+            ast
+        } else if options.yayul.contains(&i) {
+            parse_yayul(source)
+        } else {
+            parse_with(source, &mut definitions)
+        };
+        match ast {
+            Ok(ast) => asts.push(ast),
+            Err(diagnostics) => {
+                let diagnostics = diagnostics.in_file(i);
+                failed.errors.extend(diagnostics.errors);
+                failed.warnings.extend(diagnostics.warnings);
+            }
+        }
+    }
+    if !failed.errors.is_empty() {
+        return Err(failed);
+    }
+    Ok(asts)
+}
@@ -0,0 +1,735 @@
+use crate::constants::*;
+use crate::error::*;
+use crate::expr::*;
+use crate::far::*;
+use crate::types::*;
+use agc_emulator::memory::{bank_sum, parity_bit};
+
+// Places the code and data of every file, gives an erasable address to every variable and assembles
+// every instruction. Every error is reported, not only the first one
+pub fn link(files: &[Ast], options: &Options) -> Result<Image, Diagnostics> {
+    let mut errors = vec![];
+    let mut warnings = vec![];
+
+    let tables: Vec<&UndefinedTable> = files.iter().flat_map(|ast| &ast.tables).collect();
+    let table_len = |name: &str| tables.iter().find(|e| e.name == name).map_or(0, |e| e.len);
+
+    let mut defined: Vec<DefinedSymbol> = predefined();
+    let mut erasable = Erasable::new(&defined);
+
+    let mut layout = layout(files, &mut errors);
+
+    // Variables whose reads and writes are checked, with where they're declared or first used and whether
+    // they have an initial value
+    let mut tracked: Vec<(&str, usize, Span, bool)> = vec![];
+    let mut initial: Vec<(u16, u16)> = vec![];
+
+    // Define all labels. Each file has its own, only the exported ones have to be unique
+    for (file_index, ast) in files.iter().enumerate() {
+        for label in &ast.labels {
+            let address = layout.address(file_index, label.section, label.offset);
+            let exported = ast.exports.iter().any(|(name, _)| *name == label.name);
+            let defined_label = label.define(address, table_len(&label.name)).from_file(file_index, exported);
+            let imported = ast.externs.iter().any(|(name, _)| *name == label.name);
+            let clash = defined.iter().filter(|s| s.name == label.name).any(|s| match s.file {
+                None => true,
+                Some(_) => imported || (exported && s.exported),
+            });
+            if clash {
+                let kind = ErrorKind::LabelInMultipleFiles(defined_label.name);
+                errors.push(Error::new(kind, label.span).in_file(file_index));
+                continue;
+            }
+            defined.push(defined_label);
+        }
+    }
+    for (file_index, ast) in files.iter().enumerate() {
+        for (name, span) in &ast.exports {
+            if !ast.labels.iter().any(|label| label.name == *name) {
+                errors.push(Error::new(ErrorKind::UndefinedLabel(name.clone()), *span).in_file(file_index));
+            }
+        }
+        for (name, span) in &ast.externs {
+            if !defined.iter().any(|s| s.name == *name && s.exported && s.file != Some(file_index)) {
+                errors.push(Error::new(ErrorKind::NotExported(name.clone()), *span).in_file(file_index));
+            }
+        }
+    }
+
+    // Reservations with an address go first, so the rest can go around them
+    let erased: Vec<(usize, &Erase)> = files.iter().enumerate()
+        .flat_map(|(i, ast)| ast.erased.iter().map(move |e| (i, e)))
+        .collect();
+    let (fixed_place, anywhere): (Vec<_>, Vec<_>) = erased.into_iter().partition(|(_, e)| e.address.is_some());
+    for (file_index, erase) in fixed_place.into_iter().chain(anywhere) {
+        let error = |kind| Error::new(kind, erase.span).in_file(file_index);
+        let name = erase.name.as_deref().unwrap_or("ERASE");
+        if erase.name.is_some() && defined.iter().any(|s| s.name == name) {
+            errors.push(error(ErrorKind::SymbolDefinedTwice(name.to_string())));
+            continue;
+        }
+
+        let address = match erase.address {
+            Some(address) => erasable.reserve(name, address, erase.len).map(|_| address),
+            None => erasable.allocate(name, erase.len),
+        };
+        match address {
+            Ok(address) => {
+                initial.extend(erase.init.iter().enumerate().map(|(i, &word)| (address + i as u16, word)));
+                if erase.name.is_some() {
+                    defined.push(DefinedSymbol::new(name, variable(erase.len), address));
+                    tracked.push((name, file_index, erase.span, !erase.init.is_empty()));
+                }
+            }
+            Err(kind) => errors.push(error(kind)),
+        }
+    }
+
+    // Evaluate the constants in order, each one can only use the ones before it. The ones made with EQUALS
+    // can also use any symbol defined so far
+    let mut constants: Vec<(String, Value)> = options.defines.iter().map(|(n, v)| (n.clone(), Value::number(*v))).collect();
+    for (file_index, ast) in files.iter().enumerate() {
+        for constant in &ast.constants {
+            let error = |kind| Error::new(kind, constant.span).in_file(file_index);
+            let name = &constant.name;
+            if defined.iter().any(|s| &s.name == name) || constants.iter().any(|(n, _)| n == name) {
+                errors.push(error(ErrorKind::SymbolDefinedTwice(name.clone())));
+                continue;
+            }
+
+            let lookup = |symbol: &str| match constants.iter().find(|(n, _)| n == symbol) {
+                Some((_, value)) => Ok(value.clone()),
+                None => match visible(&defined, ast, file_index, symbol) {
+                    Ok(s) if constant.equals => Ok(Value::address(symbol, s.address as i64)),
+                    Err(kind @ ErrorKind::NotImported(_)) if constant.equals => Err(kind),
+                    _ => Err(ErrorKind::UndefinedConstant(symbol.to_string())),
+                },
+            };
+            match constant.expr.eval(&lookup) {
+                Ok(value) if value.is_address() && !constant.equals => {
+                    errors.push(error(ErrorKind::ConstantIsAddress(name.clone())));
+                }
+                Ok(value) => constants.push((name.clone(), value)),
+                Err(kind) => errors.push(error(kind)),
+            }
+        }
+    }
+
+    // Any other name an instruction uses is a variable, unless the instruction only works on fixed memory.
+    // Variables are as long as their table, or two words when double precision instructions use them
+    let mut variables: Vec<(&str, u16, usize, Span)> = vec![];
+    for (file_index, ast) in files.iter().enumerate() {
+        for instruction in &ast.code {
+            let operand = &instruction.operand;
+            // Channels are numbers, not variables
+            if operand.r#type == Some(SymbolType::Label) || CHANNEL.contains(&instruction.operation.as_str()) {
+                continue;
+            }
+            for name in operand.expr.symbols() {
+                if defined.iter().any(|s| s.name == name) || constants.iter().any(|(n, _)| n == name) {
+                    continue;
+                }
+                let double = DOUBLE.contains(&instruction.operation.as_str()) && operand.expr.name() == Some(name);
+                let len = table_len(name).max(if double { 2 } else { 1 });
+                match variables.iter_mut().find(|(n, ..)| *n == name) {
+                    Some((_, old, ..)) => *old = len.max(*old),
+                    None => variables.push((name, len, file_index, operand.span)),
+                }
+            }
+        }
+    }
+    for (name, len, file_index, span) in variables {
+        if options.strict {
+            errors.push(Error::new(ErrorKind::Undeclared(name.to_string()), span).in_file(file_index));
+        }
+        tracked.push((name, file_index, span, false));
+        match erasable.allocate(name, len) {
+            Ok(address) => defined.push(DefinedSymbol::new(name, variable(len), address)),
+            Err(kind) => errors.push(Error::new(kind, span).in_file(file_index)),
+        }
+    }
+
+    let lookup = |file_index: usize, name: &str| match visible(&defined, &files[file_index], file_index, name) {
+        Ok(symbol) => Ok(Value::address(name, symbol.address as i64)),
+        Err(ErrorKind::UndefinedLabel(_)) => match constants.iter().find(|(n, _)| n == name) {
+            Some((_, value)) => Ok(value.clone()),
+            None => Err(ErrorKind::UndefinedLabel(name.to_string())),
+        },
+        Err(kind) => Err(kind),
+    };
+
+    // Assemble all instructions and add the data, each word where the layout put it
+    let mut binary: Vec<u16> = vec![0; (layout.end() - FIXED_START) as usize];
+    let (mut read, mut written) = (vec![], vec![]);
+    let mut instructions = vec![];
+    let mut far_calls: Vec<FarCall> = vec![];
+    for (file_index, ast) in files.iter().enumerate() {
+        for (offset, instruction) in ast.code.iter().enumerate() {
+            let operation = instruction.operation.as_str();
+            let operand = &instruction.operand;
+            let lookup = |name: &str| lookup(file_index, name);
+            let symbol = |name: &str| visible(&defined, ast, file_index, name).ok();
+            // Numbers among the instructions are already the word
+            if operation == "WORD" {
+                let word = operand.expr.eval(&lookup).map_or(0, |value| value.value as u16);
+                layout.store(&mut binary, file_index, Section::Code, offset, word);
+                continue;
+            }
+            let here = layout.address(file_index, Section::Code, offset as u16);
+            let word = match address(operation, operand, &lookup, &symbol) {
+                Ok(value) if ADDRESS_CONSTANTS.contains(&operation) => match operation {
+                    "GENADR" => cpu_address(value.value as u16),
+                    "BBCON" => value.value as u16 / BANK_SIZE * BANK_SIZE,
+                    _ => value.value as u16,
+                },
+                Ok(value) => {
+                    let target = value.value as u16;
+                    let name = value.base.clone().unwrap_or_else(|| target.to_string());
+                    // Words of a switchable bank can only be reached from the same bank
+                    let reachable = target < FIXED_END || target / BANK_SIZE == here / BANK_SIZE;
+                    if !reachable && operation != "TC" {
+                        let kind = ErrorKind::OtherBank(name, target / BANK_SIZE);
+                        errors.push(Error::new(kind, operand.span).in_file(file_index));
+                        continue;
+                    }
+                    if !reachable {
+                        let instruction = instructions.len();
+                        far_calls.push(FarCall { file: file_index, offset, target, name, instruction, stub: 0 });
+                    }
+                    instructions.push(Assembled {
+                        file: file_index,
+                        span: instruction.span,
+                        operation: operation.to_string(),
+                        operand: cpu_address(target),
+                        address: here,
+                        extended: instruction.extended,
+                    });
+                    if let Some(base) = value.base {
+                        if WRITES.contains(&operation) {
+                            written.push(base.clone());
+                        }
+                        if operation != "TS" {
+                            read.push(base);
+                        }
+                    }
+                    decode(operation) + cpu_address(target)
+                }
+                Err(kind) => {
+                    errors.push(Error::new(kind, operand.span).in_file(file_index));
+                    0
+                }
+            };
+            layout.store(&mut binary, file_index, Section::Code, offset, word);
+        }
+        for (offset, &word) in ast.data.iter().enumerate() {
+            layout.store(&mut binary, file_index, Section::Data, offset, word);
+        }
+    }
+
+    if !far_calls.is_empty() {
+        match far_call_words(&mut far_calls, &mut layout, &mut erasable, &mut defined) {
+            Ok((start, words)) => {
+                let end = (start + words.len() as u16 - FIXED_START) as usize;
+                binary.resize(binary.len().max(end), 0);
+                binary[(start - FIXED_START) as usize..end].copy_from_slice(&words);
+                for call in &far_calls {
+                    layout.store(&mut binary, call.file, Section::Code, call.offset, decode("TC") + call.stub);
+                    instructions[call.instruction].operand = call.stub;
+                }
+            }
+            Err(kind) => {
+                let call = &far_calls[0];
+                errors.push(Error::new(kind, instructions[call.instruction].span).in_file(call.file));
+            }
+        }
+    }
+
+    let buggers = if options.bugger_words && errors.is_empty() {
+        bugger_words(files, &mut layout, &mut binary).unwrap_or_else(|error| {
+            errors.push(error);
+            vec![]
+        })
+    } else {
+        vec![]
+    };
+
+    // Words of each COUNT, until the next one of the section
+    let mut counts: Vec<BankCount> = vec![];
+    for (file_index, ast) in files.iter().enumerate() {
+        for (i, count) in ast.counts.iter().enumerate() {
+            let section_len = if count.section == Section::Code { ast.code.len() } else { ast.data.len() } as u16;
+            let end = ast.counts[i + 1..].iter().find(|c| c.section == count.section).map_or(section_len, |c| c.offset);
+            for offset in count.offset..end {
+                let bank = layout.address(file_index, count.section, offset) / BANK_SIZE;
+                match counts.iter_mut().find(|c| c.name == count.name && c.bank == bank) {
+                    Some(c) => c.words += 1,
+                    None => counts.push(BankCount { name: count.name.clone(), bank, words: 1 }),
+                }
+            }
+        }
+    }
+
+    for (file_index, ast) in files.iter().enumerate() {
+        warnings.extend(ast.warnings.iter().map(|w| w.clone().in_file(file_index)));
+
+        // The first label of the rope is where it starts running, it doesn't need to be used. Exported
+        // labels can be used by any file that declares them with EXTERN
+        let used = |name: &str| {
+            let users = files.iter().enumerate().filter(|&(i, other)| {
+                i == file_index || other.imports(name)
+            });
+            let users: Vec<&Ast> = users.map(|(_, other)| other).collect();
+            let operands = users.iter().flat_map(|ast| &ast.code).map(|i| &i.operand.expr);
+            let equates = users.iter().flat_map(|ast| &ast.constants).map(|c| &c.expr);
+            operands.chain(equates).any(|expr| expr.symbols().contains(&name))
+        };
+        for label in &ast.labels {
+            let entry = file_index == 0 && label.section == Section::Code && label.offset == 0;
+            // Numeric labels are for short jumps, nothing is lost if one isn't used
+            let numeric = label.name.contains('@');
+            if !used(&label.name) && !entry && !numeric {
+                let kind = WarningKind::UnusedLabel(label.name.clone());
+                warnings.push(Warning::new(kind, label.span).in_file(file_index));
+            }
+        }
+    }
+
+    for &(name, file_index, span, init) in &tracked {
+        let is_read = read.iter().any(|n| n == name);
+        let is_written = written.iter().any(|n| n == name);
+        // An initial value is as good as a write
+        let kind = if is_written && !is_read {
+            WarningKind::WrittenNeverRead(name.to_string())
+        } else if is_read && !is_written && !init {
+            WarningKind::ReadNeverWritten(name.to_string())
+        } else {
+            continue;
+        };
+        warnings.push(Warning::new(kind, span).in_file(file_index));
+    }
+
+    if !errors.is_empty() {
+        return Err(Diagnostics { errors, warnings });
+    }
+    // Words of every line, for the listing
+    let mut listing = vec![];
+    for (file_index, ast) in files.iter().enumerate() {
+        for line in &ast.lines {
+            let fixed = matches!(line.section, Section::Code | Section::Data);
+            let address = fixed.then(|| layout.address(file_index, line.section, line.offset));
+            let words = (0..line.len).filter_map(|i| {
+                let address = layout.address(file_index, line.section, line.offset + i);
+                binary.get(address.checked_sub(FIXED_START)? as usize).copied()
+            });
+            let (expanded, words) = (line.expanded.clone(), words.collect());
+            listing.push(ListedLine { file: file_index, line: line.line, expanded, address, words });
+        }
+    }
+    let references = references(files, &defined, &constants, &tracked);
+
+    if options.parity {
+        binary.iter_mut().for_each(|word| *word |= parity_bit(*word));
+    }
+    let erasable_end = erasable.end();
+    let banks = (2..FIXED_BANKS).filter_map(|bank| {
+        let range = (bank * BANK_SIZE - FIXED_START) as usize..((bank + 1) * BANK_SIZE - FIXED_START) as usize;
+        let words = layout.used[range].iter().filter(|&&used| used).count() as u16;
+        (words > 0).then_some((bank, words))
+    }).collect();
+    Ok(Image { fixed: binary, symbols: defined, erasable_end, erasable: initial, banks, buggers, parity: options.parity, counts, warnings, listing, references, instructions })
+}
+
+// A TC to a routine in another bank, and the stub it goes to instead
+struct FarCall {
+    file: usize,
+    offset: usize,
+    target: u16,
+    name: String,
+    // Its position in the assembled instructions
+    instruction: usize,
+    stub: u16,
+}
+
+// Gives every bank with words a bugger word, the one that makes the bank add up to plus or minus its number,
+// the way the rope check wants. It goes after the last word of the bank, or in any free word when that's
+// the last one. Returns where each one is and its word
+fn bugger_words(files: &[Ast], layout: &mut Layout, binary: &mut Vec<u16>) -> Result<Vec<(u16, u16)>, Error> {
+    let mut buggers = vec![];
+    for bank in 2..FIXED_BANKS {
+        let (first, end) = (bank * BANK_SIZE, (bank + 1) * BANK_SIZE);
+        if !(first..end).any(|a| layout.used[(a - FIXED_START) as usize]) {
+            continue;
+        }
+        let free = || (first..end).rev().find(|a| !layout.used[(a - FIXED_START) as usize]);
+        let Some(location) = layout.room(bank, 1).or_else(free) else {
+            // The last line in the bank takes the blame
+            let lines = files.iter().enumerate().flat_map(|(i, ast)| ast.lines.iter().map(move |line| (i, line)));
+            let mut in_bank = lines.filter(|(i, line)| {
+                matches!(line.section, Section::Code | Section::Data) && line.len > 0
+                    && layout.address(*i, line.section, line.offset) / BANK_SIZE == bank
+            });
+            let (file, line) = in_bank.next_back().map_or((0, 1), |(i, line)| (i, line.line));
+            return Err(Error::new(ErrorKind::NoRoomForBugger(bank), Span::new(line, 1, 1)).in_file(file));
+        };
+        layout.used[(location - FIXED_START) as usize] = true;
+        binary.resize(binary.len().max((end - FIXED_START) as usize), 0);
+
+        let words = &binary[(first - FIXED_START) as usize..(end - FIXED_START) as usize];
+        let sum = bank_sum(words.iter().copied());
+        let target = if sum & 0o40000 == 0 { bank } else { !bank & 0o77777 };
+        let bugger = bank_sum([target, !sum & 0o77777]);
+        binary[(location - FIXED_START) as usize] = bugger;
+        buggers.push((location, bugger));
+    }
+    // Only up to the last word used
+    let last = (FIXED_START..ROPE_END).rev().find(|a| layout.used[(a - FIXED_START) as usize]).unwrap_or(FIXED_START);
+    binary.truncate((last + 1 - FIXED_START) as usize);
+    Ok(buggers)
+}
+
+// Puts FARCALL and a stub for every routine called from another bank after everything else in fixed-fixed
+// memory, and gives FARCALL its erasable words. Returns where they start and their words
+fn far_call_words(
+    calls: &mut [FarCall],
+    layout: &mut Layout,
+    erasable: &mut Erasable,
+    defined: &mut Vec<DefinedSymbol>,
+) -> Result<(u16, Vec<u16>), ErrorKind> {
+    let mut routines: Vec<(u16, String)> = vec![];
+    for call in calls.iter() {
+        if !routines.iter().any(|(target, _)| *target == call.target) {
+            routines.push((call.target, call.name.clone()));
+        }
+    }
+    let len = FARCALL_LEN + STUB_LEN * routines.len() as u16;
+    let start = [2, 3].into_iter().find_map(|bank| layout.room(bank, len)).ok_or(ErrorKind::NoRoomForFarCalls)?;
+    layout.take(start, len)?;
+    let saved = erasable.allocate("FARCALL", SAVED_LEN)?;
+    defined.push(DefinedSymbol::new("FARCALL@", SymbolType::Label, start));
+    defined.push(DefinedSymbol::new("FARCALL@SAVED", variable(SAVED_LEN), saved));
+
+    let mut words = farcall(start, saved);
+    for (target, name) in routines {
+        let address = start + words.len() as u16;
+        defined.push(DefinedSymbol::new(&format!("{name}@FAR"), SymbolType::Label, address));
+        words.extend(stub(saved, start, target));
+        calls.iter_mut().filter(|call| call.target == target).for_each(|call| call.stub = address);
+    }
+    Ok((start, words))
+}
+
+fn variable(len: u16) -> SymbolType {
+    if len == 1 { SymbolType::Variable } else { SymbolType::VariableTable(len) }
+}
+
+// Where every symbol is defined and used. Registers only show up when they're used
+fn references(
+    files: &[Ast],
+    defined: &[DefinedSymbol],
+    constants: &[(String, Value)],
+    tracked: &[(&str, usize, Span, bool)],
+) -> Vec<Reference> {
+    let mut references: Vec<Reference> = defined.iter().map(|symbol| {
+        let site = match symbol.file {
+            Some(file) => files[file].labels.iter().find(|l| l.name == symbol.name).map(|l| (file, l.span)),
+            None => tracked.iter().find(|(name, ..)| *name == symbol.name).map(|&(_, file, span, _)| (file, span)),
+        };
+        let r#type = Some(symbol.r#type);
+        Reference { name: symbol.name.clone(), r#type, value: symbol.address as i64, address: true, defined: site, used: vec![] }
+    }).collect();
+    for (name, value) in constants {
+        let site = files.iter().enumerate()
+            .find_map(|(file, ast)| ast.constants.iter().find(|c| c.name == *name).map(|c| (file, c.span)));
+        let address = value.base.is_some();
+        references.push(Reference { name: name.clone(), r#type: None, value: value.value, address, defined: site, used: vec![] });
+    }
+
+    for (file_index, ast) in files.iter().enumerate() {
+        let operands = ast.code.iter().map(|i| (&i.operand.expr, i.operand.span));
+        let equates = ast.constants.iter().map(|c| (&c.expr, c.span));
+        for (expr, span) in operands.chain(equates) {
+            for name in expr.symbols() {
+                let index = match visible(defined, ast, file_index, name) {
+                    Ok(symbol) => defined.iter().position(|s| std::ptr::eq(s, symbol)),
+                    Err(_) => constants.iter().position(|(n, _)| n == name).map(|i| defined.len() + i),
+                };
+                if let Some(index) = index {
+                    references[index].used.push((file_index, span));
+                }
+            }
+        }
+    }
+
+    references.retain(|r| r.defined.is_some() || !r.used.is_empty());
+    references.sort_by(|a, b| a.name.cmp(&b.name));
+    references
+}
+
+// The symbol a name means in a file: a label of its own, an exported label it declares with EXTERN, or a
+// variable or register. A label of another file that isn't imported is an error of its own
+fn visible<'a>(defined: &'a [DefinedSymbol], ast: &Ast, file: usize, name: &str) -> Result<&'a DefinedSymbol, ErrorKind> {
+    let imported = ast.imports(name);
+    let mut same_name = defined.iter().filter(|s| s.name == name).peekable();
+    if same_name.peek().is_none() {
+        return Err(ErrorKind::UndefinedLabel(name.to_string()));
+    }
+    same_name
+        .find(|s| s.file.is_none() || s.file == Some(file) || (s.exported && imported))
+        .ok_or_else(|| ErrorKind::NotImported(name.to_string()))
+}
+
+// Evaluates the operand and checks the instruction can reach the address it gives
+fn address<'a>(
+    operation: &str,
+    operand: &UndefinedSymbol,
+    lookup: &impl Fn(&str) -> Result<Value, ErrorKind>,
+    symbol: &impl Fn(&str) -> Option<&'a DefinedSymbol>,
+) -> Result<Value, ErrorKind> {
+    let value = operand.expr.eval(lookup)?;
+    // Double precision instructions also use the word after the operand
+    let last = if DOUBLE.contains(&operation) { value.value + 1 } else { value.value };
+
+    if let Some(symbol) = value.base.as_deref().and_then(symbol) {
+        let fixed = matches!(symbol.r#type, SymbolType::Label | SymbolType::LabelTable(_));
+        if operand.r#type == Some(SymbolType::Variable) && fixed {
+            return Err(ErrorKind::ExpectedErasable(operation.to_string()));
+        }
+        if operand.r#type == Some(SymbolType::Label) && !fixed {
+            return Err(ErrorKind::ExpectedFixed(operation.to_string()));
+        }
+
+        if let SymbolType::LabelTable(len) | SymbolType::VariableTable(len) = symbol.r#type {
+            let start = symbol.address as i64;
+            if value.value < start || last >= start + len as i64 {
+                return Err(ErrorKind::OutsideOf(symbol.name.clone(), len));
+            }
+        }
+    }
+
+    // Erasable only instructions have 10 bits of address, channel instructions 9 and the rest 12. Words in
+    // the switchable banks are checked against the bank of the instruction later
+    let end = ROPE_END as i64 - 1;
+    let range = if ERASABLE.contains(&operation) || operand.r#type == Some(SymbolType::Variable) {
+        0..=1023
+    } else if FIXED.contains(&operation) || operand.r#type == Some(SymbolType::Label) {
+        1024..=end
+    } else if CHANNEL.contains(&operation) {
+        0..=511
+    } else {
+        0..=end
+    };
+    if !range.contains(&value.value) || !range.contains(&last) {
+        return Err(ErrorKind::AddressOutOfRange(value.value));
+    }
+    Ok(value)
+}
+
+// Erasable words given out so far, and what to
+struct Erasable {
+    taken: Vec<(u16, u16, String)>,
+}
+impl Erasable {
+    fn new(predefined: &[DefinedSymbol]) -> Self {
+        Self { taken: predefined.iter().map(|s| (s.address, s.r#type.words(), s.name.clone())).collect() }
+    }
+
+    fn overlapping(&self, address: u16, len: u16) -> Option<&(u16, u16, String)> {
+        self.taken.iter().find(|(start, words, _)| address < start + words && *start < address + len)
+    }
+
+    // Takes the words from `address` on
+    fn reserve(&mut self, name: &str, address: u16, len: u16) -> Result<(), ErrorKind> {
+        if address as u32 + len as u32 > ERASABLE_END as u32 {
+            return Err(ErrorKind::ErasableFull(name.to_string()));
+        }
+        if let Some((_, _, other)) = self.overlapping(address, len) {
+            return Err(ErrorKind::ErasableOverlap(name.to_string(), other.clone()));
+        }
+        self.taken.push((address, len, name.to_string()));
+        Ok(())
+    }
+
+    // Takes the first free words from RAM_START on
+    fn allocate(&mut self, name: &str, len: u16) -> Result<u16, ErrorKind> {
+        let mut address = RAM_START;
+        while let Some(&(start, words, _)) = self.overlapping(address, len) {
+            address = start + words;
+        }
+        self.reserve(name, address, len)?;
+        Ok(address)
+    }
+
+    // First address after everything given out from RAM_START on
+    fn end(&self) -> u16 {
+        self.taken.iter().map(|(start, words, _)| start + words).filter(|&end| end > RAM_START).max().unwrap_or(RAM_START)
+    }
+}
+
+// Address of every word of code and data of every file. Each list has one more address, where a label at
+// the end of the section points. Words past the fixed-fixed banks are at bank * 1024 plus their place in
+// the bank
+struct Layout {
+    code: Vec<Vec<u16>>,
+    data: Vec<Vec<u16>>,
+    // Fixed words taken, from FIXED_START on
+    used: Vec<bool>,
+}
+impl Layout {
+    fn addresses(&mut self, file: usize, section: Section) -> &mut Vec<u16> {
+        if section == Section::Data { &mut self.data[file] } else { &mut self.code[file] }
+    }
+
+    fn address(&self, file: usize, section: Section, offset: u16) -> u16 {
+        let addresses = if section == Section::Data { &self.data[file] } else { &self.code[file] };
+        addresses[offset as usize]
+    }
+
+    // First address after every word placed
+    fn end(&self) -> u16 {
+        let last = |addresses: &Vec<u16>| addresses[..addresses.len() - 1].iter().map(|&a| a.saturating_add(1)).max();
+        self.code.iter().chain(&self.data).filter_map(last).max().unwrap_or(FIXED_START).min(ROPE_END)
+    }
+
+    fn store(&self, binary: &mut [u16], file: usize, section: Section, offset: usize, word: u16) {
+        let address = self.address(file, section, offset as u16);
+        if (FIXED_START..ROPE_END).contains(&address) {
+            binary[(address - FIXED_START) as usize] = word;
+        }
+    }
+
+    // First address of the bank after every word already in it, if `len` more words fit there
+    fn room(&self, bank: u16, len: u16) -> Option<u16> {
+        let (first, end) = (bank * BANK_SIZE, (bank + 1) * BANK_SIZE);
+        let free = (first..end).rev().find(|a| self.used[(a - FIXED_START) as usize]).map_or(first, |a| a + 1);
+        (free + len <= end).then_some(free)
+    }
+
+    // Puts the segment from `start` on, as long as it fits and nothing else is there
+    fn place(&mut self, segment: &Segment, start: u16) -> Result<(), ErrorKind> {
+        let last = if segment.last { segment.end + 1 } else { segment.end };
+        let addresses = self.addresses(segment.file, segment.section);
+        for offset in segment.start..last {
+            addresses[offset as usize] = start.saturating_add(offset - segment.start);
+        }
+        self.take(start, segment.end - segment.start)
+    }
+
+    // Takes `len` words from `start` on. Out of the fixed-fixed banks they can't go past the end of the bank
+    fn take(&mut self, start: u16, len: u16) -> Result<(), ErrorKind> {
+        if start as u32 + len as u32 > ROPE_END as u32 {
+            return Err(ErrorKind::FixedFull);
+        }
+        if start >= FIXED_END && len > 0 && (start + len - 1) / BANK_SIZE != start / BANK_SIZE {
+            return Err(ErrorKind::BankFull(start / BANK_SIZE));
+        }
+        let range = (start - FIXED_START) as usize..(start - FIXED_START + len) as usize;
+        if let Some(i) = range.clone().find(|&i| self.used[i]) {
+            return Err(ErrorKind::Overlap(FIXED_START + i as u16));
+        }
+        self.used[range].fill(true);
+        Ok(())
+    }
+}
+
+// Consecutive words of a section of a file that go together
+struct Segment<'a> {
+    file: usize,
+    section: Section,
+    start: u16,
+    end: u16,
+    // Whether the section ends with it
+    last: bool,
+    placement: Option<&'a Placement>,
+}
+
+// Words without a placement go one after the other from the start of fixed memory, the code of every file
+// and then the data. The ones after a SETLOC go to its address, and the ones after a BANK or BLOCK go after
+// whatever else is in the bank. When fixed-fixed memory can't hold every file, the ones that don't fit go
+// whole to the first switchable bank with room, so their code and data reach each other
+fn layout(files: &[Ast], errors: &mut Vec<Error>) -> Layout {
+    let mut layout = Layout { code: vec![], data: vec![], used: vec![false; (ROPE_END - FIXED_START) as usize] };
+    let mut segments = vec![];
+    for (file, ast) in files.iter().enumerate() {
+        layout.code.push(vec![FIXED_START; ast.code.len() + 1]);
+        layout.data.push(vec![FIXED_START; ast.data.len() + 1]);
+
+        for (section, len) in [(Section::Code, ast.code.len() as u16), (Section::Data, ast.data.len() as u16)] {
+            let mut segment = Segment { file, section, start: 0, end: len, last: true, placement: None };
+            for placement in ast.placements.iter().filter(|p| p.section == section) {
+                segments.push(Segment { end: placement.offset, last: false, ..segment });
+                segment = Segment { start: placement.offset, placement: Some(placement), ..segment };
+            }
+            segments.push(segment);
+        }
+    }
+    let span = |segment: &Segment| match segment.section {
+        Section::Code => files[segment.file].code.get(segment.start as usize).map(|i| i.span),
+        _ => None,
+    };
+
+    // Files are kept in fixed-fixed memory in order, as long as there's room for them
+    let floating_len = |file: usize| -> u16 {
+        segments.iter().filter(|s| s.file == file && s.placement.is_none()).map(|s| s.end - s.start).sum()
+    };
+    let mut room = FIXED_END - FIXED_START;
+    let kept: Vec<bool> = (0..files.len()).map(|file| {
+        let len = floating_len(file);
+        let fits = len <= room;
+        if fits {
+            room -= len;
+        }
+        fits
+    }).collect();
+
+    let kept = &kept;
+    let floating = |section| segments.iter().filter(move |s| s.placement.is_none() && s.section == section && kept[s.file]);
+    let placed = |bank: bool| segments.iter().filter(move |s| {
+        s.placement.is_some_and(|p| matches!(p.place, Place::Bank(_)) == bank)
+    });
+
+    let mut next = FIXED_START;
+    for segment in floating(Section::Code).chain(floating(Section::Data)) {
+        if let Err(kind) = layout.place(segment, next) {
+            errors.push(Error::new(kind, span(segment).unwrap_or_default()).in_file(segment.file));
+        }
+        next = next.saturating_add(segment.end - segment.start);
+    }
+
+    for segment in placed(false).chain(placed(true)) {
+        let placement = segment.placement.unwrap();
+        let len = segment.end - segment.start;
+        let start = match placement.place {
+            Place::Address(address) if (FIXED_START..ROPE_END).contains(&address) => Ok(address),
+            Place::Address(address) => Err(ErrorKind::AddressOutOfRange(address as i64)),
+            Place::Bank(bank @ 2..FIXED_BANKS) => layout.room(bank, len).ok_or(ErrorKind::BankFull(bank)),
+            Place::Bank(bank) => Err(ErrorKind::NoSuchBank(bank)),
+        };
+        if let Err(kind) = start.and_then(|start| layout.place(segment, start)) {
+            errors.push(Error::new(kind, placement.span).in_file(segment.file));
+        }
+    }
+
+    for file in (0..files.len()).filter(|&file| !kept[file]) {
+        let len = floating_len(file);
+        let mut file_segments = segments.iter().filter(|s| s.file == file && s.placement.is_none());
+        let first = file_segments.clone().find_map(span).unwrap_or_default();
+        let start = match (FIXED_END / BANK_SIZE..FIXED_BANKS).find_map(|bank| layout.room(bank, len)) {
+            Some(start) => start,
+            None if len > BANK_SIZE => {
+                errors.push(Error::new(ErrorKind::FileOverBank(len), first).in_file(file));
+                continue;
+            }
+            None => {
+                errors.push(Error::new(ErrorKind::FixedFull, first).in_file(file));
+                continue;
+            }
+        };
+        let mut next = start;
+        for segment in file_segments.by_ref() {
+            // Checked for room already
+            layout.place(segment, next).unwrap();
+            next += segment.end - segment.start;
+        }
+    }
+    layout
+}
@@ -0,0 +1,113 @@
+use crate::constants::{cpu_address, BANK_SIZE};
+use crate::error::Span;
+use crate::types::*;
+
+impl Image {
+    // Every source line with the bank, address and words it assembled to, in octal, followed by how many
+    // words each file and each bank uses and where every symbol is defined and used. `paths` and `sources`
+    // are the files given to `link`, in the same order
+    pub fn to_listing(&self, paths: &[String], sources: &[&str]) -> String {
+        let mut out = String::new();
+        let mut banks: Vec<(u16, u16)> = vec![];
+
+        for (file, source) in sources.iter().enumerate() {
+            out.push_str(&format!("{}\n\nBANK ADDR  WORD   LINE\n", paths[file]));
+            let lines: Vec<&ListedLine> = self.listing.iter().filter(|l| l.file == file).collect();
+            let mut file_banks: Vec<(u16, u16)> = vec![];
+
+            for (index, text) in source.lines().enumerate() {
+                let number = index + 1;
+                let own = lines.iter().find(|l| l.line == number && l.expanded.is_none());
+                out.push_str(&row(own.copied(), &number.to_string(), text));
+
+                for line in lines.iter().filter(|l| l.line == number && l.expanded.is_some()) {
+                    out.push_str(&row(Some(line), "+", line.expanded.as_deref().unwrap_or("")));
+                }
+            }
+            for line in &lines {
+                for (i, _) in line.words.iter().enumerate() {
+                    let bank = line.address.unwrap_or(0).wrapping_add(i as u16) / BANK_SIZE;
+                    add(&mut file_banks, bank);
+                    add(&mut banks, bank);
+                }
+            }
+
+            let total: u16 = file_banks.iter().map(|(_, words)| words).sum();
+            let per_bank: Vec<String> = file_banks.iter().map(|(bank, words)| format!("bank {bank:02o}: {words}")).collect();
+            out.push_str(&format!("\n{total} words ({})\n\n", per_bank.join(", ")));
+        }
+
+        for (location, _) in &self.buggers {
+            add(&mut banks, location / BANK_SIZE);
+        }
+        out.push_str("Banks\n\n");
+        for (bank, words) in &banks {
+            out.push_str(&format!("bank {bank:02o}: {words} words used, {} free\n", BANK_SIZE - words));
+            for count in self.counts.iter().filter(|c| c.bank == *bank) {
+                out.push_str(&format!("    {}: {} words\n", count.name, count.words));
+            }
+            if let Some((location, word)) = self.buggers.iter().find(|(location, _)| location / BANK_SIZE == *bank) {
+                out.push_str(&format!("    bugger word at {:04o}: {:05o}\n", cpu_address(*location), word));
+            }
+        }
+
+        out.push_str("\nCross-reference\n\n");
+        let place = |&(file, span): &(usize, Span)| format!("{}:{}", paths[file], span.line);
+        for reference in &self.references {
+            let kind = reference.r#type.map_or("constant".to_string(), kind);
+            // Addresses in octal, like the rest of the listing
+            let value = if reference.address { format!("{:05o}", reference.value) } else { reference.value.to_string() };
+            let defined = reference.defined.as_ref().map_or("-".to_string(), place);
+            let used: Vec<String> = reference.used.iter().map(place).collect();
+            let row = format!("{:<12} {value:>6}  {kind:<16} {defined:<24} {}", display_name(&reference.name), used.join(", "));
+            out.push_str(row.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+//  BANK ADDR  WORD   LINE
+//    02 4000 30005      5 START:  CA X
+fn row(line: Option<&ListedLine>, number: &str, text: &str) -> String {
+    let address = line.and_then(|l| l.address);
+    let words = line.map_or(&[][..], |l| &l.words[..]);
+    let place = |address: u16| format!("  {:02o} {:04o}", address / BANK_SIZE, cpu_address(address));
+
+    let mut out = match (address, words.first()) {
+        (Some(address), Some(word)) => format!("{} {word:05o}", place(address)),
+        (Some(address), None) => format!("{}      ", place(address)),
+        _ => " ".repeat(15),
+    };
+    out.push_str(&format!(" {number:>6} {text}\n"));
+    // Lines with more than one word, like 2DEC, get a row for each of the others
+    for (i, word) in words.iter().enumerate().skip(1) {
+        let address = address.unwrap_or(0) + i as u16;
+        out.push_str(&format!("{} {word:05o}\n", place(address)));
+    }
+    out
+}
+
+fn add(banks: &mut Vec<(u16, u16)>, bank: u16) {
+    match banks.iter_mut().find(|(b, _)| *b == bank) {
+        Some((_, words)) => *words += 1,
+        None => {
+            banks.push((bank, 1));
+            banks.sort();
+        }
+    }
+}
+
+fn kind(r#type: SymbolType) -> String {
+    match r#type {
+        SymbolType::Label => "label".to_string(),
+        SymbolType::Variable => "variable".to_string(),
+        SymbolType::LabelTable(len) => format!("label table({len})"),
+        SymbolType::VariableTable(len) => format!("variable table({len})"),
+    }
+}
+
+// Numeric labels are named `1@0`, `1@1`... to tell them apart, but they're written `1`
+fn display_name(name: &str) -> &str {
+    name.split('@').next().unwrap_or(name)
+}
@@ -0,0 +1,445 @@
+use crate::error::ErrorKind;
+use crate::expr::*;
+use crate::types::Ast;
+
+// Expansions can use other macros, but not this deep
+const MAX_DEPTH: usize = 16;
+
+// `MACRO NAME a,b` up to `ENDM`. The lines are kept as they are and only parsed when the macro is used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<String>,
+}
+
+// Macros and constants a file can use while it's parsed: the ones of the files before it, and the ones
+// given in the command line. IF can only test constants known by then
+#[derive(Debug, Clone, Default)]
+pub struct Definitions {
+    constants: Vec<(String, i64)>,
+    macros: Vec<Macro>,
+    // Expansions so far, to give each one its own local labels
+    expansions: usize,
+}
+impl Definitions {
+    pub fn new(constants: &[(String, i64)]) -> Self {
+        Self { constants: constants.to_vec(), ..Self::default() }
+    }
+
+    // Takes the macros and constants of a file that's already parsed, like an object
+    pub fn add(&mut self, ast: &Ast) {
+        for constant in &ast.constants {
+            self.constant(&constant.name, &constant.expr);
+        }
+        self.macros.extend(ast.macros.iter().cloned());
+    }
+
+    // Notes the value of a constant, if it's a number that can be known already
+    pub(crate) fn constant(&mut self, name: &str, expr: &Expr) {
+        if let Ok(value) = expr.eval(&|symbol| self.value(symbol)) {
+            if !value.is_address() {
+                self.constants.push((name.to_string(), value.value));
+            }
+        }
+    }
+
+    fn value(&self, name: &str) -> Result<Value, ErrorKind> {
+        match self.constants.iter().find(|(n, _)| n == name) {
+            Some(&(_, value)) => Ok(Value::number(value)),
+            None => Err(ErrorKind::UndefinedConstant(name.to_string())),
+        }
+    }
+}
+
+// What to do with a source line
+pub enum Output {
+    // Parse it as it is
+    Keep,
+    // It's a directive, or it's in a false branch of an IF
+    Skip,
+    // It uses a macro or starts or ends a structured block, parse these lines instead
+    Expand(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Condition {
+    value: bool,
+    in_else: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Structure {
+    If { has_else: bool },
+    While,
+    // The variable that counts down
+    For(String),
+}
+
+// An IFZERO, IFNEG, WHILE or FOR that's open, with the number its labels end with. `conditions` is how
+// many IFs were open when it started, so ELSE and ENDIF go to whichever of the two is innermost. One in a
+// false branch of an IF is still kept, so its ELSE and ENDIF aren't taken for the IF's, but it's skipped
+#[derive(Debug, Clone)]
+struct Block {
+    structure: Structure,
+    opened: String,
+    number: usize,
+    conditions: usize,
+    skipped: bool,
+}
+
+// Takes care of MACRO, IF, ELSE and ENDIF, of using the macros and of the structured IFZERO, IFNEG, WHILE
+// and FOR, before the lines are parsed
+pub struct Preprocessor<'a> {
+    definitions: &'a mut Definitions,
+    conditions: Vec<Condition>,
+    // The macro being defined, and the ones this file defined
+    defining: Option<Macro>,
+    defined: Vec<Macro>,
+    blocks: Vec<Block>,
+}
+impl<'a> Preprocessor<'a> {
+    pub fn new(definitions: &'a mut Definitions) -> Self {
+        Self { definitions, conditions: vec![], defining: None, defined: vec![], blocks: vec![] }
+    }
+
+    pub fn definitions(&mut self) -> &mut Definitions {
+        self.definitions
+    }
+
+    pub fn defined(&self) -> &[Macro] {
+        &self.defined
+    }
+
+    pub fn line(&mut self, text: &str) -> Result<Output, ErrorKind> {
+        let mut tokens = text.split_whitespace();
+        let first = tokens.next().unwrap_or("");
+
+        if let Some(definition) = &mut self.defining {
+            match first {
+                "ENDM" => {
+                    let definition = self.defining.take().unwrap();
+                    self.defined.push(definition.clone());
+                    self.definitions.macros.push(definition);
+                }
+                "MACRO" => return Err(ErrorKind::MacroInMacro),
+                _ => definition.body.push(text.to_string()),
+            }
+            return Ok(Output::Skip);
+        }
+
+        if matches!(first, "ELSE" | "ENDIF" | "ENDWHILE" | "ENDFOR") {
+            if let Some(lines) = self.close(first)? {
+                return Ok(if lines.is_empty() { Output::Skip } else { Output::Expand(lines) });
+            }
+        }
+        if conditional(first, tokens.clone(), &mut self.conditions, self.definitions)? {
+            return Ok(Output::Skip);
+        }
+        if !active(&self.conditions) {
+            if matches!(first, "IFZERO" | "IFNEG" | "WHILE" | "FOR") {
+                self.skip(first);
+            }
+            return Ok(Output::Skip);
+        }
+        match first {
+            "IFZERO" | "IFNEG" | "WHILE" | "FOR" => return Ok(Output::Expand(self.open(first, tokens)?)),
+            "ENDWHILE" | "ENDFOR" => return Err(ErrorKind::Unexpected(first.to_string())),
+            _ => {}
+        }
+
+        if first == "MACRO" {
+            let name = tokens.next().ok_or(ErrorKind::MissingName)?;
+            if self.definitions.macros.iter().any(|m| m.name == name) {
+                return Err(ErrorKind::SymbolDefinedTwice(name.to_string()));
+            }
+            let params = arguments(tokens);
+            self.defining = Some(Macro { name: name.to_string(), params, body: vec![] });
+            return Ok(Output::Skip);
+        }
+        if first == "ENDM" {
+            return Err(ErrorKind::Unexpected(first.to_string()));
+        }
+
+        match self.definitions.macros.iter().any(|m| m.name == first) {
+            true => {
+                let lines = expand(self.definitions, first, arguments(tokens), 0)?;
+                Ok(Output::Expand(self.structured(lines)?))
+            }
+            false => Ok(Output::Keep),
+        }
+    }
+
+    // What was left open at the end of the file
+    pub fn finish(&self) -> Option<ErrorKind> {
+        if let Some(definition) = &self.defining {
+            return Some(ErrorKind::Unclosed(format!("MACRO {}", definition.name)));
+        }
+        if let Some(block) = self.blocks.last() {
+            return Some(ErrorKind::Unclosed(block.opened.clone()));
+        }
+        (!self.conditions.is_empty()).then(|| ErrorKind::Unclosed("IF".to_string()))
+    }
+
+    // The lines an IFZERO, IFNEG, WHILE or FOR starts with. The labels end with a number no other
+    // block or expansion uses. A is changed by all of them
+    //     IFZERO var                   the branch runs when var is +0 or -0
+    //     IFNEG var                    when var is less than zero
+    //     WHILE var                    the loop runs while var isn't zero
+    //     FOR var FROM n DOWNTO 0      n+1 times, with var from n down to 0. n is a number or the
+    //                                  label of the word to start from, and the loop can't make var negative
+    fn open<'t>(&mut self, first: &str, tokens: impl Iterator<Item = &'t str>) -> Result<Vec<String>, ErrorKind> {
+        let operand: Vec<&str> = tokens.take_while(|token| !token.starts_with('#')).collect();
+        self.definitions.expansions += 1;
+        let n = self.definitions.expansions;
+
+        let (structure, lines) = match (first, &operand[..]) {
+            (_, []) => return Err(ErrorKind::MissingOperand),
+            ("IFZERO", [var]) => {
+                let lines = [format!("CA {var}"), "EXTEND".into(), format!("BZF THEN__{n}"), format!("TCF ELSE__{n}")];
+                (Structure::If { has_else: false }, lines.to_vec())
+            }
+            // BZMF takes zero too, so zero goes to the ELSE first
+            ("IFNEG", [var]) => {
+                let lines = [format!("CA {var}"), "EXTEND".into(), format!("BZF ELSE__{n}"), "EXTEND".into(), format!("BZMF THEN__{n}"), format!("TCF ELSE__{n}")];
+                (Structure::If { has_else: false }, lines.to_vec())
+            }
+            ("WHILE", [var]) => {
+                let lines = [format!("WHILE__{n}:"), format!("CA {var}"), "EXTEND".into(), format!("BZF ENDWHILE__{n}")];
+                return self.push(Structure::While, first, n, lines.to_vec());
+            }
+            ("FOR", [var, "FROM", start, "DOWNTO", "0"]) => {
+                let start = if start.starts_with(|c: char| c.is_ascii_digit()) { format!("={start}") } else { start.to_string() };
+                let lines = [format!("CA {start}"), format!("TS {var}"), format!("FOR__{n}:")];
+                return self.push(Structure::For(var.to_string()), first, n, lines.to_vec());
+            }
+            _ => return Err(ErrorKind::InvalidOperand(operand.join(" "))),
+        };
+        let mut lines = lines;
+        lines.push(format!("THEN__{n}:"));
+        self.push(structure, first, n, lines)
+    }
+
+    fn push(&mut self, structure: Structure, first: &str, number: usize, lines: Vec<String>) -> Result<Vec<String>, ErrorKind> {
+        let conditions = self.conditions.len();
+        self.blocks.push(Block { structure, opened: first.to_string(), number, conditions, skipped: false });
+        Ok(lines.into_iter().map(indent).collect())
+    }
+
+    // A block in a false branch of an IF. Its operand isn't looked at and it has no lines
+    fn skip(&mut self, first: &str) {
+        let structure = match first {
+            "WHILE" => Structure::While,
+            "FOR" => Structure::For(String::new()),
+            _ => Structure::If { has_else: false },
+        };
+        let conditions = self.conditions.len();
+        self.blocks.push(Block { structure, opened: first.to_string(), number: 0, conditions, skipped: true });
+    }
+
+    // Turns the blocks in the lines of an expansion into their lines too
+    fn structured(&mut self, lines: Vec<String>) -> Result<Vec<String>, ErrorKind> {
+        let mut out = vec![];
+        for line in lines {
+            let mut tokens = line.split_whitespace();
+            match tokens.next().unwrap_or("") {
+                first @ ("IFZERO" | "IFNEG" | "WHILE" | "FOR") => out.extend(self.open(first, tokens)?),
+                first @ ("ELSE" | "ENDIF" | "ENDWHILE" | "ENDFOR") => {
+                    out.extend(self.close(first)?.ok_or(ErrorKind::Unexpected(first.to_string()))?)
+                }
+                _ => out.push(line),
+            }
+        }
+        Ok(out)
+    }
+
+    // The lines ELSE, ENDIF, ENDWHILE or ENDFOR end a block with. ELSE and ENDIF only belong to a block
+    // when there's no IF inside it still open, otherwise they're the IF's
+    fn close(&mut self, first: &str) -> Result<Option<Vec<String>>, ErrorKind> {
+        let depth = self.conditions.len();
+        let Some(block) = self.blocks.last_mut().filter(|block| block.conditions == depth) else {
+            return Ok(None);
+        };
+        let n = block.number;
+        let lines = match (first, &mut block.structure) {
+            ("ELSE", Structure::If { has_else }) if !*has_else => {
+                *has_else = true;
+                let lines = [format!("TCF ENDIF__{n}"), format!("ELSE__{n}:")];
+                return Ok(Some(if block.skipped { vec![] } else { lines.map(indent).to_vec() }));
+            }
+            ("ENDIF", Structure::If { has_else: true }) => vec![format!("ENDIF__{n}:")],
+            // Without an ELSE, the false branch goes to the end
+            ("ENDIF", Structure::If { has_else: false }) => vec![format!("ELSE__{n}:")],
+            ("ENDWHILE", Structure::While) => vec![format!("TCF WHILE__{n}"), format!("ENDWHILE__{n}:")],
+            // CCS leaves var-1 in A and goes on when var is more than zero, and skips a word when it's +0
+            ("ENDFOR", Structure::For(var)) => vec![
+                format!("CCS {var}"),
+                format!("TCF NEXT__{n}"),
+                format!("TCF ENDFOR__{n}"),
+                format!("NEXT__{n}:"),
+                format!("TS {var}"),
+                format!("TCF FOR__{n}"),
+                format!("ENDFOR__{n}:"),
+            ],
+            _ => return Err(ErrorKind::Unexpected(first.to_string())),
+        };
+        let block = self.blocks.pop().unwrap();
+        Ok(Some(if block.skipped { vec![] } else { lines.into_iter().map(indent).collect() }))
+    }
+}
+
+// Labels start in the first column, like in the sources
+fn indent(line: String) -> String {
+    if line.ends_with(':') { line } else { format!("    {line}") }
+}
+
+fn active(conditions: &[Condition]) -> bool {
+    conditions.iter().all(|c| c.value != c.in_else)
+}
+
+// Handles IF, ELSE and ENDIF. Returns whether the line was one of them.
+// `IF expr` is true when expr isn't zero, `IF a = b` and `IF a <> b` compare
+fn conditional<'a>(
+    first: &str,
+    rest: impl Iterator<Item = &'a str>,
+    conditions: &mut Vec<Condition>,
+    definitions: &Definitions,
+) -> Result<bool, ErrorKind> {
+    match first {
+        "IF" => {
+            // The condition of an IF inside a false branch isn't looked at, it may use constants that
+            // only exist for the other branch. A wrong condition still opens the IF, so its ELSE and ENDIF
+            // aren't errors too
+            let value = if active(conditions) { condition(rest, definitions) } else { Ok(false) };
+            conditions.push(Condition { value: value.clone().unwrap_or(false), in_else: false });
+            value?;
+        }
+        "ELSE" => match conditions.last_mut() {
+            Some(condition) if !condition.in_else => condition.in_else = true,
+            _ => return Err(ErrorKind::Unexpected(first.to_string())),
+        },
+        "ENDIF" => {
+            conditions.pop().ok_or(ErrorKind::Unexpected(first.to_string()))?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn condition<'a>(rest: impl Iterator<Item = &'a str>, definitions: &Definitions) -> Result<bool, ErrorKind> {
+    let text: String = rest.take_while(|token| !token.starts_with('#')).collect();
+    let eval = |text: &str| -> Result<i64, ErrorKind> {
+        let value = parse_expr(text)?.eval(&|symbol| definitions.value(symbol))?;
+        Ok(value.value)
+    };
+    if text.is_empty() {
+        return Err(ErrorKind::MissingOperand);
+    }
+    Ok(if let Some((a, b)) = text.split_once("<>") {
+        eval(a)? != eval(b)?
+    } else if let Some((a, b)) = text.split_once('=') {
+        eval(a)? == eval(b)?
+    } else {
+        eval(&text)? != 0
+    })
+}
+
+// `a,b,c`, the arguments of a macro or the parameters of its definition. Spaces after the commas are fine
+fn arguments<'a>(rest: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let text: String = rest.take_while(|token| !token.starts_with('#')).collect();
+    if text.is_empty() {
+        return vec![];
+    }
+    text.split(',').map(str::to_string).collect()
+}
+
+// Lines a use of the macro turns into, with the macros it uses expanded too
+fn expand(definitions: &mut Definitions, name: &str, args: Vec<String>, depth: usize) -> Result<Vec<String>, ErrorKind> {
+    if depth == MAX_DEPTH {
+        return Err(ErrorKind::MacroTooDeep(name.to_string()));
+    }
+    let definition = definitions.macros.iter().find(|m| m.name == name).unwrap().clone();
+    if args.len() != definition.params.len() {
+        return Err(ErrorKind::MacroArguments(name.to_string(), definition.params.len()));
+    }
+    definitions.expansions += 1;
+    let expansion = definitions.expansions;
+
+    let mut lines = vec![];
+    let mut conditions = vec![];
+    // The blocks opened in the body, left for the preprocessor to turn into lines: how many IFs were open
+    // then, whether they're kept, and the line's first word
+    let mut blocks: Vec<(usize, bool, String)> = vec![];
+    for text in &definition.body {
+        let text = substitute(text, &definition.params, &args, expansion);
+        let mut tokens = text.split_whitespace();
+        let first = tokens.next().unwrap_or("");
+
+        let kept = match first {
+            "IFZERO" | "IFNEG" | "WHILE" | "FOR" => {
+                blocks.push((conditions.len(), active(&conditions), first.to_string()));
+                Some(active(&conditions))
+            }
+            "ELSE" | "ENDIF" | "ENDWHILE" | "ENDFOR" if blocks.last().is_some_and(|block| block.0 == conditions.len()) => {
+                let kept = blocks.last().map(|block| block.1);
+                if first != "ELSE" {
+                    blocks.pop();
+                }
+                kept
+            }
+            _ => None,
+        };
+        match kept {
+            Some(true) => {
+                lines.push(text);
+                continue;
+            }
+            Some(false) => continue,
+            None => {}
+        }
+        if conditional(first, tokens.clone(), &mut conditions, definitions)? || !active(&conditions) {
+            continue;
+        }
+        if definitions.macros.iter().any(|m| m.name == first) {
+            lines.extend(expand(definitions, first, arguments(tokens), depth + 1)?);
+        } else {
+            lines.push(text);
+        }
+    }
+    if let Some((_, _, opened)) = blocks.pop() {
+        return Err(ErrorKind::Unclosed(opened));
+    }
+    if !conditions.is_empty() {
+        return Err(ErrorKind::Unclosed("IF".to_string()));
+    }
+    Ok(lines)
+}
+
+// Puts the arguments in place of the parameters, and turns local labels, `@NAME`, into a name only this
+// expansion uses. Comments are left alone
+fn substitute(text: &str, params: &[String], args: &[String], expansion: usize) -> String {
+    let (code, comment) = text.split_at(text.find('#').unwrap_or(text.len()));
+    let mut out = String::new();
+    let mut rest = code;
+    while let Some(c) = rest.chars().next() {
+        let word_len = |s: &str| s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(s.len());
+        if c == '@' {
+            let len = word_len(&rest[1..]);
+            out.push_str(&format!("{}__{expansion}", &rest[1..1 + len]));
+            rest = &rest[1 + len..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let len = word_len(rest);
+            let word = &rest[..len];
+            // Numbers are copied whole, so 0x1F isn't taken for a name
+            match params.iter().position(|p| p == word).filter(|_| !c.is_ascii_digit()) {
+                Some(i) => out.push_str(&args[i]),
+                None => out.push_str(word),
+            }
+            rest = &rest[len..];
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out + comment
+}
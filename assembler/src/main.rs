@@ -1,326 +1,154 @@
 use std::fs;
-mod constants;
-use constants::*;
-mod types;
-use types::*;
-
-fn main() {
-    const FILE_COUNT: usize = 8;
-    
-    let files = [
-        fs::read_to_string("../programs/manager.agc").unwrap(),
-        fs::read_to_string("../programs/blink.agc").unwrap(),
-        fs::read_to_string("../programs/for.agc").unwrap(),
-        fs::read_to_string("../programs/if.agc").unwrap(),
-        fs::read_to_string("../programs/player.agc").unwrap(),
-        fs::read_to_string("../programs/laberinto.agc").unwrap(),
-        fs::read_to_string("../programs/pong.agc").unwrap(),
-        fs::read_to_string("../programs/utils.agc").unwrap(),
-    ];
-
-    let sections = [Section::None, Section::Config, Section::Code, Section::Data];
-    let mut contents: [FileContent; FILE_COUNT] = [FileContent::new(), FileContent::new(), FileContent::new(), FileContent::new(), FileContent::new(), FileContent::new(), FileContent::new(), FileContent::new()];
-    let mut tables: Vec<UndefinedTable> = vec![];
-
-    // Parse files
-    for file_index in 0..files.len() {
-        let mut current_section = Section::None;
-        let mut next_extended = false;
-        for ln in files[file_index].lines() {
-            let mut line = ln.split_whitespace();
-            let first = line.next();
-
-            // Ignore blank lines
-            if first == None {
-                continue;
-            }
-            let first = first.unwrap();
-
-            // Ignore comments
-            if first.starts_with("#") {
-                continue;
-            }
-
-            // Handle sections
-            if first.starts_with(".") {
-                let sec = &first[1..];
-                let sec = sec.into();
-
-                if !sections.contains(&sec) {
-                    panic!("Invalid section name")
-                }
-
-                if sections.iter().position(|s| s == &sec).unwrap()
-                < sections.iter().position(|s| s == &current_section).unwrap() {
-                    panic!("There can only be one section of each type, and should be in the order 'config', 'code', 'data'")
-                }
-
-                current_section = sec;
-                continue;
-            }
-
-            if current_section == Section::Config {
-                if first == "VEC" {
-                    let name = line.next().expect("No name");
-                    let len = line.next().expect("No len").parse().unwrap();
-                    tables.push(UndefinedTable::new(name, len));
-                }
-            }
-
-            if current_section == Section::Data {
-                // For labels
-                if first.ends_with(":") {
-                    let name = &first[..first.len()-1];
-
-                    let label = UndefinedLabel::new(name, Section::Data, contents[file_index].data.len() as u16);
-
-                    if contents[file_index].labels.contains(&label) {
-                        panic!("Duplicated label")
-                    }
-
-                    contents[file_index].labels.push(label);
-                    continue;
-                }
-
-                if first != "DEC" {
-                    panic!("Only DEC in data")
-                }
-
-                let number = line.next().expect("No number");
-                let num;
-                if number.starts_with("-") {
-                    let number = &number[1..];
-                    let number: u16 = number.parse().unwrap();
-                    num = (!number) % 32768;
-                } else {
-                    num = number.parse().unwrap();
-                }
-
-                contents[file_index].data.push(num)
-            }
-
-            if current_section == Section::Code {
-                // For labels
-                if first.ends_with(":") {
-                    let name = &first[..first.len()-1];
-
-                    let label = UndefinedLabel::new(name, Section::Code, contents[file_index].code.len() as u16);
-
-                    if contents[file_index].labels.contains(&label) {
-                        panic!("Duplicated label")
-                    }
-
-                    contents[file_index].labels.push(label);
-                    continue;
-                }
-                
-                if EXTENDED.contains(&first) {
-                    if !next_extended {
-                        panic!("Extended instruction not preceded by EXTEND");
-                    }
-                }
-
-                if IMPLIED.contains(&first) {
-                    next_extended = false;
-
-                    if first == "EXTEND" {
-                        next_extended = true;
-                    }
-
-                    let instruction = Instruction::new(
-                        first, 
-                    UndefinedSymbol::new("ACC", Some(SymbolType::Variable)));
-
-                    contents[file_index].code.push(instruction);
-                } else {
-                    if first != "INDEX" {
-                        next_extended = false;
-                    }
-
-                    let operation = first;
-                    let operand = line.next().expect("No operand");
-
-                    let operand = 
-                    {
-                        if GENERAL.contains(&operation) {
-                            UndefinedSymbol::new(operand, None)
-                        } else if ERASABLE.contains(&operation) {
-                            UndefinedSymbol::new(operand, Some(SymbolType::Variable))
-                        } else if FIXED.contains(&operation) {
-                            UndefinedSymbol::new(operand, Some(SymbolType::Label))
-                        } else {
-                            panic!("Invalid instruction")
-                        }
-                    };
-
-                    let instruction = Instruction::new(operation, operand);
-                    contents[file_index].code.push(instruction);
-                }
-            }
-
+mod cli;
+use cli::{Format, Options, Verbosity};
+use assembler::*;
+use assembler::constants::RAM_START;
+
+// Prints only when asked for at least that much verbosity
+macro_rules! log {
+    ($options:expr, $level:ident, $($arg:tt)*) => {
+        if $options.verbosity >= Verbosity::$level {
+            println!($($arg)*);
+        }
+    };
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {message}");
+    std::process::exit(1)
+}
+
+fn warn(options: &Options, sources: &[String], warnings: &[Warning]) {
+    if options.verbosity > Verbosity::Quiet {
+        for w in warnings {
+            let file = w.file.unwrap_or(0);
+            eprintln!("{}", w.render(&options.files[file].display().to_string(), &sources[file]));
         }
     }
-    
-    let start_of_fixed = FIXED_START; 
-    let mut erasable = RAM_START; // Start of RAM
-
-    let mut defined: Vec<DefinedSymbol> = vec![ACC, L, Q, Z, BB, ZERO, PANT, BTNUP, BTNRGT, BTNDWN, BTNLFT, BTN1, BTN2, POTE, CORTO, MEDIO, LARGO];
-    let mut binary: Vec<u16> = vec![];
-
-    let mut len_code_total = 0;
-    let mut len_code = [0; FILE_COUNT];
-    let mut len_data = [0; FILE_COUNT];
-
-    for file_index in 0..contents.len() {
-        let code_len = contents[file_index].code.len();
-        let data_len = contents[file_index].data.len();
-
-        len_code_total += code_len;
-
-        len_code[file_index] = code_len;
-        len_data[file_index] = data_len;
+}
+
+// Prints every error, and the warnings, and exits
+fn report(options: &Options, sources: &[String], diagnostics: Diagnostics) -> ! {
+    warn(options, sources, &diagnostics.warnings);
+    let count = diagnostics.errors.len();
+    for e in diagnostics.errors {
+        let file = e.file.unwrap_or(0);
+        eprintln!("{}", e.render(&options.files[file].display().to_string(), &sources[file]));
     }
+    fail(format!("could not assemble due to {count} error{}", if count == 1 {""} else {"s"}))
+}
 
-    // Define all labels
-    for file_index in 0..contents.len() {
-        for label in &contents[file_index].labels {
-            let mut section_offset = start_of_fixed;
-
-            // Add the len of the previous code sections
-            if file_index > 0 && label.section == Section::Code {
-                for i in 0..=file_index-1 {
-                    section_offset += len_code[i];
-                }
-            }
-
-            // Add the len of all code sections and of previous data sections
-            if label.section == Section::Data {
-                section_offset += len_code_total;
-
-                if file_index > 0 {
-                    for i in 0..=file_index-1 {
-                        section_offset += len_data[i];
-                    }
-                }
-            }
-
-            let len;
-            let und = &UndefinedTable::new(label.name, 0);
-            if tables.contains(und) {
-                len = tables.iter().find(|&e| e == und).unwrap().len;
-                println!("Fixed table {} added, len {}", label.name, len);
-            } else {
-                len = 0;
-            }
+fn main() {
+    let options = cli::parse(std::env::args().skip(1));
+
+    let files: Vec<String> = options.files.iter().map(|path| {
+        fs::read_to_string(path).unwrap_or_else(|e| fail(format!("can't read {}: {e}", path.display())))
+    }).collect();
+
+    let sources: Vec<&str> = files.iter().map(String::as_str).collect();
+    // Objects carry the source they were made from, to show where errors are
+    let shown: Vec<String> = files.iter().enumerate().map(|(i, text)| {
+        if options.objects.contains(&i) { object_source(text).to_string() } else { text.clone() }
+    }).collect();
+    let assembler_options = assembler::Options {
+        strict: options.strict,
+        defines: options.defines.clone(),
+        yayul: options.yayul.clone(),
+        objects: options.objects.clone(),
+        bugger_words: options.bugger_words,
+        parity: options.parity,
+    };
+
+    if options.compile {
+        compile(&options, &assembler_options, &sources, &shown);
+        return;
+    }
+    let image = assemble_with(&sources, &assembler_options).unwrap_or_else(|diagnostics| report(&options, &shown, diagnostics));
+    warn(&options, &shown, &image.warnings);
 
-            let defined_label = label.define(section_offset as u16, len);
-            if defined.contains(&defined_label) {
-                panic!("Label {} defined in multiple files", defined_label.name)
+    if options.verify {
+        let errors = image.verify();
+        if !errors.is_empty() {
+            report(&options, &shown, Diagnostics { errors, warnings: vec![] });
+        }
+        log!(options, Normal, "{} instructions decode back to their source", image.instructions.len());
+        if options.bugger_words {
+            if let Some((bank, sum)) = image.check_banks().first() {
+                fail(format!("bank {bank:02o} adds up to {sum:05o}, not to plus or minus its number"));
             }
-            defined.push(defined_label);
-
-            println!("{:?} {:?}", defined.last().unwrap(), section_offset);
+            log!(options, Normal, "{} banks add up to their number", image.banks.len());
         }
     }
 
-    // Assemble all instructions
-    for file_index in 0..contents.len() {
-        for instruction in &contents[file_index].code {
-            let mut assembled: u16 = decode(instruction.operation);
-
-            let len;
-            let und = &UndefinedTable::new(instruction.operand.name, 0);
-            if tables.contains(und) {
-                len = tables.iter().find(|&e| e == und).unwrap().len;
-                println!("Erasable table {} considered, len {}", instruction.operand.name, len);
-            } else {
-                len = 0;
-            }
-            let op_defined = instruction.operand.define(erasable, len);
-            
-            if defined.contains(&op_defined) {
-                let op = defined.iter().find(|&e| e == &op_defined).unwrap();
-                if ERASABLE.contains(&&instruction.operation) {
-                    if op.r#type == SymbolType::Label {
-                        panic!("The operand is a position in fixed memory but the operation works on erasable only, {:?}", instruction)
-                    }
-                    if let SymbolType::LabelTable(_) = op.r#type {
-                        panic!("The operand is a position in fixed memory but the operation works on erasable only, {:?}", instruction)
-                    }
-                    
-                }
-                if FIXED.contains(&&instruction.operation) {
-                    if op.r#type == SymbolType::Variable {
-                        panic!("The operand is a position in erasable memory but the operation works on fixed only, {:?}", instruction)
-                    }
-                    if let SymbolType::VariableTable(_) = op.r#type {
-                        panic!("The operand is a position in erasable memory but the operation works on fixed only, {:?}", instruction)
-                    }
-                }
-                assembled += op.address
-            } else if op_defined.r#type == SymbolType::Label {
-                panic!("Label never defined, {:?}", op_defined)
-            } else {
-                if op_defined.r#type == SymbolType::Label {
-                    panic!("The operand is a position in fixed memory but the operation works on erasable only, {:?}", instruction)
-                }
-                if let SymbolType::LabelTable(_) = op_defined.r#type {
-                    panic!("The operand is a position in fixed memory but the operation works on erasable only, {:?}", instruction)
-                }
-
-                // Create new variable
-                assembled += erasable;
-                erasable += 1;
-                
-                defined.push(op_defined);
-                
-            }
-
-            binary.push(assembled);
-            println!("{:?} {:?}", instruction, assembled);
-        }
+    for symbol in &image.symbols {
+        log!(options, Debug, "{:?}", symbol);
+    }
+    for (i, word) in image.fixed.iter().enumerate() {
+        log!(options, Debug, "{:04o}: {:05o}", constants::FIXED_START as usize + i, word);
     }
+    for count in &image.counts {
+        log!(options, Verbose, "{}: {} words in bank {}", count.name, count.words, count.bank);
+    }
+    log!(options, Normal, "{} fixed words up to bank {:02o} and {} erasable words used", image.fixed.len(), image.last_bank(), image.erasable_end - RAM_START);
 
-    // Add the data sections
-    for file_index in 0..contents.len() {
-        for num in &contents[file_index].data {
-            binary.push(*num);
-            println!("data: {}", num);
+    if let Err(e) = fs::create_dir_all(&options.out_dir) {
+        fail(format!("can't create {}: {e}", options.out_dir.display()));
+    }
+    let paths: Vec<String> = options.files.iter().map(|path| path.display().to_string()).collect();
+    let mut written: Vec<(&str, Vec<u8>)> = vec![];
+    for format in &options.formats {
+        match format {
+            Format::Rust => written.extend([
+                ("fixed.in", image.to_rust_fixed().into_bytes()),
+                ("names.in", image.to_rust_names().into_bytes()),
+                ("erasable.in", image.to_rust_erasable().into_bytes()),
+            ]),
+            Format::Octal => written.extend([
+                ("rope.oct", image.to_octal().into_bytes()),
+                ("erasable.oct", image.to_octal_erasable().into_bytes()),
+            ]),
+            Format::Bin => written.push(("rope.bin", image.to_bin())),
+            Format::Hex => written.push(("rope.hex", image.to_hex().into_bytes())),
+            Format::Uf2 => written.push(("rope.uf2", image.to_uf2())),
+            Format::Json => written.push(("rope.json", image.to_json(&paths).into_bytes())),
         }
     }
-
-    //println!("{:#?}", defined);
-
-    let mut to_file: String = "[".to_string();
-    let mut bin_iter = binary.iter();
-
-    for _ in 0..1024 {
-        to_file.push_str(&format!("\nMemloc::new({}),", bin_iter.next().unwrap_or(&0)));
+    // The formats that have no room for the memory map and checksum
+    if options.formats.iter().any(|format| matches!(format, Format::Octal | Format::Bin)) {
+        written.push(("rope.map", image.to_header().into_bytes()));
     }
-    to_file.push_str("\n]");
-
-    fs::write("../agc_emulator/memory/fixed.in", to_file).unwrap();
-
-
-    let mut to_file: String = "match addr {".to_string();
-
-    for symbol in defined {
-        if let SymbolType::LabelTable(len) = symbol.r#type {
-            for i in 0..len {
-                to_file.push_str(&format!("\n\t{} => \"{}+{}\",", symbol.address + i, symbol.name, i));
-            }
+    if let Some(path) = &options.listing {
+        if let Err(e) = fs::write(path, image.to_listing(&paths, &shown.iter().map(String::as_str).collect::<Vec<_>>())) {
+            fail(format!("can't write {}: {e}", path.display()));
         }
-        else if let SymbolType::VariableTable(len) = symbol.r#type {
-            for i in 0..len {
-                to_file.push_str(&format!("\n\t{} => \"{}+{}\",", symbol.address + i, symbol.name, i));
-            }
+        log!(options, Verbose, "Wrote {}", path.display());
+    }
+    for (name, text) in written {
+        let path = options.out_dir.join(name);
+        if let Err(e) = fs::write(&path, text) {
+            fail(format!("can't write {}: {e}", path.display()));
         }
-        else {
-            to_file.push_str(&format!("\n\t{} => \"{}\",", symbol.address, symbol.name));
+        log!(options, Verbose, "Wrote {}", path.display());
+    }
+}
+
+// Writes an object for each source file, without linking them
+fn compile(options: &Options, assembler_options: &assembler::Options, sources: &[&str], shown: &[String]) {
+    let asts = parse_files(sources, assembler_options).unwrap_or_else(|diagnostics| report(options, shown, diagnostics));
+    let warnings: Vec<Warning> = asts.iter().enumerate()
+        .flat_map(|(i, ast)| ast.warnings.iter().map(move |w| w.clone().in_file(i)))
+        .collect();
+    warn(options, shown, &warnings);
+
+    if let Err(e) = fs::create_dir_all(&options.out_dir) {
+        fail(format!("can't create {}: {e}", options.out_dir.display()));
+    }
+    for (i, ast) in asts.iter().enumerate().filter(|(i, _)| !options.objects.contains(i)) {
+        let name = options.files[i].file_stem().unwrap_or_default();
+        let path = options.out_dir.join(name).with_extension("o");
+        if let Err(e) = fs::write(&path, ast.to_object(sources[i])) {
+            fail(format!("can't write {}: {e}", path.display()));
         }
-        
+        log!(options, Verbose, "Wrote {}", path.display());
     }
-    to_file.push_str("\n\t_ => \"\",\n}");
-
-    fs::write("../agc_emulator/memory/names.in", to_file).unwrap();
-}
\ No newline at end of file
+}
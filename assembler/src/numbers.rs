@@ -0,0 +1,92 @@
+use crate::error::ErrorKind;
+
+// Largest magnitude of a word, 2^14 - 1, and of a double precision pair, 2^28 - 1
+const SINGLE_MAX: u64 = (1 << 14) - 1;
+const DOUBLE_MAX: u64 = (1 << 28) - 1;
+// Largest octal constants, one and two full 15 bit words
+const OCT_MAX: u64 = 0o77777;
+const OCT2_MAX: u64 = 0o7777777777;
+
+// Integer literal: decimal, or hexadecimal, binary and octal with the 0x, 0b and 0o prefixes.
+// Returns the sign apart so -0 can be told from 0
+pub fn parse_integer(text: &str) -> Option<(bool, u64)> {
+    let (negative, digits) = split_sign(text);
+    let magnitude = if let Some(hex) = digits.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2)
+    } else if let Some(oct) = digits.strip_prefix("0o") {
+        u64::from_str_radix(oct, 8)
+    } else {
+        digits.parse()
+    };
+    Some((negative, magnitude.ok()?))
+}
+
+fn split_sign(text: &str) -> (bool, &str) {
+    match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    }
+}
+
+// Scale factor after a DEC number: E multiplies by a power of ten and B by a power of two
+pub fn parse_scale(token: &str) -> Option<(char, i32)> {
+    let kind = token.chars().next().filter(|c| *c == 'E' || *c == 'B')?;
+    Some((kind, token[1..].parse().ok()?))
+}
+
+// One's complement word with that sign and magnitude
+fn word(negative: bool, magnitude: u64) -> u16 {
+    let magnitude = magnitude as u16 & 0x7FFF;
+    if negative { !magnitude & 0x7FFF } else { magnitude }
+}
+
+// Splits a double precision magnitude into its two words, both carrying the sign
+fn double(negative: bool, magnitude: u64) -> Vec<u16> {
+    vec![word(negative, magnitude >> 14), word(negative, magnitude & SINGLE_MAX)]
+}
+
+// DEC and 2DEC. Numbers with a decimal point are fractions, 1.0 being 2^14 (2^28 for 2DEC), and must stay
+// below 1 once scaled. Numbers without one are integers. Scale factors apply to both:
+// `DEC 0.5 B-1` is 0.25, `DEC -3.2 E2 B-9` is -0.625
+pub fn dec(number: &str, scales: &[(char, i32)], two: bool) -> Result<Vec<u16>, ErrorKind> {
+    let invalid = || ErrorKind::InvalidNumber(number.to_string());
+    let out_of_range = || ErrorKind::NumberOutOfRange(number.to_string());
+    let (negative, digits) = split_sign(number);
+    let max = if two { DOUBLE_MAX } else { SINGLE_MAX };
+
+    let fraction = digits.contains('.');
+    let mut value: f64 = if fraction {
+        digits.parse().map_err(|_| invalid())?
+    } else {
+        parse_integer(digits).ok_or_else(invalid)?.1 as f64
+    };
+    for &(kind, exponent) in scales {
+        value *= if kind == 'E' { 10f64.powi(exponent) } else { 2f64.powi(exponent) };
+    }
+    if fraction {
+        value *= (max + 1) as f64;
+    }
+
+    let magnitude = value.round();
+    if magnitude > max as f64 {
+        return Err(out_of_range());
+    }
+    let magnitude = magnitude as u64;
+    Ok(if two { double(negative, magnitude) } else { vec![word(negative, magnitude)] })
+}
+
+// OCT and 2OCT take the bits as they are: 5 octal digits for one word, 10 for two
+pub fn oct(number: &str, two: bool) -> Result<Vec<u16>, ErrorKind> {
+    let (negative, digits) = split_sign(number);
+    let magnitude = u64::from_str_radix(digits, 8).map_err(|_| ErrorKind::InvalidNumber(number.to_string()))?;
+    if magnitude > if two { OCT2_MAX } else { OCT_MAX } {
+        return Err(ErrorKind::NumberOutOfRange(number.to_string()));
+    }
+    Ok(if two {
+        vec![word(negative, magnitude >> 15), word(negative, magnitude & OCT_MAX)]
+    } else {
+        vec![word(negative, magnitude)]
+    })
+}
@@ -0,0 +1,340 @@
+use crate::error::*;
+use crate::expr::Expr;
+use crate::macros::Macro;
+use crate::types::*;
+
+// First line of every object, with the version of the format
+const HEADER: &str = "AGC OBJECT 1";
+// Everything after this line is the source the object was made from
+const SOURCE: &str = "SOURCE";
+
+// An object is the parsed file, one record per line, so it can be linked later without parsing it again.
+// Code keeps each instruction with its operand, which is what gets relocated, and data is already the
+// words. The labels it exports and imports and the erasable words it needs go with them. The source goes
+// last, for listings and to show where link errors are. Warnings were already shown when it was made
+impl Ast {
+    pub fn to_object(&self, source: &str) -> String {
+        let mut out = format!("{HEADER}\n");
+        let mut record = |fields: Vec<String>| {
+            out.push_str(&fields.join(" "));
+            out.push('\n');
+        };
+
+        for table in &self.tables {
+            record(vec!["TABLE".into(), table.name.clone(), table.len.to_string()]);
+        }
+        for constant in &self.constants {
+            let mut fields = vec!["CONST".into(), constant.name.clone(), flag(constant.equals), span(constant.span)];
+            write_expr(&constant.expr, &mut fields);
+            record(fields);
+        }
+        for erase in &self.erased {
+            let name = erase.name.clone().unwrap_or("-".into());
+            let address = erase.address.map_or("-".into(), |a| a.to_string());
+            let mut fields = vec!["ERASE".into(), name, erase.len.to_string(), address, span(erase.span)];
+            fields.extend(erase.init.iter().map(u16::to_string));
+            record(fields);
+        }
+        for placement in &self.placements {
+            let (kind, n) = match placement.place {
+                Place::Address(address) => ("ADDRESS", address),
+                Place::Bank(bank) => ("BANK", bank),
+            };
+            let (section, offset) = (section(placement.section), placement.offset.to_string());
+            record(vec!["PLACE".into(), section, offset, kind.into(), n.to_string(), span(placement.span)]);
+        }
+        for count in &self.counts {
+            record(vec!["COUNT".into(), section(count.section), count.offset.to_string(), count.name.clone()]);
+        }
+        for label in &self.labels {
+            let (section, offset) = (section(label.section), label.offset.to_string());
+            record(vec!["LABEL".into(), label.name.clone(), section, offset, span(label.span)]);
+        }
+        for (name, at) in &self.exports {
+            record(vec!["EXPORT".into(), name.clone(), span(*at)]);
+        }
+        for (name, at) in &self.externs {
+            record(vec!["EXTERN".into(), name.clone(), span(*at)]);
+        }
+        if self.imports_all {
+            record(vec!["IMPORTALL".into()]);
+        }
+        for instruction in &self.code {
+            let operand = &instruction.operand;
+            let mut fields = vec![
+                "CODE".into(),
+                instruction.operation.clone(),
+                flag(instruction.extended),
+                span(instruction.span),
+                r#type(operand.r#type),
+                span(operand.span),
+            ];
+            write_expr(&operand.expr, &mut fields);
+            record(fields);
+        }
+        for word in &self.data {
+            record(vec!["DATA".into(), word.to_string()]);
+        }
+        for definition in &self.macros {
+            let mut fields = vec!["MACRO".into(), definition.name.clone(), definition.body.len().to_string()];
+            fields.extend(definition.params.iter().cloned());
+            record(fields);
+            for line in &definition.body {
+                record(vec![format!(">{line}")]);
+            }
+        }
+        for expansion in &self.expansions {
+            record(vec!["EXPANSION".into(), expansion.line.to_string(), expansion.lines.len().to_string()]);
+            // Marked, so no line is taken for the start of the source
+            for line in &expansion.lines {
+                record(vec![format!(">{line}")]);
+            }
+        }
+        for line in &self.lines {
+            let mut fields = vec!["LINE".into(), line.line.to_string(), section(line.section)];
+            fields.extend([line.offset.to_string(), line.len.to_string()]);
+            // The text of an expanded line goes last, it has spaces
+            if let Some(text) = &line.expanded {
+                fields.push(format!("={text}"));
+            }
+            record(fields);
+        }
+
+        out.push_str(SOURCE);
+        out.push('\n');
+        out.push_str(source);
+        out
+    }
+}
+
+// Reads an object made by `Ast::to_object`
+pub fn read_object(text: &str) -> Result<Ast, Error> {
+    let mut ast = Ast::default();
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+    let invalid = |number: usize, line: &str| Error::new(ErrorKind::InvalidObject, Span::new(number, 1, line.chars().count()));
+
+    match lines.next() {
+        Some((_, HEADER)) => {}
+        Some((number, line)) => return Err(invalid(number, line)),
+        None => return Err(invalid(1, "")),
+    }
+    while let Some((number, line)) = lines.next() {
+        if line == SOURCE {
+            return Ok(ast);
+        }
+        if read_record(&mut Fields { rest: line }, &mut ast, &mut lines).is_none() {
+            return Err(invalid(number, line));
+        }
+    }
+    // The source can't be missing, an object without it was cut short
+    Err(invalid(text.lines().count(), ""))
+}
+
+// Reads a record into the Ast. Expansions take their lines from `lines`
+fn read_record<'a>(
+    fields: &mut Fields<'a>,
+    ast: &mut Ast,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Option<()> {
+    match fields.next()? {
+        "TABLE" => ast.tables.push(UndefinedTable::new(fields.next()?, fields.number()?)),
+        "CONST" => {
+            let name = fields.next()?.to_string();
+            let (equals, span) = (fields.flag()?, fields.span()?);
+            ast.constants.push(Constant { name, expr: fields.expr()?, equals, span });
+        }
+        "ERASE" => {
+            let name = Some(fields.next()?).filter(|name| *name != "-").map(str::to_string);
+            let len = fields.number()?;
+            let address = match fields.next()? {
+                "-" => None,
+                address => Some(address.parse().ok()?),
+            };
+            let span = fields.span()?;
+            let mut init = vec![];
+            while !fields.rest.is_empty() {
+                init.push(fields.number()?);
+            }
+            ast.erased.push(Erase { name, len, address, init, span });
+        }
+        "PLACE" => {
+            let (section, offset) = (fields.section()?, fields.number()?);
+            let place = match (fields.next()?, fields.number()?) {
+                ("ADDRESS", address) => Place::Address(address),
+                ("BANK", bank) => Place::Bank(bank),
+                _ => return None,
+            };
+            ast.placements.push(Placement { section, offset, place, span: fields.span()? });
+        }
+        "COUNT" => {
+            let (section, offset) = (fields.section()?, fields.number()?);
+            ast.counts.push(Count { name: fields.next()?.to_string(), section, offset });
+        }
+        "LABEL" => {
+            let name = fields.next()?;
+            let (section, offset) = (fields.section()?, fields.number()?);
+            ast.labels.push(UndefinedLabel::new(name, section, offset, fields.span()?));
+        }
+        "EXPORT" => ast.exports.push((fields.next()?.to_string(), fields.span()?)),
+        "EXTERN" => ast.externs.push((fields.next()?.to_string(), fields.span()?)),
+        "IMPORTALL" => ast.imports_all = true,
+        "CODE" => {
+            let operation = fields.next()?;
+            let (extended, span) = (fields.flag()?, fields.span()?);
+            let (r#type, operand_span) = (fields.r#type()?, fields.span()?);
+            let operand = UndefinedSymbol::new(fields.expr()?, r#type, operand_span);
+            ast.code.push(Instruction::new(operation, operand, span, extended));
+        }
+        "DATA" => ast.data.push(fields.number()?),
+        "MACRO" => {
+            let (name, count) = (fields.next()?.to_string(), fields.number()?);
+            let params = std::iter::from_fn(|| fields.next().map(str::to_string)).collect();
+            ast.macros.push(Macro { name, params, body: marked(lines, count)? });
+        }
+        "EXPANSION" => {
+            let (line, count) = (fields.number()?, fields.number()?);
+            ast.expansions.push(Expansion { line, lines: marked(lines, count)? });
+        }
+        "LINE" => {
+            let (line, section) = (fields.number()?, fields.section()?);
+            let (offset, len) = (fields.number()?, fields.number()?);
+            let expanded = match fields.rest {
+                "" => None,
+                rest => Some(rest.strip_prefix('=')?.to_string()),
+            };
+            fields.rest = "";
+            ast.lines.push(SourceLine { line, expanded, section, offset, len });
+        }
+        _ => return None,
+    }
+    fields.rest.is_empty().then_some(())
+}
+
+// The `count` lines after a record, each marked with a >
+fn marked<'a>(lines: &mut impl Iterator<Item = (usize, &'a str)>, count: usize) -> Option<Vec<String>> {
+    let marked: Vec<String> = lines.take(count)
+        .map_while(|(_, text)| text.strip_prefix('>').map(str::to_string))
+        .collect();
+    (marked.len() == count).then_some(marked)
+}
+
+// The source an object was made from
+pub fn object_source(text: &str) -> &str {
+    match text.find(&format!("\n{SOURCE}\n")) {
+        Some(start) => &text[start + SOURCE.len() + 2..],
+        None => "",
+    }
+}
+
+fn flag(value: bool) -> String {
+    (value as u8).to_string()
+}
+
+fn span(span: Span) -> String {
+    format!("{}:{}:{}", span.line, span.column, span.len)
+}
+
+fn section(section: Section) -> String {
+    match section {
+        Section::None => "none",
+        Section::Config => "config",
+        Section::Erasable => "erasable",
+        Section::Code => "code",
+        Section::Data => "data",
+    }.to_string()
+}
+
+fn r#type(r#type: Option<SymbolType>) -> String {
+    match r#type {
+        None => "-".to_string(),
+        Some(SymbolType::Label) => "L".to_string(),
+        Some(SymbolType::Variable) => "V".to_string(),
+        Some(SymbolType::LabelTable(len)) => format!("L{len}"),
+        Some(SymbolType::VariableTable(len)) => format!("V{len}"),
+    }
+}
+
+// Prefix notation, `+ $TABLE #3`, so there's no need for parentheses. Names have a $ and numbers a #,
+// as some names start with a digit
+fn write_expr(expr: &Expr, fields: &mut Vec<String>) {
+    match expr {
+        Expr::Number(n) => fields.push(format!("#{n}")),
+        Expr::Symbol(name) => fields.push(format!("${name}")),
+        Expr::Add(a, b) | Expr::Sub(a, b) => {
+            fields.push(if matches!(expr, Expr::Add(..)) { "+" } else { "-" }.to_string());
+            write_expr(a, fields);
+            write_expr(b, fields);
+        }
+        Expr::Neg(a) => {
+            fields.push("~".to_string());
+            write_expr(a, fields);
+        }
+    }
+}
+
+// The fields of a record, separated by a single space
+struct Fields<'a> {
+    rest: &'a str,
+}
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let (field, rest) = self.rest.split_once(' ').unwrap_or((self.rest, ""));
+        self.rest = rest;
+        Some(field)
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Option<T> {
+        self.next()?.parse().ok()
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        match self.next()? {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None,
+        }
+    }
+
+    fn span(&mut self) -> Option<Span> {
+        let mut parts = self.next()?.split(':').map(|n| n.parse().ok());
+        let span = Span::new(parts.next()??, parts.next()??, parts.next()??);
+        parts.next().is_none().then_some(span)
+    }
+
+    fn section(&mut self) -> Option<Section> {
+        match self.next()? {
+            "none" => Some(Section::None),
+            name => Section::from_name(name),
+        }
+    }
+
+    fn r#type(&mut self) -> Option<Option<SymbolType>> {
+        let field = self.next()?;
+        let len = || field[1..].parse().ok();
+        Some(match field {
+            "-" => None,
+            "L" => Some(SymbolType::Label),
+            "V" => Some(SymbolType::Variable),
+            _ if field.starts_with('L') => Some(SymbolType::LabelTable(len()?)),
+            _ if field.starts_with('V') => Some(SymbolType::VariableTable(len()?)),
+            _ => return None,
+        })
+    }
+
+    fn expr(&mut self) -> Option<Expr> {
+        let field = self.next()?;
+        Some(match field {
+            "+" => Expr::Add(Box::new(self.expr()?), Box::new(self.expr()?)),
+            "-" => Expr::Sub(Box::new(self.expr()?), Box::new(self.expr()?)),
+            "~" => Expr::Neg(Box::new(self.expr()?)),
+            _ => match (field.strip_prefix('#'), field.strip_prefix('$')) {
+                (Some(n), _) => Expr::Number(n.parse().ok()?),
+                (_, Some(name)) if !name.is_empty() => Expr::Symbol(name.to_string()),
+                _ => return None,
+            },
+        })
+    }
+}
@@ -0,0 +1,244 @@
+use crate::constants::{BANK_SIZE, ERASABLE_END, ERASABLE_START, FIXED_START, PICO_ROPE_ADDRESS, RAM_START};
+use crate::types::*;
+use agc_emulator::memory::parity_bit;
+use agc_emulator::rope::{crc32, HEADER_LEN as PICO_HEADER_LEN, MAGIC as PICO_MAGIC, VERSION as PICO_VERSION};
+// Blocks of a UF2 file, and the family of the RP2040
+const UF2_PAYLOAD: usize = 256;
+const UF2_FAMILY: u32 = 0xE48B_FF56;
+
+impl Image {
+    // Every bank up to the last one used, from bank 2 on, with the unused words as zero
+    pub(crate) fn banked(&self) -> Vec<u16> {
+        let banks = self.fixed.len().div_ceil(BANK_SIZE as usize).max(1);
+        let mut words = self.fixed.clone();
+        words.resize(banks * BANK_SIZE as usize, if self.parity { parity_bit(0) } else { 0 });
+        words
+    }
+
+    pub fn last_bank(&self) -> u16 {
+        (FIXED_START + self.banked().len() as u16) / BANK_SIZE - 1
+    }
+
+    // CRC-32 of the fixed words of every bank, two bytes each, low byte first. It's the same for every
+    // output format, so they can be checked against each other
+    pub fn checksum(&self) -> u32 {
+        crc32(&self.banked().iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>())
+    }
+
+    // CRC-32 of the initial values of erasable memory, the same way. Only the Pico's rope has it
+    pub fn erasable_checksum(&self) -> u32 {
+        crc32(&self.erasable_words().iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>())
+    }
+
+    // The memory map and checksum every output starts with, or goes with
+    pub fn to_header(&self) -> String {
+        let mut out = format!("AGC rope, banks 02 to {:02o}, checksum {:08x}\n", self.last_bank(), self.checksum());
+        for (bank, words) in &self.banks {
+            out.push_str(&format!("bank {bank:02o}: {words} words used, {} free\n", BANK_SIZE - words));
+        }
+        out.push_str(&format!("erasable: {RAM_START:04o} to {:04o} used\n", self.erasable_end));
+        out
+    }
+
+    // Fixed memory contents, as the array the emulator includes. It has every bank up to the last one used,
+    // from bank 2 on
+    pub fn to_rust_fixed(&self) -> String {
+        let mut to_file: String = self.to_header().lines().map(|line| format!("// {line}\n")).collect();
+        to_file.push('[');
+        for word in self.banked() {
+            to_file.push_str(&format!("\n{word},"));
+        }
+        to_file.push_str("\n]");
+        to_file
+    }
+
+    // Initial values of erasable memory from address 48 on, as the array the emulator includes
+    pub fn to_rust_erasable(&self) -> String {
+        let mut to_file: String = "[".to_string();
+        for word in self.erasable_words() {
+            to_file.push_str(&format!("\n{word},"));
+        }
+        to_file.push_str("\n]");
+        to_file
+    }
+
+    fn erasable_words(&self) -> Vec<u16> {
+        (ERASABLE_START..ERASABLE_END)
+            .map(|address| self.erasable.iter().find(|(a, _)| *a == address).map_or(0, |&(_, word)| word))
+            .collect()
+    }
+
+    // Name of every address, as the match the emulator includes
+    pub fn to_rust_names(&self) -> String {
+        let mut to_file: String = "match addr {".to_string();
+
+        for symbol in &self.symbols {
+            match symbol.r#type {
+                SymbolType::LabelTable(len) | SymbolType::VariableTable(len) => {
+                    for i in 0..len {
+                        to_file.push_str(&format!("\n\t{} => \"{}+{}\",", symbol.address + i, symbol.name, i));
+                    }
+                }
+                _ => to_file.push_str(&format!("\n\t{} => \"{}\",", symbol.address, symbol.name)),
+            }
+        }
+        to_file.push_str("\n\t_ => \"\",\n}");
+        to_file
+    }
+
+    // Fixed memory contents, one octal word per line, six digits with the parity bit
+    pub fn to_octal(&self) -> String {
+        let digits = if self.parity { 6 } else { 5 };
+        self.fixed.iter().map(|word| format!("{word:0digits$o}\n")).collect()
+    }
+
+    // Erasable words that don't start as zero, address and word in octal
+    pub fn to_octal_erasable(&self) -> String {
+        self.erasable.iter().map(|(address, word)| format!("{address:04o} {word:05o}\n")).collect()
+    }
+
+    // The rope as yaYUL writes it: two bytes a word, high byte first, with the word shifted one bit left
+    // and the parity bit, if there's one, in the lowest bit. The banks go 2, 3, 0, 1, 4, 5... and banks 0 and 1 are empty
+    pub fn to_bin(&self) -> Vec<u8> {
+        let words = self.banked();
+        let banks = (words.len() / BANK_SIZE as usize + 2).max(4);
+        let mut out = vec![];
+        for block in 0..banks {
+            let bank = match block {
+                0 | 1 => block + 2,
+                2 | 3 => block - 2,
+                _ => block,
+            };
+            for offset in 0..BANK_SIZE as usize {
+                let word = bank.checked_sub(2).and_then(|b| words.get(b * BANK_SIZE as usize + offset));
+                let word = word.copied().unwrap_or(0);
+                out.extend(((word & 0o77777) << 1 | word >> 15).to_be_bytes());
+            }
+        }
+        out
+    }
+
+    // What goes at PICO_ROPE_ADDRESS: the header, then the fixed words from bank 2 on and the erasable
+    // ones from address 48 on, two bytes each, low byte first
+    pub fn to_pico(&self) -> Vec<u8> {
+        let fixed: Vec<u8> = self.banked().iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut out = PICO_MAGIC.to_vec();
+        out.extend(PICO_VERSION.to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        out.extend(((fixed.len() / 2) as u16 / BANK_SIZE).to_le_bytes());
+        out.extend(self.erasable_end.to_le_bytes());
+        out.extend((PICO_HEADER_LEN as u32).to_le_bytes());
+        out.extend(((PICO_HEADER_LEN + fixed.len()) as u32).to_le_bytes());
+        out.extend(self.checksum().to_le_bytes());
+        for bank in 0..32 {
+            let words = self.banks.iter().find(|(b, _)| *b == bank).map_or(0, |&(_, words)| words);
+            out.extend(words.to_le_bytes());
+        }
+        out.extend(self.erasable_checksum().to_le_bytes());
+        out.resize(PICO_HEADER_LEN, 0);
+        out.extend(fixed);
+        out.extend(self.erasable_words().iter().flat_map(|word| word.to_le_bytes()));
+        out
+    }
+
+    // The Pico's rope as Intel HEX, 16 bytes a record
+    pub fn to_hex(&self) -> String {
+        let mut out = String::new();
+        let mut upper = None;
+        for (i, chunk) in self.to_pico().chunks(16).enumerate() {
+            let address = PICO_ROPE_ADDRESS + i as u32 * 16;
+            // Addresses past 64K need the upper half in a record of their own
+            if upper != Some(address >> 16) {
+                upper = Some(address >> 16);
+                out.push_str(&hex_record(0, 4, &((address >> 16) as u16).to_be_bytes()));
+            }
+            out.push_str(&hex_record(address as u16, 0, chunk));
+        }
+        out.push_str(&hex_record(0, 1, &[]));
+        out
+    }
+
+    // The Pico's rope as UF2, what the Pico takes when it's plugged in as a drive
+    pub fn to_uf2(&self) -> Vec<u8> {
+        let payload = self.to_pico();
+        let blocks = payload.len().div_ceil(UF2_PAYLOAD);
+        let mut out = vec![];
+        for (i, chunk) in payload.chunks(UF2_PAYLOAD).enumerate() {
+            let address = PICO_ROPE_ADDRESS + (i * UF2_PAYLOAD) as u32;
+            // Magic numbers, flags (the family is given), address, payload size, block, blocks, family
+            let fields = [0x0A32_4655, 0x9E5D_5157, 0x2000, address, UF2_PAYLOAD as u32, i as u32, blocks as u32, UF2_FAMILY];
+            let start = out.len();
+            out.extend(fields.iter().flat_map(|field| field.to_le_bytes()));
+            out.extend(chunk);
+            out.resize(start + 508, 0);
+            out.extend(0x0AB1_6F30u32.to_le_bytes());
+        }
+        out
+    }
+
+    // Everything about the image as JSON: the header, the words, every symbol, and the source map, the
+    // words of each line of each file. `paths` are the files given to `link`, in the same order
+    pub fn to_json(&self, paths: &[String]) -> String {
+        let words = |words: &[u16]| words.iter().map(u16::to_string).collect::<Vec<_>>().join(", ");
+        let mut out = "{\n  \"header\": {\n".to_string();
+        out.push_str(&format!("    \"first_bank\": 2,\n    \"last_bank\": {},\n", self.last_bank()));
+        let banks: Vec<String> = self.banks.iter()
+            .map(|(bank, words)| format!("{{\"bank\": {bank}, \"used\": {words}}}"))
+            .collect();
+        out.push_str(&format!("    \"banks\": [{}],\n", banks.join(", ")));
+        out.push_str(&format!("    \"erasable\": {{\"start\": {RAM_START}, \"end\": {}}},\n", self.erasable_end));
+        out.push_str(&format!("    \"checksum\": \"{:08x}\"\n  }},\n", self.checksum()));
+
+        out.push_str(&format!("  \"fixed\": [{}],\n", words(&self.banked())));
+        let erasable: Vec<String> = self.erasable.iter().map(|(address, word)| format!("[{address}, {word}]")).collect();
+        out.push_str(&format!("  \"erasable\": [{}],\n", erasable.join(", ")));
+
+        let symbols: Vec<String> = self.symbols.iter().map(|symbol| {
+            let (kind, len) = match symbol.r#type {
+                SymbolType::Label => ("label", 1),
+                SymbolType::Variable => ("variable", 1),
+                SymbolType::LabelTable(len) => ("label", len),
+                SymbolType::VariableTable(len) => ("variable", len),
+            };
+            let file = symbol.file.map_or("null".to_string(), |file| json_string(&paths[file]));
+            format!(
+                "    {{\"name\": {}, \"type\": \"{kind}\", \"len\": {len}, \"address\": {}, \"file\": {file}, \"exported\": {}}}",
+                json_string(&symbol.name), symbol.address, symbol.exported,
+            )
+        }).collect();
+        out.push_str(&format!("  \"symbols\": [\n{}\n  ],\n", symbols.join(",\n")));
+
+        let lines: Vec<String> = self.listing.iter().filter(|line| line.address.is_some()).map(|line| {
+            let expanded = line.expanded.as_deref().map_or("null".to_string(), json_string);
+            format!(
+                "    {{\"file\": {}, \"line\": {}, \"expanded\": {expanded}, \"address\": {}, \"words\": [{}]}}",
+                json_string(&paths[line.file]), line.line, line.address.unwrap_or(0), words(&line.words),
+            )
+        }).collect();
+        out.push_str(&format!("  \"source_map\": [\n{}\n  ]\n}}\n", lines.join(",\n")));
+        out
+    }
+}
+
+// A record: the byte count, address and type, the bytes, and what makes them all add to zero
+fn hex_record(address: u16, r#type: u8, data: &[u8]) -> String {
+    let [high, low] = address.to_be_bytes();
+    let bytes: Vec<u8> = [data.len() as u8, high, low, r#type].into_iter().chain(data.iter().copied()).collect();
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let hex: String = bytes.iter().chain([&sum.wrapping_neg()]).map(|byte| format!("{byte:02X}")).collect();
+    format!(":{hex}\n")
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = "\"".to_string();
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            _ if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,494 @@
+use crate::constants::*;
+use crate::error::*;
+use crate::expr::*;
+use crate::macros::*;
+use crate::numbers::*;
+use crate::types::*;
+use std::iter::Peekable;
+
+// Directives, which can't name a variable any more than instructions can
+const DIRECTIVES: [&str; 13] = ["DEC", "OCT", "ERASE", "EQUALS", "SETLOC", "BANK", "BLOCK", "COUNT", "NOOP", "VEC", "CONST", "EXPORT", "EXTERN"];
+
+// Reads one source file. Addresses aren't known until every file is linked together.
+// A wrong line doesn't stop the parsing, every error of the file is returned
+pub fn parse(source: &str) -> Result<Ast, Diagnostics> {
+    parse_with(source, &mut Definitions::default())
+}
+
+// Parses using the macros and constants defined before, and adds the ones of this file to them
+pub fn parse_with(source: &str, definitions: &mut Definitions) -> Result<Ast, Diagnostics> {
+    let mut ast = Ast::default();
+    let mut errors = vec![];
+    let mut state = State::default();
+    let mut preprocessor = Preprocessor::new(definitions);
+    let mut last = Span::default();
+
+    for (index, text) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let first = text.split_whitespace().next().unwrap_or(text);
+        let origin = Span::of(first, text, line_number);
+        last = origin;
+        let constants = ast.constants.len();
+
+        let result = match preprocessor.line(text) {
+            Ok(Output::Keep) => {
+                let before = (ast.code.len(), ast.data.len(), ast.labels.len());
+                let result = parse_line(&mut ast, &mut state, text, line_number, None);
+                list(&mut ast, state.section, line_number, None, before);
+                result
+            }
+            Ok(Output::Skip) => Ok(()),
+            // Everything in an expansion points at the macro's name
+            Ok(Output::Expand(lines)) => {
+                let mut result = Ok(());
+                for line in &lines {
+                    let before = (ast.code.len(), ast.data.len(), ast.labels.len());
+                    result = result.and(parse_line(&mut ast, &mut state, line, line_number, Some(origin)));
+                    list(&mut ast, state.section, line_number, Some(line), before);
+                }
+                ast.expansions.push(Expansion { line: line_number, lines });
+                result
+            }
+            Err(kind) => Err(Error::new(kind, origin)),
+        };
+        if let Err(error) = result {
+            errors.push(error);
+        }
+
+        for constant in &ast.constants[constants..] {
+            preprocessor.definitions().constant(&constant.name, &constant.expr);
+        }
+    }
+    if let Some(kind) = preprocessor.finish() {
+        errors.push(Error::new(kind, last));
+    }
+    ast.macros = preprocessor.defined().to_vec();
+    finish(ast, &mut state, errors)
+}
+
+// References to numeric labels get the name of the label they mean. The ones with no label are left as
+// they are, to be reported as undefined
+pub(crate) fn finish(mut ast: Ast, state: &mut State, errors: Vec<Error>) -> Result<Ast, Diagnostics> {
+    pool(&mut ast, state);
+    for (offset, instruction) in ast.code.iter_mut().enumerate() {
+        let labels = &state.numeric_labels;
+        instruction.operand.expr.rename(&|symbol| numeric_label(labels, symbol, offset as u16));
+    }
+
+    if errors.is_empty() { Ok(ast) } else { Err(Diagnostics { errors, warnings: ast.warnings }) }
+}
+
+// Notes the words the line added, `before` being the length of the code, data and labels before it. Lines
+// with only a label are kept too, to show where it points
+pub(crate) fn list(ast: &mut Ast, section: Section, line: usize, expanded: Option<&String>, before: (usize, usize, usize)) {
+    // A pool the line ended is listed already, a line for each word, and each word has its label
+    let pooled = ast.lines.iter().filter(|l| l.line == line && l.expanded.is_some() && l.offset as usize >= before.0);
+    let pooled: Vec<usize> = pooled.filter(|l| l.section == Section::Code).map(|l| (l.offset + l.len) as usize).collect();
+    let before = (pooled.iter().copied().max().unwrap_or(before.0), before.1, before.2 + pooled.len());
+    let (section, offset, len) = if ast.code.len() > before.0 {
+        (Section::Code, before.0, ast.code.len() - before.0)
+    } else if ast.data.len() > before.1 {
+        (Section::Data, before.1, ast.data.len() - before.1)
+    } else if expanded.is_some() || ast.labels.len() > before.2 {
+        (section, section_len(ast, section) as usize, 0)
+    } else {
+        return;
+    };
+    ast.lines.push(SourceLine { line, expanded: expanded.cloned(), section, offset: offset as u16, len: len as u16 });
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    pub(crate) section: Section,
+    next_extended: bool,
+    // Where the next ERASE goes, after a SETLOC in the config section
+    erasable_location: Option<u16>,
+    // Number, offset and name given to each numeric label of the code
+    numeric_labels: Vec<(String, u16, String)>,
+    // Literals used since the last pool: word, label and how it was first written. Pools so far and the
+    // line they go after
+    literals: Vec<(u16, String, String, Span)>,
+    pools: usize,
+    line: usize,
+}
+
+// Puts the literals used so far after the code, each word once, with a label for the instructions that
+// use it. A pool ends each stretch of code that's placed on its own, so it's in the same bank as them
+fn pool(ast: &mut Ast, state: &mut State) {
+    for (word, name, text, span) in std::mem::take(&mut state.literals) {
+        let before = (ast.code.len(), ast.data.len(), ast.labels.len());
+        ast.labels.push(UndefinedLabel::new(&name, Section::Code, ast.code.len() as u16, span));
+        ast.code.push(Instruction::new("WORD", UndefinedSymbol::new(Expr::Number(word as i64), None, span), span, false));
+        list(ast, Section::Code, state.line, Some(&text), before);
+    }
+    state.pools += 1;
+}
+
+// `1b` is the last `1:` at or before the instruction, `1f` the first one after it
+fn numeric_label(labels: &[(String, u16, String)], reference: &str, offset: u16) -> Option<String> {
+    if !is_numeric_reference(reference) {
+        return None;
+    }
+    let (number, direction) = reference.split_at(reference.len() - 1);
+    let mut candidates = labels.iter().filter(|(n, ..)| n == number);
+    let label = if direction == "b" {
+        candidates.rfind(|(_, o, _)| *o <= offset)
+    } else {
+        candidates.find(|(_, o, _)| *o > offset)
+    };
+    label.map(|(.., name)| name.clone())
+}
+
+// Lines from a macro have an origin, where the macro is used
+pub(crate) fn parse_line(ast: &mut Ast, state: &mut State, text: &str, line_number: usize, origin: Option<Span>) -> Result<(), Error> {
+    let sections = [Section::None, Section::Config, Section::Erasable, Section::Code, Section::Data];
+    let span = |token: &str| origin.unwrap_or_else(|| Span::of(token, text, line_number));
+    let mut line = text.split_whitespace().peekable();
+    state.line = line_number;
+
+    // Ignore blank lines
+    let Some(first) = line.next() else {
+        return Ok(());
+    };
+    let error = |kind| Error::new(kind, span(first));
+    // Errors about a missing token point right after the last one
+    let missing = |kind, last: &str| {
+        let Span { line, column, len } = span(last);
+        Error::new(kind, origin.unwrap_or(Span::new(line, column + len, 1)))
+    };
+
+    // Ignore comments
+    if first.starts_with('#') {
+        return Ok(());
+    }
+
+    // Handle sections
+    if let Some(name) = first.strip_prefix('.') {
+        let sec = Section::from_name(name).ok_or(error(ErrorKind::InvalidSection(name.to_string())))?;
+        let position = |s: Section| sections.iter().position(|&e| e == s).unwrap();
+
+        if position(sec) < position(state.section) {
+            return Err(error(ErrorKind::SectionOrder));
+        }
+        if state.section == Section::Code && sec != Section::Code {
+            pool(ast, state);
+        }
+
+        state.section = sec;
+        return Ok(());
+    }
+
+    // NAME EQUALS expr, or NAME = expr
+    if state.section != Section::None && matches!(line.peek(), Some(&("EQUALS" | "="))) {
+        let directive = line.next().unwrap();
+        let value = line.next().ok_or(missing(ErrorKind::MissingOperand, directive))?;
+        let expr = parse_expr(value).map_err(|kind| Error::new(kind, span(value)))?;
+        ast.constants.push(Constant { name: first.to_string(), expr, equals: true, span: span(first) });
+        trailing(ast, &mut line, &span);
+        return Ok(());
+    }
+
+    // SETLOC, BANK and BLOCK take a number, where the next words go
+    let location = |line: &mut dyn Iterator<Item = &str>| {
+        let operand = line.next().ok_or(missing(ErrorKind::MissingOperand, first))?;
+        match parse_integer(operand) {
+            Some((false, n)) if n <= u16::MAX as u64 => Ok((n as u16, span(operand))),
+            _ => Err(Error::new(ErrorKind::InvalidNumber(operand.to_string()), span(operand))),
+        }
+    };
+
+    let erasable = matches!(state.section, Section::Config | Section::Erasable);
+
+    if erasable && first == "SETLOC" {
+        state.erasable_location = Some(location(&mut line)?.0);
+        trailing(ast, &mut line, &span);
+        return Ok(());
+    }
+
+    // NAME ERASE n, or ERASE n to leave the words unnamed. n is 1 if it's not given
+    if erasable && (first == "ERASE" || line.peek() == Some(&"ERASE")) {
+        let name = match first {
+            "ERASE" => None,
+            _ => {
+                line.next();
+                Some(variable_name(first).map_err(error)?.to_string())
+            }
+        };
+        let len = match line.next_if(|token| !token.starts_with('#')) {
+            Some(len) => match parse_integer(len) {
+                Some((false, n)) if (1..=1024).contains(&n) => n as u16,
+                _ => return Err(Error::new(ErrorKind::InvalidNumber(len.to_string()), span(len))),
+            },
+            None => 1,
+        };
+        let address = state.erasable_location;
+        state.erasable_location = address.map(|a| a.saturating_add(len));
+        ast.erased.push(Erase { name, len, address, init: vec![], span: span(first) });
+        trailing(ast, &mut line, &span);
+        return Ok(());
+    }
+
+    // NAME, NAME n for n words, or NAME DEC 5 (2DEC, OCT, 2OCT) for the words of the number
+    if state.section == Section::Erasable {
+        let name = variable_name(first).map_err(error)?;
+        let (len, init) = match line.next_if(|token| !token.starts_with('#')) {
+            None => (1, vec![]),
+            Some(directive @ ("DEC" | "2DEC" | "OCT" | "2OCT")) => {
+                let number = line.next().ok_or(missing(ErrorKind::MissingOperand, directive))?;
+                let words = number_words(directive, number, &mut line, &span)?;
+                (words.len() as u16, words)
+            }
+            Some(len) => match parse_integer(len) {
+                Some((false, n)) if (1..=1024).contains(&n) => (n as u16, vec![]),
+                _ => return Err(Error::new(ErrorKind::InvalidNumber(len.to_string()), span(len))),
+            },
+        };
+        let address = state.erasable_location;
+        state.erasable_location = address.map(|a| a.saturating_add(len));
+        ast.erased.push(Erase { name: Some(name.to_string()), len, address, init, span: span(name) });
+        trailing(ast, &mut line, &span);
+        return Ok(());
+    }
+
+    let placed = matches!(state.section, Section::Code | Section::Data);
+
+    // BLOCK is BANK for the fixed-fixed banks
+    if placed && matches!(first, "SETLOC" | "BANK" | "BLOCK") {
+        let (n, operand) = location(&mut line)?;
+        if state.section == Section::Code {
+            pool(ast, state);
+        }
+        let place = if first == "SETLOC" { Place::Address(n) } else { Place::Bank(n) };
+        let offset = section_len(ast, state.section);
+        ast.placements.push(Placement { section: state.section, offset, place, span: operand });
+        trailing(ast, &mut line, &span);
+        return Ok(());
+    }
+
+    if placed && matches!(first, "COUNT" | "COUNT*") {
+        let name = line.next().ok_or(missing(ErrorKind::MissingName, first))?;
+        let offset = section_len(ast, state.section);
+        ast.counts.push(Count { name: name.to_string(), section: state.section, offset });
+        trailing(ast, &mut line, &span);
+        return Ok(());
+    }
+
+    if state.section == Section::Config && first == "VEC" {
+        let name = line.next().ok_or(missing(ErrorKind::MissingName, first))?;
+        let len = line.next().ok_or(missing(ErrorKind::MissingLength, name))?;
+        let invalid = Error::new(ErrorKind::InvalidNumber(len.to_string()), span(len));
+        let len = match parse_integer(len) {
+            Some((false, len)) if len <= 1024 => len as u16,
+            _ => return Err(invalid),
+        };
+        ast.tables.push(UndefinedTable::new(name, len));
+    }
+
+    // Labels are only seen by their own file unless they're exported, and the files using them declare
+    // them EXTERN. `EXPORT A,B` can list several
+    if state.section == Section::Config && matches!(first, "EXPORT" | "EXTERN") {
+        let names = line.next().ok_or(missing(ErrorKind::MissingName, first))?;
+        let list = if first == "EXPORT" { &mut ast.exports } else { &mut ast.externs };
+        list.extend(names.split(',').filter(|name| !name.is_empty()).map(|name| (name.to_string(), span(name))));
+        trailing(ast, &mut line, &span);
+        return Ok(());
+    }
+
+    if state.section == Section::Config && first == "CONST" {
+        let name = line.next().ok_or(missing(ErrorKind::MissingName, first))?;
+        let value = line.next().ok_or(missing(ErrorKind::MissingOperand, name))?;
+        let expr = parse_expr(value).map_err(|kind| Error::new(kind, span(value)))?;
+        ast.constants.push(Constant { name: name.to_string(), expr, equals: false, span: span(name) });
+    }
+
+    if state.section == Section::Data {
+        // For labels
+        if let Some(name) = first.strip_suffix(':') {
+            let label = UndefinedLabel::new(name, Section::Data, ast.data.len() as u16, span(name));
+            if ast.labels.contains(&label) {
+                return Err(error(ErrorKind::DuplicatedLabel(name.to_string())));
+            }
+            ast.labels.push(label);
+            return Ok(());
+        }
+
+        let number = line.next().ok_or(missing(ErrorKind::MissingOperand, first))?;
+
+        if !matches!(first, "DEC" | "2DEC" | "OCT" | "2OCT") {
+            return Err(error(ErrorKind::OnlyNumbersInData));
+        }
+        let words = number_words(first, number, &mut line, &span)?;
+
+        ast.data.extend(words);
+        trailing(ast, &mut line, &span);
+    }
+
+    if state.section == Section::Code {
+        // For labels
+        if let Some(name) = first.strip_suffix(':') {
+            let offset = ast.code.len() as u16;
+            // Numeric labels can be repeated, each one gets a name of its own
+            let unique = if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) {
+                let unique = format!("{name}@{}", state.numeric_labels.len());
+                state.numeric_labels.push((name.to_string(), offset, unique.clone()));
+                unique
+            } else {
+                name.to_string()
+            };
+            let label = UndefinedLabel::new(&unique, Section::Code, offset, span(name));
+            if ast.labels.contains(&label) {
+                return Err(error(ErrorKind::DuplicatedLabel(name.to_string())));
+            }
+            ast.labels.push(label);
+            return Ok(());
+        }
+
+        // Constants among the instructions: numbers, and addresses in the forms the AGC uses for them.
+        // 2CADR is the address and the bank, for DTCB
+        if matches!(first, "DEC" | "2DEC" | "OCT" | "2OCT") {
+            let number = line.next().ok_or(missing(ErrorKind::MissingOperand, first))?;
+            for word in number_words(first, number, &mut line, &span)? {
+                let operand = UndefinedSymbol::new(Expr::Number(word as i64), None, span(number));
+                ast.code.push(Instruction::new("WORD", operand, span(first), false));
+            }
+            state.next_extended = false;
+            trailing(ast, &mut line, &span);
+            return Ok(());
+        }
+        if ADDRESS_CONSTANTS.contains(&first) || first == "2CADR" {
+            let operand = line.next().ok_or(missing(ErrorKind::MissingOperand, first))?;
+            let expr = parse_expr(operand).map_err(|kind| Error::new(kind, span(operand)))?;
+            let forms: &[&str] = match first {
+                "2CADR" => &["GENADR", "BBCON"],
+                _ => &[first],
+            };
+            for &form in forms {
+                let r#type = match form {
+                    "ECADR" => Some(SymbolType::Variable),
+                    "FCADR" | "BBCON" => Some(SymbolType::Label),
+                    _ => None,
+                };
+                let symbol = UndefinedSymbol::new(expr.clone(), r#type, span(operand));
+                ast.code.push(Instruction::new(form, symbol, span(first), false));
+            }
+            state.next_extended = false;
+            trailing(ast, &mut line, &span);
+            return Ok(());
+        }
+
+        // EXTEND only reaches the next instruction, INDEX passes it along
+        let extended = state.next_extended;
+        state.next_extended = first == "EXTEND" || (first == "INDEX" && extended);
+
+        if EXTENDED.contains(&first) && !extended {
+            return Err(error(ErrorKind::ExtendedWithoutExtend(first.to_string())));
+        }
+        if extended && !EXTENDED.contains(&first) && first != "INDEX" {
+            ast.warnings.push(Warning::new(WarningKind::BasicAfterExtend(first.to_string()), span(first)));
+        }
+
+        // Named instructions are assembled as what they stand for
+        if let Some(&(_, base, address)) = NAMED.iter().find(|(name, ..)| *name == first) {
+            let operand = UndefinedSymbol::new(Expr::Number(address as i64), None, span(first));
+            ast.code.push(Instruction::new(base, operand, span(first), extended));
+        } else if first == "NOOP" {
+            // A jump to the next word, which is faster than CA A. It gets a label of its own to jump to
+            let offset = ast.code.len() as u16;
+            let name = format!("NOOP@{offset}");
+            ast.labels.push(UndefinedLabel::new(&name, Section::Code, offset + 1, span(first)));
+            let operand = UndefinedSymbol::new(Expr::Symbol(name), Some(SymbolType::Label), span(first));
+            ast.code.push(Instruction::new("TCF", operand, span(first), extended));
+        } else {
+            let operation = first;
+            let operand = line.next().ok_or(missing(ErrorKind::MissingOperand, first))?;
+
+            let r#type = if operation == "INDEX" && !extended {
+                // Only the extracode INDEX has 12 bits of address, the basic one works on erasable
+                Some(SymbolType::Variable)
+            } else if GENERAL.contains(&operation) || CHANNEL.contains(&operation) {
+                None
+            } else if ERASABLE.contains(&operation) {
+                Some(SymbolType::Variable)
+            } else if FIXED.contains(&operation) {
+                Some(SymbolType::Label)
+            } else {
+                return Err(error(ErrorKind::InvalidInstruction(operation.to_string())));
+            };
+
+            // `CA =255` reads a word of the pool that holds 255
+            let expr = match operand.strip_prefix('=') {
+                Some(number) => {
+                    let erasable = r#type.is_some() || DOUBLE.contains(&operation) || CHANNEL.contains(&operation);
+                    if erasable {
+                        return Err(Error::new(ErrorKind::LiteralOperand(operation.to_string()), span(operand)));
+                    }
+                    let word = dec(number, &[], false).map_err(|kind| Error::new(kind, span(operand)))?[0];
+                    Expr::Symbol(literal(state, word, operand, span(operand)))
+                }
+                None => parse_expr(operand).map_err(|kind| Error::new(kind, span(operand)))?,
+            };
+            let operand = UndefinedSymbol::new(expr, r#type, span(operand));
+            ast.code.push(Instruction::new(operation, operand, span(operation), extended));
+        }
+        trailing(ast, &mut line, &span);
+    }
+
+    Ok(())
+}
+
+// Label of the word in the pool, the same for every literal with that word
+fn literal(state: &mut State, word: u16, text: &str, span: Span) -> String {
+    if let Some((.., name, _, _)) = state.literals.iter().find(|(w, ..)| *w == word) {
+        return name.clone();
+    }
+    let name = format!("{text}@{}.{}", state.pools, state.literals.len());
+    state.literals.push((word, name.clone(), text.to_string(), span));
+    name
+}
+
+// The name of a variable, `NAME` or `NAME:` like a label. It has to be a name that isn't an instruction or
+// a directive, so a line in the wrong section isn't taken for a variable
+fn variable_name(name: &str) -> Result<&str, ErrorKind> {
+    let name = name.strip_suffix(':').unwrap_or(name);
+    let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let reserved = [&GENERAL[..], &ERASABLE, &FIXED, &EXTENDED, &CHANNEL, &ADDRESS_CONSTANTS, &DIRECTIVES].concat().contains(&name)
+        || NAMED.iter().any(|(named, ..)| *named == name);
+    if identifier && !reserved { Ok(name) } else { Err(ErrorKind::InvalidName(name.to_string())) }
+}
+
+// Words of a DEC, 2DEC, OCT or 2OCT. DEC numbers can be followed by scale factors, which are separate tokens
+fn number_words<'a, I: Iterator<Item = &'a str>>(
+    directive: &str,
+    number: &'a str,
+    line: &mut Peekable<I>,
+    span: &impl Fn(&str) -> Span,
+) -> Result<Vec<u16>, Error> {
+    if directive.ends_with("OCT") {
+        return oct(number, directive == "2OCT").map_err(|kind| Error::new(kind, span(number)));
+    }
+    let mut scales = vec![];
+    let mut last = number;
+    while let Some(scale) = line.peek().and_then(|token| parse_scale(token)) {
+        scales.push(scale);
+        last = line.next().unwrap();
+    }
+    let (first, last) = (span(number), span(last));
+    let whole = Span { len: last.column + last.len - first.column, ..first };
+    dec(number, &scales, directive == "2DEC").map_err(|kind| Error::new(kind, whole))
+}
+
+// Words of code or data the file has so far
+fn section_len(ast: &Ast, section: Section) -> u16 {
+    if section == Section::Code { ast.code.len() as u16 } else { ast.data.len() as u16 }
+}
+
+// Warns about anything left on the line that isn't a comment
+fn trailing<'a>(ast: &mut Ast, rest: impl Iterator<Item = &'a str>, span: &impl Fn(&str) -> Span) {
+    let tokens: Vec<&str> = rest.take_while(|token| !token.starts_with('#')).collect();
+    if let (Some(first), Some(last)) = (tokens.first(), tokens.last()) {
+        let (first, last) = (span(first), span(last));
+        let len = last.column + last.len - first.column;
+        ast.warnings.push(Warning::new(WarningKind::TrailingText, Span { len, ..first }));
+    }
+}
@@ -0,0 +1,954 @@
+use crate::*;
+use crate::constants::*;
+use crate::types::*;
+
+// The programs of the rope, in the order of the manifest
+fn programs() -> Vec<String> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../programs/");
+    let manifest = std::fs::read_to_string(format!("{dir}manifest.txt")).unwrap();
+    manifest.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|file| std::fs::read_to_string(format!("{dir}{file}")).unwrap())
+        .collect()
+}
+
+fn symbol<'a>(image: &'a Image, name: &str) -> &'a DefinedSymbol {
+    image.symbols.iter().find(|s| s.name == name).unwrap()
+}
+
+// Kind of the first error parsing `source`
+fn parse_error_kind(source: &str) -> ErrorKind {
+    parse(source).unwrap_err().errors[0].kind.clone()
+}
+
+// Kinds of every error assembling `sources`
+fn link_error_kinds(sources: &[&str]) -> Vec<ErrorKind> {
+    assemble(sources).unwrap_err().errors.into_iter().map(|e| e.kind).collect()
+}
+
+#[test]
+fn test_assemble_program() {
+    let source = "
+.code
+START:
+    CA X
+    TS Y # Trailing comment
+    EXTEND
+    BZF START
+    TCF END
+END:
+    TCF END
+.data
+X:
+    DEC -3
+";
+    let image = assemble(&[source]).unwrap();
+
+    assert_eq!(image.fixed, [
+        decode("CA") + 2054,
+        decode("TS") + RAM_START,
+        decode("EXTEND"),
+        decode("BZF") + 2048,
+        decode("TCF") + 2053,
+        decode("TCF") + 2053,
+        0o77774,
+    ]);
+    assert_eq!(symbol(&image, "Y").address, RAM_START);
+    assert_eq!(image.erasable_end, RAM_START + 1);
+}
+
+#[test]
+fn test_files_are_laid_out_in_order() {
+    let first = ".config\n    EXPORT A\n    EXTERN B\n.code\nA:\n    TCF B\n.data\nD1:\n    DEC 1\n";
+    let second = ".config\n    EXPORT B\n    EXTERN A\n.code\nB:\n    TCF A\n.data\nD2:\n    DEC 2\n";
+    let image = assemble(&[first, second]).unwrap();
+
+    // Code of every file, then data of every file
+    assert_eq!(symbol(&image, "A").address, 2048);
+    assert_eq!(symbol(&image, "B").address, 2049);
+    assert_eq!(symbol(&image, "D1").address, 2050);
+    assert_eq!(symbol(&image, "D2").address, 2051);
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(parse_error_kind(".stack"), ErrorKind::InvalidSection("stack".to_string()));
+    assert_eq!(parse_error_kind(".data\n.code"), ErrorKind::SectionOrder);
+    assert_eq!(parse_error_kind(".code\nA:\nA:"), ErrorKind::DuplicatedLabel("A".to_string()));
+    assert_eq!(parse_error_kind(".code\n    BZF A"), ErrorKind::ExtendedWithoutExtend("BZF".to_string()));
+    assert_eq!(parse_error_kind(".code\n    FOO A"), ErrorKind::InvalidInstruction("FOO".to_string()));
+    assert_eq!(parse_error_kind(".code\n    CA"), ErrorKind::MissingOperand);
+    assert_eq!(parse_error_kind(".data\n    HEX 7"), ErrorKind::OnlyNumbersInData);
+    assert_eq!(parse(".code\n\n    CA\n").unwrap_err().errors[0].span, Span::new(3, 7, 1));
+
+    // Parsing goes on after an error
+    let errors = parse(".code\n    FOO A\n    CA\n.data\n    DEC x").unwrap_err().errors;
+    let lines: Vec<usize> = errors.iter().map(|e| e.span.line).collect();
+    assert_eq!(lines, [2, 3, 5]);
+}
+
+#[test]
+fn test_link_errors() {
+    let exports_a = ".config\n    EXPORT A\n.code\nA:\n    TCF A";
+    let errors = assemble(&[exports_a, exports_a]).unwrap_err().errors;
+    assert_eq!(errors[0].kind, ErrorKind::LabelInMultipleFiles("A".to_string()));
+    assert_eq!((errors[0].file, errors[0].span.line), (Some(1), 4));
+
+    // Every wrong operand is reported
+    let errors = assemble(&[".code\nA:\n    TCF NOWHERE\n    TS A\n    TCF ACC"]).unwrap_err().errors;
+    let kinds: Vec<ErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+    assert_eq!(kinds, [
+        ErrorKind::UndefinedLabel("NOWHERE".to_string()),
+        ErrorKind::ExpectedErasable("TS".to_string()),
+        ErrorKind::ExpectedFixed("TCF".to_string()),
+    ]);
+}
+
+#[test]
+fn test_local_labels() {
+    // Each file has its own LOOP, and they can share the exported ones
+    let first = ".config\n    EXPORT WAIT\n.code\nLOOP:\n    TCF LOOP\nWAIT:\n    TC Q\n";
+    let second = ".config\n    EXTERN WAIT\n.code\nLOOP:\n    TC WAIT\n    TCF LOOP\n";
+    let image = assemble(&[first, second]).unwrap();
+    assert_eq!(image.fixed, [decode("TCF") + 2048, decode("TC") + 2, decode("TC") + 2049, decode("TCF") + 2050]);
+
+    // Without EXPORT and EXTERN the label of the other file isn't seen
+    assert_eq!(link_error_kinds(&[".code\nWAIT:\n    TC Q", ".code\n    TC WAIT"]), [ErrorKind::NotImported("WAIT".to_string())]);
+    assert_eq!(link_error_kinds(&[".code\nWAIT:\n    TC Q", ".config\n    EXTERN WAIT\n.code\n    TC WAIT"]), [
+        ErrorKind::NotExported("WAIT".to_string()),
+        ErrorKind::NotImported("WAIT".to_string()),
+    ]);
+    assert_eq!(link_error_kinds(&[".config\n    EXPORT X\n.code\n    TC Q"]), [ErrorKind::UndefinedLabel("X".to_string())]);
+    // A file can't define a label it takes from another
+    let errors = link_error_kinds(&[first, ".config\n    EXTERN WAIT\n.code\nWAIT:\n    TC WAIT"]);
+    assert_eq!(errors, [ErrorKind::LabelInMultipleFiles("WAIT".to_string())]);
+}
+
+#[test]
+fn test_numeric_labels() {
+    let source = "
+.code
+1:
+    CCS X
+    TCF 1f
+    TCF 1b
+1:
+    TCF 1b
+    TCF 2f
+2:
+    TC Q
+";
+    let image = assemble(&[source]).unwrap();
+    assert_eq!(image.fixed[1..], [
+        decode("TCF") + 2051,
+        decode("TCF") + 2048,
+        decode("TCF") + 2051,
+        decode("TCF") + 2053,
+        decode("TC") + 2,
+    ]);
+    assert!(image.warnings.iter().all(|w| !matches!(w.kind, WarningKind::UnusedLabel(_))));
+
+    // There's no label 1 after the last one
+    let errors = assemble(&[".code\n1:\n    TCF 1f"]).unwrap_err().errors;
+    assert_eq!(errors[0].kind, ErrorKind::UndefinedLabel("1f".to_string()));
+}
+
+#[test]
+fn test_programs() {
+    let programs = programs();
+    let sources: Vec<&str> = programs.iter().map(String::as_str).collect();
+    let image = assemble(&sources).unwrap();
+
+    assert_eq!(symbol(&image, "INICIO").address, FIXED_START);
+    assert_eq!(symbol(&image, "PROGS").r#type, SymbolType::LabelTable(6));
+    assert!(image.fixed.len() <= 1024);
+}
+
+#[test]
+fn test_warnings() {
+    let image = assemble(&[".code\nSTART:\n    EXTEND\n    CA X\n    TS Y Z # Comment\nUNUSED:\n    TCF START"]).unwrap();
+    let kinds: Vec<WarningKind> = image.warnings.iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, [
+        WarningKind::BasicAfterExtend("CA".to_string()),
+        WarningKind::TrailingText,
+        WarningKind::UnusedLabel("UNUSED".to_string()),
+        WarningKind::ReadNeverWritten("X".to_string()),
+        WarningKind::WrittenNeverRead("Y".to_string()),
+    ]);
+    assert_eq!(image.warnings[1].span, Span::new(5, 10, 1));
+}
+
+#[test]
+fn test_render() {
+    let source = ".code\n    FOO A\n";
+    let error = &parse(source).unwrap_err().errors[0];
+    assert_eq!(error.render("test.agc", source), "\
+error: invalid instruction 'FOO'
+ --> test.agc:2:5
+  |
+2 |     FOO A
+  |     ^^^
+");
+
+    // A span from a damaged object may have no column, it's shown from the start of the line
+    let error = Error::new(ErrorKind::InvalidObject, Span::new(2, 0, 3));
+    assert!(error.render("test.agc", source).ends_with("2 |     FOO A\n  | ^^^\n"));
+}
+
+// Words a data line assembles to
+fn data(line: &str) -> Result<Vec<u16>, ErrorKind> {
+    parse(&format!(".data\n    {line}")).map(|ast| ast.data).map_err(|d| d.errors[0].kind.clone())
+}
+
+#[test]
+fn test_number_literals() {
+    assert_eq!(data("DEC 12"), Ok(vec![12]));
+    assert_eq!(data("DEC -1"), Ok(vec![0o77776]));
+    assert_eq!(data("DEC -0"), Ok(vec![0o77777]));
+    assert_eq!(data("DEC 0x1F"), Ok(vec![31]));
+    assert_eq!(data("DEC 0b101"), Ok(vec![5]));
+    assert_eq!(data("OCT 37777"), Ok(vec![0o37777]));
+    assert_eq!(data("OCT 77777"), Ok(vec![0o77777]));
+
+    // Fractions, with 1.0 at 2^14
+    assert_eq!(data("DEC 0.5"), Ok(vec![0o20000]));
+    assert_eq!(data("DEC 0.5 B-1"), Ok(vec![0o10000]));
+    assert_eq!(data("DEC -3.2 E2 B-9"), Ok(vec![!0o24000 & 0o77777])); // -0.625
+    assert_eq!(data("DEC 5 B3"), Ok(vec![40]));
+
+    // Double precision, both words with the same sign
+    assert_eq!(data("2DEC 0.5"), Ok(vec![0o20000, 0]));
+    assert_eq!(data("2DEC 16385"), Ok(vec![1, 1]));
+    assert_eq!(data("2DEC -16385"), Ok(vec![0o77776, 0o77776]));
+    assert_eq!(data("2OCT 0123456701"), Ok(vec![0o01234, 0o56701]));
+}
+
+#[test]
+fn test_number_range() {
+    let out_of_range = |number: &str| Err(ErrorKind::NumberOutOfRange(number.to_string()));
+
+    assert_eq!(data("DEC 16383"), Ok(vec![16383]));
+    assert_eq!(data("DEC 16384"), out_of_range("16384"));
+    assert_eq!(data("DEC -16384"), out_of_range("-16384"));
+    assert_eq!(data("DEC 1.0"), out_of_range("1.0"));
+    assert_eq!(data("DEC -3.2 E2 B-7"), out_of_range("-3.2")); // -2.5
+    assert_eq!(data("OCT 100000"), out_of_range("100000"));
+    assert_eq!(data("2DEC 268435456"), out_of_range("268435456"));
+    assert_eq!(data("OCT 8"), Err(ErrorKind::InvalidNumber("8".to_string())));
+
+    // The error covers the scale factors
+    let errors = parse(".data\n    DEC 0.9 B1 # Too big").unwrap_err().errors;
+    assert_eq!(errors[0].span, Span::new(2, 9, 6));
+}
+
+#[test]
+fn test_double_words_are_laid_out() {
+    let image = assemble(&[".code\nA:\n    EXTEND\n    DCA PAIR\n.data\nPAIR:\n    2DEC 0.25\nNEXT:\n    OCT 7"]).unwrap();
+    assert_eq!(symbol(&image, "NEXT").address, symbol(&image, "PAIR").address + 2);
+    assert_eq!(&image.fixed[2..], [0o10000, 0, 7]);
+}
+
+#[test]
+fn test_address_arithmetic() {
+    let source = format!("\
+.config
+    VEC TABLE 8
+    CONST WIDTH 8
+    CONST LAST WIDTH-1
+.code
+START:
+    CA TABLE+LAST
+    CA TABLE+(WIDTH-2)
+    CA START+3
+    CA 0x10
+    TCF START
+.data
+TABLE:
+{}", "    DEC 0\n".repeat(8));
+    let image = assemble(&[&source]).unwrap();
+    let table = symbol(&image, "TABLE").address;
+    assert_eq!(image.fixed[0], decode("CA") + table + 7);
+    assert_eq!(image.fixed[1], decode("CA") + table + 6);
+    assert_eq!(image.fixed[2], decode("CA") + FIXED_START + 3);
+    assert_eq!(image.fixed[3], decode("CA") + 16);
+}
+
+#[test]
+fn test_address_arithmetic_errors() {
+    assert_eq!(link_error_kinds(&[".config\n    VEC T 2\n.code\n    CA T+2\n    CA T-1\n    TS T+1"]), [
+        ErrorKind::OutsideOf("T".to_string(), 2),
+        ErrorKind::OutsideOf("T".to_string(), 2),
+    ]);
+    assert_eq!(link_error_kinds(&[".code\nA:\n    CA A+A\n    CA -A\n    CA A-A"]), [
+        ErrorKind::InvalidAddressArithmetic,
+        ErrorKind::InvalidAddressArithmetic,
+    ]);
+    assert_eq!(link_error_kinds(&[".code\n    CA 32768\n    TS 1024\n    TCF 100"]), [
+        ErrorKind::AddressOutOfRange(32768),
+        ErrorKind::AddressOutOfRange(1024),
+        ErrorKind::AddressOutOfRange(100),
+    ]);
+    assert_eq!(link_error_kinds(&[".config\n    CONST A B\n    CONST B 1\n    CONST B 2\n.code\n    CA B"]), [
+        ErrorKind::UndefinedConstant("B".to_string()),
+        ErrorKind::SymbolDefinedTwice("B".to_string()),
+    ]);
+    assert_eq!(parse_error_kind(".code\n    CA A+"), ErrorKind::InvalidOperand("A+".to_string()));
+
+    // Numbers too big for the arithmetic are out of range, not a crash
+    assert_eq!(link_error_kinds(&[".code\n    CA 9223372036854775807+1\n    CA -(0-9223372036854775807-1)"]), [
+        ErrorKind::NumberOutOfRange("9223372036854775807+1".to_string()),
+        ErrorKind::NumberOutOfRange("-(-9223372036854775808)".to_string()),
+    ]);
+    assert_eq!(parse_error_kind(".code\n    CA 9223372036854775808"), ErrorKind::NumberOutOfRange("9223372036854775808".to_string()));
+}
+
+#[test]
+fn test_double_word_variables() {
+    let image = assemble(&[".code\nA:\n    EXTEND\n    DCA PAIR\n    DXCH PAIR\n    TS OTHER\n    TS PAIR+1"]).unwrap();
+    assert_eq!(symbol(&image, "PAIR").r#type, SymbolType::VariableTable(2));
+    assert_eq!(symbol(&image, "OTHER").address, RAM_START + 2);
+    assert_eq!(image.fixed[1], decode("DCA") + RAM_START);
+    assert_eq!(image.fixed[4], decode("TS") + RAM_START + 1);
+    assert_eq!(image.erasable_end, RAM_START + 3);
+}
+
+#[test]
+fn test_equals() {
+    let image = assemble(&[".config\n    SIZE EQUALS 4\n.code\nSTART:\n    CA LAST\n    TS COUNTER\n    TCF AGAIN\nAGAIN = START\nLAST = TABLE+SIZE-1\n.data\nTABLE:\n    DEC 0"]).unwrap();
+    assert_eq!(image.fixed[0], decode("CA") + symbol(&image, "TABLE").address + 3);
+    assert_eq!(image.fixed[2], decode("TCF") + FIXED_START);
+
+    // Aliases keep the kind of memory of what they name
+    let errors = assemble(&[".code\nSTART:\n    TS HERE\nHERE = START"]).unwrap_err().errors;
+    assert_eq!(errors[0].kind, ErrorKind::ExpectedErasable("TS".to_string()));
+}
+
+#[test]
+fn test_erase() {
+    let image = assemble(&[".config\n    SETLOC 300\n    FLAGS ERASE 2\n    WORD ERASE\n    BUFFER ERASE 3\n.code\nA:\n    TS FIRST\n    TS BUFFER+2\n    TS FLAGS+1"]).unwrap();
+    assert_eq!(symbol(&image, "FLAGS").address, 300);
+    assert_eq!(symbol(&image, "WORD").address, 302);
+    assert_eq!(symbol(&image, "BUFFER").r#type, SymbolType::VariableTable(3));
+    // Implicit variables go around the reserved words
+    assert_eq!(symbol(&image, "FIRST").address, RAM_START);
+    assert_eq!(image.fixed[1], decode("TS") + 305);
+
+    assert_eq!(link_error_kinds(&[".config\n    SETLOC 270\n    X ERASE 2\n    SETLOC 510\n    Y ERASE 4"]), [
+        ErrorKind::ErasableOverlap("X".to_string(), "POTE".to_string()),
+        ErrorKind::ErasableFull("Y".to_string()),
+    ]);
+}
+
+#[test]
+fn test_setloc_and_bank() {
+    let source = "\
+.code
+START:
+    TCF FAR
+    COUNT MAIN
+    SETLOC 3000
+FAR:
+    TCF START
+    BANK 2
+NEXT:
+    TCF NEXT
+.data
+    BLOCK 3
+VALUE:
+    DEC 5";
+    let image = assemble(&[source]).unwrap();
+    assert_eq!(symbol(&image, "FAR").address, 3000);
+    assert_eq!(symbol(&image, "NEXT").address, 3001);
+    assert_eq!(symbol(&image, "VALUE").address, 3072);
+    assert_eq!(image.fixed[0], decode("TCF") + 3000);
+    assert_eq!(image.fixed[1024], 5);
+    assert_eq!(image.fixed.len(), 1025);
+    assert_eq!(image.counts, [BankCount { name: "MAIN".to_string(), bank: 2, words: 2 }]);
+
+    assert_eq!(link_error_kinds(&[".code\nA:\n    TCF A\n    SETLOC 2048\n    TCF A\n    BANK 1\n    TCF A\n    SETLOC 40000\n    TCF A"]), [
+        ErrorKind::Overlap(2048),
+        ErrorKind::AddressOutOfRange(40000),
+        ErrorKind::NoSuchBank(1),
+    ]);
+}
+
+#[test]
+fn test_bank_layout() {
+    let main = ".config\n    EXTERN FAR\n.code\nSTART:\n    TC FAR\n    TC FAR\n    TCF START";
+    let filler = ".data\n".to_string() + &"    DEC 1\n".repeat(1000);
+    let far = ".config\n    EXPORT FAR\n.code\nFAR:\n    CA TABLE\n    RETURN\n.data\nTABLE:\n".to_string() + &"    DEC 2\n".repeat(500);
+    let image = assemble(&[main, &filler, &filler, &far]).unwrap();
+
+    // The last file doesn't fit in fixed-fixed memory anymore, it goes to bank 4 with its data
+    assert_eq!(symbol(&image, "FAR").address, 4 * BANK_SIZE);
+    assert_eq!(symbol(&image, "TABLE").address, 4 * BANK_SIZE + 2);
+    assert_eq!(image.fixed[4096 - 2048], decode("CA") + 1026);
+    assert_eq!(image.fixed.len(), 4096 - 2048 + 502);
+
+    // Both calls go through the same stub, after FARCALL
+    let stub = symbol(&image, "FAR@FAR").address;
+    assert_eq!(symbol(&image, "FARCALL@").address, 2048 + 2003);
+    assert_eq!(stub, 2048 + 2003 + 33);
+    assert_eq!(image.fixed[..2], [decode("TC") + stub, decode("TC") + stub]);
+    let saved = symbol(&image, "FARCALL@SAVED").address;
+    assert_eq!(image.fixed[(stub - 2048) as usize..][..5], [
+        decode("XCH") + saved,
+        decode("CA") + 2,
+        decode("TC") + 2048 + 2003,
+        1024,
+        4 * BANK_SIZE,
+    ]);
+    assert_eq!(image.verify(), []);
+    let listing = image.to_listing(&["main".to_string(), "a".to_string(), "b".to_string(), "far".to_string()], &[main, &filler, &filler, &far]);
+    assert!(listing.contains("  04 2000 32002      5     CA TABLE"));
+
+    let data = ".config\n    EXTERN TABLE\n.code\n    CA TABLE";
+    let table = ".config\n    EXPORT TABLE\n.data\n    BANK 6\nTABLE:\n    DEC 1";
+    assert_eq!(link_error_kinds(&[data, table]), [ErrorKind::OtherBank("TABLE".to_string(), 6)]);
+    let large = ".data\n".to_string() + &"    DEC 1\n".repeat(1100);
+    assert_eq!(link_error_kinds(&[&filler, &filler, &large]), [ErrorKind::FileOverBank(1100)]);
+}
+
+#[test]
+fn test_erasable_section() {
+    let source = "\
+.vars
+    CICLOS
+    POS 2
+    LIVES DEC 3
+    SPEED 2DEC 0.5
+    MOVES 4
+.code
+START:
+    CA LIVES
+    TS CICLOS
+    CA CICLOS
+    EXTEND
+    DCA SPEED
+    TS POS+1
+    CA MOVES+3";
+    let image = assemble(&[source]).unwrap();
+    assert_eq!(symbol(&image, "CICLOS").address, RAM_START);
+    assert_eq!(symbol(&image, "POS").r#type, SymbolType::VariableTable(2));
+    assert_eq!(symbol(&image, "LIVES").address, RAM_START + 3);
+    assert_eq!(image.erasable, [(RAM_START + 3, 3), (RAM_START + 4, 0o20000), (RAM_START + 5, 0)]);
+
+    let kinds: Vec<WarningKind> = image.warnings.into_iter().map(|w| w.kind).collect();
+    assert_eq!(kinds, [
+        WarningKind::WrittenNeverRead("POS".to_string()),
+        WarningKind::ReadNeverWritten("MOVES".to_string()),
+    ]);
+
+    // A name can end with a colon like a label, but instructions, directives and other words aren't names
+    let image = assemble(&[".vars
+    COUNTER:
+    STEPS: 2
+.code
+START:
+    TS COUNTER
+    CA STEPS+1"]).unwrap();
+    assert_eq!(symbol(&image, "STEPS").r#type, SymbolType::VariableTable(2));
+    assert_eq!(parse_error_kind(".vars
+    DEC 3"), ErrorKind::InvalidName("DEC".to_string()));
+    assert_eq!(parse_error_kind(".vars
+    CA X"), ErrorKind::InvalidName("CA".to_string()));
+    assert_eq!(parse_error_kind(".vars
+    X+1"), ErrorKind::InvalidName("X+1".to_string()));
+    assert_eq!(parse_error_kind(".config
+    TS ERASE 2"), ErrorKind::InvalidName("TS".to_string()));
+}
+
+#[test]
+fn test_strict() {
+    let source = ".vars\n    CICLOS\n.code\nSTART:\n    TS CICLOS\n    CA CICLO\n    TS CICLO";
+    let options = Options { strict: true, ..Options::default() };
+    let errors = assemble_with(&[source], &options).unwrap_err().errors;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, ErrorKind::Undeclared("CICLO".to_string()));
+    assert_eq!(errors[0].span, Span::new(6, 8, 5));
+
+    // Without it the typo is a new variable
+    assert!(assemble(&[source]).is_ok());
+}
+
+#[test]
+fn test_macros() {
+    let macros = "\
+MACRO CLAMP var,max
+    CS var
+    AD max
+    COM
+    EXTEND
+    BZMF @OK
+    CA max
+    TS var
+@OK:
+ENDM
+MACRO TWICE var,max
+    CLAMP var, max
+    CLAMP var, max # Spaces after the commas are fine
+ENDM";
+    let program = ".code\nSTART:\n    TWICE X,MAX\n    TCF START\n.data\nMAX:\n    DEC 5";
+    let image = assemble(&[macros, program]).unwrap();
+    let x = symbol(&image, "X").address;
+    let max = symbol(&image, "MAX").address;
+    assert_eq!(image.fixed[..7], [decode("CS") + x, decode("AD") + max, decode("COM"), 6, decode("BZMF") + FIXED_START + 7, decode("CA") + max, decode("TS") + x]);
+    // Each expansion has its own labels
+    assert_eq!(symbol(&image, "OK__2").address, FIXED_START + 7);
+    assert_eq!(symbol(&image, "OK__3").address, FIXED_START + 14);
+
+    let ast = parse(&format!("{macros}\n{program}")).unwrap();
+    assert_eq!(ast.expansions[0].line, 17);
+    assert_eq!(ast.expansions[0].lines.len(), 16);
+
+    // Errors inside an expansion point at where the macro is used
+    let errors = parse("MACRO BAD\n    FOO A\nENDM\n.code\n    BAD").unwrap_err().errors;
+    assert_eq!((errors[0].kind.clone(), errors[0].span), (ErrorKind::InvalidInstruction("FOO".to_string()), Span::new(5, 5, 3)));
+
+    assert_eq!(parse_error_kind("MACRO M a\nENDM\n.code\n    M"), ErrorKind::MacroArguments("M".to_string(), 1));
+    assert_eq!(parse_error_kind("MACRO M\n    M\nENDM\n.code\n    M"), ErrorKind::MacroTooDeep("M".to_string()));
+    assert_eq!(parse_error_kind("MACRO M\n"), ErrorKind::Unclosed("MACRO M".to_string()));
+    assert_eq!(parse_error_kind("ENDM"), ErrorKind::Unexpected("ENDM".to_string()));
+}
+
+#[test]
+fn test_conditional_assembly() {
+    let source = "\
+.config
+    CONST BOARD 2
+.code
+START:
+IF BOARD = 2
+    CA ONE
+ELSE
+    CA TWO
+    IF MISSING # Not looked at
+    ENDIF
+ENDIF
+IF BOARD-2
+    CA THREE
+ENDIF
+    TCF START";
+    let image = assemble(&[source]).unwrap();
+    assert_eq!(image.fixed.len(), 2);
+    assert!(image.symbols.iter().any(|s| s.name == "ONE"));
+
+    // Constants can come from outside, and from the files before
+    let board = ".code\nA:\nIF BOARD\n    CA ONE\nENDIF\n    TCF A";
+    let options = Options { defines: vec![("BOARD".to_string(), 1)], ..Options::default() };
+    assert_eq!(assemble_with(&[board], &options).unwrap().fixed.len(), 2);
+    assert_eq!(assemble(&[".config\n    BOARD = 0", board]).unwrap().fixed.len(), 1);
+
+    assert_eq!(parse_error_kind("IF NOPE\nENDIF"), ErrorKind::UndefinedConstant("NOPE".to_string()));
+    assert_eq!(parse_error_kind("IF 1"), ErrorKind::Unclosed("IF".to_string()));
+    assert_eq!(parse_error_kind("ELSE"), ErrorKind::Unexpected("ELSE".to_string()));
+}
+
+#[test]
+fn test_structured_control_flow() {
+    let source = "\
+.code
+START:
+    IFZERO X
+    CA ONE
+    ELSE
+    CA TWO
+    ENDIF
+    TCF START
+.data
+X:
+    DEC 0
+ONE:
+    DEC 1
+TWO:
+    DEC 2";
+    let image = assemble(&[source]).unwrap();
+    let (x, then, r#else, end) = (symbol(&image, "X").address, symbol(&image, "THEN__1").address, symbol(&image, "ELSE__1").address, symbol(&image, "ENDIF__1").address);
+    assert_eq!((then, r#else, end), (FIXED_START + 4, FIXED_START + 6, FIXED_START + 7));
+    assert_eq!(image.fixed[..4], [decode("CA") + x, 6, decode("BZF") + then, decode("TCF") + r#else]);
+    assert_eq!(image.fixed[5], decode("TCF") + end);
+    assert_eq!(image.warnings, []);
+
+    // The lines they expand to are listed after them
+    let listing = image.to_listing(&["main".to_string()], &[source]);
+    assert!(listing.contains("                     3     IFZERO X\n  02 4000 34010      +     CA X\n"));
+    assert!(listing.contains("  02 4002 14004      +     BZF THEN__1\n"));
+    assert!(listing.contains("  02 4004            + THEN__1:\n  02 4004 34011      4     CA ONE\n"));
+    assert!(listing.contains("                     5     ELSE\n  02 4005 14007      +     TCF ENDIF__1\n"));
+
+    // Blocks nest, and ELSE and ENDIF go to the innermost block or IF
+    let nested = "\
+.code
+START:
+    FOR I FROM 3 DOWNTO 0
+    WHILE N
+IF 1
+    IFNEG N
+    CA ONE
+    ENDIF
+ENDIF
+    ENDWHILE
+    ENDFOR
+    TCF START";
+    let image = assemble(&[nested]).unwrap();
+    assert!(image.symbols.iter().any(|s| s.name == "ENDFOR__1"));
+    assert!(image.symbols.iter().any(|s| s.name == "ENDWHILE__2"));
+    assert!(image.symbols.iter().any(|s| s.name == "ELSE__3"));
+
+    assert_eq!(parse_error_kind(".code\n    WHILE N\n    ENDIF"), ErrorKind::Unexpected("ENDIF".to_string()));
+    assert_eq!(parse_error_kind(".code\n    ENDWHILE"), ErrorKind::Unexpected("ENDWHILE".to_string()));
+    assert_eq!(parse_error_kind(".code\n    IFZERO X\nIF 1\n    ENDIF"), ErrorKind::Unclosed("IFZERO".to_string()));
+    assert_eq!(parse_error_kind(".code\n    FOR I FROM 3 DOWNTO 1\n    ENDFOR"), ErrorKind::InvalidOperand("I FROM 3 DOWNTO 1".to_string()));
+    assert_eq!(parse_error_kind(".code\n    WHILE\n    ENDWHILE"), ErrorKind::MissingOperand);
+
+    // Blocks in a false branch are skipped whole, their ELSE and ENDIF aren't the IF's
+    let skipped = "\
+.code
+START:
+IF 0
+    IFZERO X
+    CA ONE
+    ELSE
+    WHILE X
+    ENDWHILE
+    ENDIF
+ELSE
+    CA TWO
+ENDIF
+    TCF START
+.data
+TWO:
+    DEC 2";
+    let image = assemble(&[skipped]).unwrap();
+    assert_eq!(image.fixed[..2], [decode("CA") + symbol(&image, "TWO").address, decode("TCF") + FIXED_START]);
+    assert!(!image.symbols.iter().any(|s| s.name.contains("__")));
+
+    // And macros can use them
+    let clamp = "\
+MACRO CLAMP v,max
+    IFNEG v
+    CA max
+    TS v
+    ENDIF
+ENDM
+.code
+START:
+    CLAMP X,TWO
+    CLAMP X,ONE
+    TCF START";
+    let image = assemble(&[clamp]).unwrap();
+    assert!(image.symbols.iter().any(|s| s.name == "ELSE__2"));
+    assert!(image.symbols.iter().any(|s| s.name == "ELSE__4"));
+    assert_eq!(parse_error_kind("MACRO M\n    WHILE X\nENDM\n.code\n    M"), ErrorKind::Unclosed("WHILE".to_string()));
+}
+
+#[test]
+fn test_listing() {
+    let source = "
+MACRO TWICE op
+    op X
+    op X
+ENDM
+.code
+START:
+    CA X # Read it
+    TWICE AD
+    TCF START
+.data
+BIG:
+    2DEC 1
+";
+    let image = assemble(&[source]).unwrap();
+    let listing = image.to_listing(&["a.agc".to_string()], &[source]);
+    let lines: Vec<&str> = listing.lines().collect();
+
+    assert!(lines.contains(&"  02 4000            7 START:"));
+    assert!(lines.contains(&"  02 4000 30422      8     CA X # Read it"));
+    assert!(lines.contains(&"                     9     TWICE AD"));
+    assert!(lines.contains(&"  02 4002 60422      +     AD X"));
+    assert!(lines.contains(&"  02 4004 00000     13     2DEC 1"));
+    assert!(lines.contains(&"  02 4005 00001"));
+    assert!(lines.contains(&"6 words (bank 02: 6)"));
+    assert!(lines.contains(&"bank 02: 6 words used, 1018 free"));
+
+    let reference = image.references.iter().find(|r| r.name == "X").unwrap();
+    assert_eq!(reference.defined, Some((0, Span::new(8, 8, 1))));
+    let used: Vec<usize> = reference.used.iter().map(|&(_, span)| span.line).collect();
+    assert_eq!(used, [8, 9, 9]);
+    assert!(!image.references.iter().any(|r| r.name == "ACC"));
+
+    // Constants that are addresses are in octal too
+    let source = ".config\n    SIZE EQUALS 10\n.code\nSTART:\n    CA LAST\n    TCF START\nLAST = START+SIZE";
+    let listing = assemble(&[source]).unwrap().to_listing(&["a.agc".to_string()], &[source]);
+    assert!(listing.contains("\nLAST          04012  constant"));
+    assert!(listing.contains("\nSIZE             10  constant"));
+}
+
+#[test]
+fn test_named_instructions() {
+    let source = "
+.code
+START:
+    INHINT
+    DOUBLE
+    DDOUBL
+    ZL
+    EXTEND
+    SQUARE
+    EXTEND
+    WRITE 10
+    NOOP
+    DTCB
+    EXTEND
+    INDEX TABLE
+    CA TABLE
+.data
+TABLE:
+    DEC 1
+";
+    let image = assemble(&[source]).unwrap();
+    assert_eq!(image.fixed[..12], [
+        4,
+        decode("AD"),
+        decode("DAS"),
+        decode("LXCH") + 7,
+        6,
+        decode("MP"),
+        6,
+        decode("WRITE") + 10,
+        decode("TCF") + 2057,
+        decode("DXCH") + 5,
+        6,
+        decode("INDEX") + 2061,
+    ]);
+    assert_eq!(decode("DTCB"), 0o52006);
+    assert_eq!(decode("DCOM"), 0o40001);
+
+    assert_eq!(link_error_kinds(&[".code\n    SQUARE"])[0], ErrorKind::ExtendedWithoutExtend("SQUARE".to_string()));
+    assert_eq!(link_error_kinds(&[".code\n    EXTEND\n    ROR 512"])[0], ErrorKind::AddressOutOfRange(512));
+    assert_eq!(link_error_kinds(&[".code\n    EXTEND\n    READ PORT"])[0], ErrorKind::UndefinedLabel("PORT".to_string()));
+    // The basic INDEX only reaches erasable memory
+    assert_eq!(link_error_kinds(&[".code\nA:\n    INDEX A"])[0], ErrorKind::ExpectedErasable("INDEX".to_string()));
+}
+
+#[test]
+fn test_verify() {
+    // Every program decodes back to its source with the emulator's decoder
+    let programs = programs();
+    let sources: Vec<&str> = programs.iter().map(String::as_str).collect();
+    let image = assemble(&sources).unwrap();
+    assert_eq!(image.verify(), []);
+
+    let source = ".code\nSTART:\n    EXTEND\n    DCA X\n    EXTEND\n    DV X\n    RESUME\n    EXTEND\n    ROR 7\n    TCF START";
+    let mut image = assemble(&[source]).unwrap();
+    assert_eq!(image.verify(), []);
+
+    // A word changed after assembling no longer matches
+    image.fixed[1] = decode("MP") + 100;
+    let errors = image.verify();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, ErrorKind::DecodesAs(format!("DCA {RAM_START}"), "MP 100".to_string()));
+    assert_eq!(errors[0].span.line, 4);
+}
+
+#[test]
+fn test_yayul() {
+    let yayul = "
+# Published routines are written like this
+COUNTER         ERASE
+BUFFER          ERASE   +3
+                SETLOC  6000
+                COUNT*  $$/DEMO
+START           CAF     FIVE
+                TS      COUNTER
+LOOP            CA      BUFFER  +2
+                AD      A
+                EXTEND
+                DCA     PAIR
+                TC      NATIVE
+FIVE            DEC     5
+PAIR            2DEC    1.0 B-1
+TEN             =       12
+                CA      TEN
+                CA      10D
+                2CADR   LOOP
+                ECADR   COUNTER
+";
+    let native = ".config\n    EXPORT NATIVE\n    EXTERN LOOP\n.code\nNATIVE:\n    TCF LOOP\n";
+    let options = Options { yayul: vec![0], ..Options::default() };
+    let image = assemble_with(&[yayul, native], &options).unwrap();
+
+    let (counter, buffer) = (symbol(&image, "COUNTER").address, symbol(&image, "BUFFER").address);
+    assert_eq!(symbol(&image, "BUFFER").r#type, SymbolType::VariableTable(4));
+    // Numbers go among the instructions, in the order of the source. The native file starts fixed memory
+    assert_eq!(image.fixed[0], decode("TCF") + 3074);
+    assert_eq!(image.fixed[1024..], [
+        decode("CA") + 3079,
+        decode("TS") + counter,
+        decode("CA") + buffer + 2,
+        decode("AD"),
+        6,
+        decode("DCA") + 3080,
+        decode("TC") + 2048,
+        5,
+        0o20000,
+        0,
+        decode("CA") + 10,
+        decode("CA") + 10,
+        3074,
+        0o6000,
+        counter,
+    ]);
+    assert_eq!(image.counts, [BankCount { name: "$$/DEMO".to_string(), bank: 3, words: 15 }]);
+    assert_eq!(image.verify(), []);
+
+    let errors = parse_yayul("        TCF     +2\n").unwrap_err().errors;
+    assert_eq!((errors[0].kind.clone(), errors[0].span), (ErrorKind::InvalidOperand("+2".to_string()), Span::new(1, 9, 3)));
+}
+
+#[test]
+fn test_objects() {
+    let programs = programs();
+    let sources: Vec<&str> = programs.iter().map(String::as_str).collect();
+    let image = assemble(&sources).unwrap();
+
+    // Linking the objects of every file gives the same rope, with the same listing
+    let asts = parse_files(&sources, &Options::default()).unwrap();
+    let objects: Vec<String> = asts.iter().zip(&sources).map(|(ast, source)| ast.to_object(source)).collect();
+    let linked = link(&objects.iter().map(|object| read_object(object).unwrap()).collect::<Vec<_>>(), &Options::default()).unwrap();
+    assert_eq!((&linked.fixed, &linked.erasable), (&image.fixed, &image.erasable));
+    let shown: Vec<&str> = objects.iter().map(|object| object_source(object)).collect();
+    let paths = vec![String::new(); sources.len()];
+    assert_eq!(shown, sources);
+    assert_eq!(linked.to_listing(&paths, &shown), image.to_listing(&paths, &sources));
+
+    // Objects and sources can be given together
+    let mut mixed = sources.clone();
+    mixed[1] = &objects[1];
+    let options = Options { objects: vec![1], ..Options::default() };
+    assert_eq!(assemble_with(&mixed, &options).unwrap().fixed, image.fixed);
+
+    // The files after an object can use its macros and constants
+    let library = ".config\n    CONST MAX 3\nMACRO CLEAR var\n    CA ZERO\n    TS var\nENDM\n.code\nLIB:\n    TCF LIB";
+    let main = ".code\nSTART:\nIF MAX = 3\n    CLEAR X\nENDIF\n    TCF START";
+    let object = parse(library).unwrap().to_object(library);
+    let options = Options { objects: vec![0], ..Options::default() };
+    assert_eq!(assemble_with(&[&object, main], &options).unwrap().fixed, assemble(&[library, main]).unwrap().fixed);
+
+    let damaged = objects[1].replacen("LABEL BLINK code 0", "LABEL BLINK code", 1);
+    let error = read_object(&damaged).unwrap_err();
+    assert_eq!((error.kind, error.span.line), (ErrorKind::InvalidObject, 2));
+    let cut = &objects[1][..objects[1].find("SOURCE").unwrap()];
+    assert_eq!(read_object(cut).unwrap_err().kind, ErrorKind::InvalidObject);
+    assert_eq!(read_object(sources[1]).unwrap_err().kind, ErrorKind::InvalidObject);
+}
+
+#[test]
+fn test_output_formats() {
+    let source = ".code\nSTART:\n    CA X\n    TCF START\n.data\nX:\n    DEC 5\n";
+    let far = ".code\n    BANK 4\nFAR:\n    TCF FAR\n";
+    let image = assemble(&[source, far]).unwrap();
+    assert_eq!(image.banks, [(2, 3), (4, 1)]);
+    assert_eq!(image.last_bank(), 4);
+    assert_eq!(image.to_header(), format!(
+        "AGC rope, banks 02 to 04, checksum {:08x}\nbank 02: 3 words used, 1021 free\nbank 04: 1 words used, 1023 free\nerasable: 0422 to 0422 used\n",
+        image.checksum(),
+    ));
+    assert!(image.to_rust_fixed().starts_with(&format!("// AGC rope, banks 02 to 04, checksum {:08x}\n", image.checksum())));
+
+    // yaYUL's banks go 2, 3, 0, 1, 4
+    let bin = image.to_bin();
+    assert_eq!(bin.len(), 5 * 2048);
+    assert_eq!(bin[..4], [(decode("CA") + 2050) << 1, (decode("TCF") + 2048) << 1].map(u16::to_be_bytes).concat());
+    assert_eq!(bin[4 * 2048..4 * 2048 + 2], ((decode("TCF") + 1024) << 1).to_be_bytes());
+
+    let pico = image.to_pico();
+    assert_eq!(pico[..8], *b"AGCROPE\0");
+    assert_eq!(pico[24..28], image.checksum().to_le_bytes());
+    assert_eq!(pico[92..96], image.erasable_checksum().to_le_bytes());
+    assert_eq!(pico[256..258], (decode("CA") + 2050).to_le_bytes());
+    assert_eq!(pico.len(), 256 + 3 * 2048 + 464 * 2);
+
+    let hex = image.to_hex();
+    assert!(hex.starts_with(":02000004101FCB\n:10000000"));
+    assert!(hex.ends_with(":00000001FF\n"));
+    let uf2 = image.to_uf2();
+    assert_eq!(uf2.len(), pico.len().div_ceil(256) * 512);
+    assert_eq!(uf2[32..32 + 256], pico[..256]);
+
+    let json = image.to_json(&["main.agc".to_string(), "far.agc".to_string()]);
+    assert!(json.contains(&format!("\"checksum\": \"{:08x}\"", image.checksum())));
+    assert!(json.contains("{\"name\": \"FAR\", \"type\": \"label\", \"len\": 1, \"address\": 4096, \"file\": \"far.agc\", \"exported\": false}"));
+    assert!(json.contains("{\"file\": \"main.agc\", \"line\": 3, \"expanded\": null, \"address\": 2048, \"words\": [14338]}"));
+}
+
+#[test]
+fn test_bugger_words_and_parity() {
+    let source = ".code\nSTART:\n    CA X\n    TCF START\n.data\nX:\n    DEC -5000\n";
+    let far = ".code\n    BANK 4\nFAR:\n    TCF FAR\n";
+    let options = Options { bugger_words: true, ..Options::default() };
+    let image = assemble_with(&[source, far], &options).unwrap();
+
+    // Each bank adds up to plus or minus its number with the word after its last one
+    let sum = agc_emulator::memory::bank_sum(image.fixed[..3].iter().copied());
+    assert_eq!(sum & 0o40000, 0o40000);
+    assert_eq!(image.buggers, [(2051, agc_emulator::memory::bank_sum([!2 & 0o77777, !sum & 0o77777])), (4097, 4 + (0o77777 - (decode("TCF") + 1024)))]);
+    assert_eq!(image.fixed[3], image.buggers[0].1);
+    assert_eq!(image.banks, [(2, 4), (4, 2)]);
+    assert_eq!(image.check_banks(), []);
+    let listing = image.to_listing(&["main".to_string(), "far".to_string()], &[source, far]);
+    assert!(listing.contains(&format!("bank 02: 4 words used, 1020 free\n    bugger word at 4003: {:05o}\n", image.buggers[0].1)));
+
+    // A full bank has no room for it
+    let full = ".data\n    BANK 4\n".to_string() + &"    DEC 1\n".repeat(1024);
+    let errors = assemble_with(&[source, &full], &options).unwrap_err().errors;
+    assert_eq!((errors[0].kind.clone(), errors[0].file, errors[0].span.line), (ErrorKind::NoRoomForBugger(4), Some(1), 1026));
+
+    // Every word gets an odd number of ones, unused ones too
+    let options = Options { bugger_words: true, parity: true, ..Options::default() };
+    let image = assemble_with(&[source, far], &options).unwrap();
+    assert!(image.banked().iter().all(|word| word.count_ones() % 2 == 1));
+    assert_eq!(image.fixed[0] & 0o77777, decode("CA") + 2050);
+    assert_eq!(image.check_banks(), []);
+    assert_eq!(image.verify(), []);
+    let bin = image.to_bin();
+    assert_eq!(u16::from_be_bytes([bin[0], bin[1]]), (image.fixed[0] & 0o77777) << 1 | 1);
+    assert!(image.to_octal().starts_with(&format!("{:06o}\n", image.fixed[0])));
+}
+
+#[test]
+fn test_literals() {
+    let source = "
+.code
+START:
+    CA =255
+    AD =-1
+    MASK =0xFF
+    TCF START
+    BANK 4
+FAR:
+    CA =3
+    TCF FAR
+";
+    let image = assemble(&[source]).unwrap();
+
+    // Each stretch of code ends with its own pool, each word once
+    assert_eq!(image.fixed[..6], [decode("CA") + 2052, decode("AD") + 2053, decode("MASK") + 2052, decode("TCF") + 2048, 255, 0o77776]);
+    assert_eq!(image.fixed[2048..], [decode("CA") + 1026, decode("TCF") + 1024, 3]);
+    assert_eq!(image.warnings, []);
+    assert_eq!(image.verify(), []);
+    let listing = image.to_listing(&["main".to_string()], &[source]);
+    assert!(listing.contains("                     8     BANK 4\n  02 4004 00377      + =255\n  02 4005 77776      + =-1\n"));
+    assert!(listing.contains("\n  04 2002 00003      + =3\n"));
+    assert!(listing.contains("\n=255          04004  label            main:4                   main:4, main:6\n"));
+
+    // Objects keep them
+    let ast = parse(source).unwrap();
+    let object = read_object(&ast.to_object(source)).unwrap();
+    assert_eq!(link(&[object], &Options::default()).unwrap().fixed, image.fixed);
+
+    let error = |line: &str| parse_error_kind(&format!(".code\n{line}\n"));
+    assert_eq!(error("    TS =1"), ErrorKind::LiteralOperand("TS".to_string()));
+    assert_eq!(error("    EXTEND\n    DCA =1"), ErrorKind::LiteralOperand("DCA".to_string()));
+    assert_eq!(error("    CA =70000"), ErrorKind::NumberOutOfRange("70000".to_string()));
+}
@@ -1,3 +1,6 @@
+use crate::error::{Span, Warning};
+use crate::expr::Expr;
+use crate::macros::Macro;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum SymbolType {
@@ -6,141 +9,307 @@ pub enum SymbolType {
     LabelTable(u16),
     VariableTable(u16),
 }
+impl SymbolType {
+    // Words of memory the symbol takes
+    pub fn words(self) -> u16 {
+        match self {
+            SymbolType::Label | SymbolType::Variable => 1,
+            SymbolType::LabelTable(len) | SymbolType::VariableTable(len) => len,
+        }
+    }
+}
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
 pub enum Section {
+    #[default]
     None,
     Config,
+    Erasable,
     Code,
     Data,
 }
-impl From<&str> for Section {
-    fn from(value: &str) -> Self {
-        match value {
-            "config" => Section::Config,
-            "code" => Section::Code,
-            "data" => Section::Data,
-            _ => panic!("Invalid section (thrown from conversion)")
+impl Section {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "config" => Some(Section::Config),
+            "erasable" | "vars" => Some(Section::Erasable),
+            "code" => Some(Section::Code),
+            "data" => Some(Section::Data),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug, Eq)]
-pub struct UndefinedLabel<'a> {
-    pub name: &'a str,
+#[derive(Debug, Clone, Eq)]
+pub struct UndefinedLabel {
+    pub name: String,
     pub section: Section,
     pub offset: u16,
+    pub span: Span,
 }
-impl<'a> PartialEq for UndefinedLabel<'a> {
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
-    }
-    
+impl PartialEq for UndefinedLabel {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
     }
 }
 
-impl<'a> UndefinedLabel<'a> {
-    pub fn new(name: &'a str, section: Section, offset: u16) -> Self {
-        Self{name, section, offset}
+impl UndefinedLabel {
+    pub fn new(name: &str, section: Section, offset: u16, span: Span) -> Self {
+        Self{name: name.to_string(), section, offset, span}
     }
 
-    pub fn define(&self, section_start: u16, len: u16) -> DefinedSymbol<'a> {
+    pub fn define(&self, address: u16, len: u16) -> DefinedSymbol {
         if len == 0 {
-            DefinedSymbol::new(self.name, SymbolType::Label, section_start + self.offset)
+            DefinedSymbol::new(&self.name, SymbolType::Label, address)
         } else {
-            DefinedSymbol::new(self.name, SymbolType::LabelTable(len), section_start + self.offset)
+            DefinedSymbol::new(&self.name, SymbolType::LabelTable(len), address)
         }
     }
 }
 
-#[derive(Debug)]
-pub struct UndefinedSymbol<'a> {
-    pub name: &'a str,
+// Operand of an instruction. The type is the kind of memory the instruction works on, if it's restricted
+#[derive(Debug, Clone)]
+pub struct UndefinedSymbol {
+    pub expr: Expr,
     pub r#type: Option<SymbolType>,
+    pub span: Span,
 }
-impl<'a> UndefinedSymbol<'a> {
-    pub const fn new (name: &'a str, r#type: Option<SymbolType>) -> Self {
-        Self {name, r#type}
-    }
-
-    pub fn define(&self, value: u16, len: u16) -> DefinedSymbol<'a> {
-        if len == 0 {
-            DefinedSymbol::new(self.name, self.r#type.unwrap_or(SymbolType::Variable), value)
-        } else {
-            if self.r#type == Some(SymbolType::Variable) {
-                DefinedSymbol::new(self.name, SymbolType::VariableTable(len), value)
-            } else {
-                DefinedSymbol::new(self.name, SymbolType::Label, value)
-            }
-            
-        }
+impl UndefinedSymbol {
+    pub fn new(expr: Expr, r#type: Option<SymbolType>, span: Span) -> Self {
+        Self {expr, r#type, span}
     }
 }
 
-#[derive(Debug, Eq)]
-pub struct DefinedSymbol<'a> {
-    pub name: &'a str,
+#[derive(Debug, Clone, Eq)]
+pub struct DefinedSymbol {
+    pub name: String,
     pub r#type: SymbolType,
     pub address: u16,
+    // File of a label, which is the only one that sees it unless it's exported. Variables and registers
+    // are seen by every file
+    pub file: Option<usize>,
+    pub exported: bool,
 }
-impl<'a> PartialEq for DefinedSymbol<'a> {
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
-    }
-    
+impl PartialEq for DefinedSymbol {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
     }
 }
-impl<'a> DefinedSymbol<'a> {
-    pub const fn new (name: &'a str, r#type: SymbolType, address: u16) -> Self {
-        Self {name, r#type, address}
+impl DefinedSymbol {
+    pub fn new(name: &str, r#type: SymbolType, address: u16) -> Self {
+        Self {name: name.to_string(), r#type, address, file: None, exported: false}
     }
-}
 
-#[derive(Debug)]
-pub struct Instruction<'a> {
-    pub operation: &'a str,
-    pub operand: UndefinedSymbol<'a>,
-}
-impl<'a> Instruction<'a> {
-    pub fn new(operation: &'a str, operand: UndefinedSymbol<'a>) -> Self {
-        Self {operation, operand}
+    pub fn from_file(self, file: usize, exported: bool) -> Self {
+        Self {file: Some(file), exported, ..self}
     }
 }
 
-#[derive(Debug)]
-pub struct FileContent<'a> {
-    pub labels: Vec<UndefinedLabel<'a>>,
-    pub code: Vec<Instruction<'a>>,
-    pub data: Vec<u16>
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub operation: String,
+    pub operand: UndefinedSymbol,
+    // Span of the operation
+    pub span: Span,
+    // Whether it comes after EXTEND, and runs as an extracode
+    pub extended: bool,
 }
-
-impl FileContent<'_> {
-    pub const fn new() -> Self {
-        Self{labels: vec![],code: vec![],data: vec![]}
+impl Instruction {
+    pub fn new(operation: &str, operand: UndefinedSymbol, span: Span, extended: bool) -> Self {
+        Self {operation: operation.to_string(), operand, span, extended}
     }
 }
 
-#[derive(Debug)]
-pub struct UndefinedTable<'a> {
-    pub name: &'a str,
+#[derive(Debug, Clone, Eq)]
+pub struct UndefinedTable {
+    pub name: String,
     pub len: u16,
 }
-
-impl<'a> UndefinedTable<'a> {
-    pub fn new(name: &'a str, len: u16) -> Self {
-        UndefinedTable {name, len}
+impl UndefinedTable {
+    pub fn new(name: &str, len: u16) -> Self {
+        UndefinedTable {name: name.to_string(), len}
     }
 }
-impl<'a> PartialEq for UndefinedTable<'a> {
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
-    }
-    
+impl PartialEq for UndefinedTable {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
     }
-}
\ No newline at end of file
+}
+
+// Symbolic constant, `CONST WIDTH 8` in the config section, or `NAME EQUALS expr` (`NAME = expr`) anywhere.
+// Constants made with EQUALS can also be addresses, other names for a symbol
+#[derive(Debug, Clone)]
+pub struct Constant {
+    pub name: String,
+    pub expr: Expr,
+    pub equals: bool,
+    pub span: Span,
+}
+
+// Where the words after a SETLOC, BANK or BLOCK go
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Place {
+    Address(u16),
+    // After whatever else is in the bank
+    Bank(u16),
+}
+
+// The words of the section from `offset` on go to `place`, until the next placement
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub section: Section,
+    pub offset: u16,
+    pub place: Place,
+    pub span: Span,
+}
+
+// `COUNT name`: the words of the section from `offset` on, until the next COUNT, are accounted to `name`
+#[derive(Debug, Clone)]
+pub struct Count {
+    pub name: String,
+    pub section: Section,
+    pub offset: u16,
+}
+
+// `NAME ERASE n` in the config section, or a declaration in the erasable section, reserves n erasable words,
+// at `address` if it came after a SETLOC. They start with `init`, or with zeros if it's empty
+#[derive(Debug, Clone)]
+pub struct Erase {
+    pub name: Option<String>,
+    pub len: u16,
+    pub address: Option<u16>,
+    pub init: Vec<u16>,
+    pub span: Span,
+}
+
+// Lines a macro turned into, for the listing
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    // Line where the macro is used
+    pub line: usize,
+    pub lines: Vec<String>,
+}
+
+// Words of the section a line of source turned into, for the listing. Lines of macro expansions are all
+// kept, with their text, even when they don't make words
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub line: usize,
+    pub expanded: Option<String>,
+    pub section: Section,
+    pub offset: u16,
+    pub len: u16,
+}
+
+// Everything a source file declares, before addresses are given out
+#[derive(Debug, Clone, Default)]
+pub struct Ast {
+    pub tables: Vec<UndefinedTable>,
+    pub constants: Vec<Constant>,
+    pub erased: Vec<Erase>,
+    pub placements: Vec<Placement>,
+    pub counts: Vec<Count>,
+    pub labels: Vec<UndefinedLabel>,
+    // Labels other files can use, and labels of other files this one uses
+    pub exports: Vec<(String, Span)>,
+    pub externs: Vec<(String, Span)>,
+    // Files in yaYUL's syntax don't declare them, they see every exported label
+    pub imports_all: bool,
+    pub code: Vec<Instruction>,
+    pub data: Vec<u16>,
+    pub expansions: Vec<Expansion>,
+    pub lines: Vec<SourceLine>,
+    pub warnings: Vec<Warning>,
+    // Macros defined in the file, for the files after it
+    pub macros: Vec<Macro>,
+}
+
+impl Ast {
+    // Whether the file uses the label `name` of another file
+    pub fn imports(&self, name: &str) -> bool {
+        self.imports_all || self.externs.iter().any(|(n, _)| n == name)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    // Operands that aren't declared are errors instead of new variables
+    pub strict: bool,
+    // Constants given from outside the sources, like the board to build for
+    pub defines: Vec<(String, i64)>,
+    // Position of the files written in yaYUL's syntax
+    pub yayul: Vec<usize>,
+    // Position of the files that are objects, made by `Ast::to_object`
+    pub objects: Vec<usize>,
+    // Every bank with words gets a bugger word, and fixed words carry their odd parity bit in bit 16, like
+    // a real rope
+    pub bugger_words: bool,
+    pub parity: bool,
+}
+
+// Assembled fixed memory, from FIXED_START up to the last bank used, and the address of every symbol
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub fixed: Vec<u16>,
+    pub symbols: Vec<DefinedSymbol>,
+    // First erasable address that wasn't given to a variable
+    pub erasable_end: u16,
+    // Initial value of the erasable words that don't start as zero
+    pub erasable: Vec<(u16, u16)>,
+    // Words used in each bank that has any, and where its bugger word is and what it is
+    pub banks: Vec<(u16, u16)>,
+    pub buggers: Vec<(u16, u16)>,
+    // Whether the fixed words have their parity bit
+    pub parity: bool,
+    // Words accounted to each COUNT name, in each bank
+    pub counts: Vec<BankCount>,
+    // Warnings of every file, from parsing and from linking
+    pub warnings: Vec<Warning>,
+    // What the listing shows: the words of each line, and where every symbol is defined and used
+    pub listing: Vec<ListedLine>,
+    pub references: Vec<Reference>,
+    // Every instruction with the operand it was given, to check the words decode back to them
+    pub instructions: Vec<Assembled>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembled {
+    pub file: usize,
+    pub span: Span,
+    pub operation: String,
+    pub operand: u16,
+    pub address: u16,
+    pub extended: bool,
+}
+
+// A line of source and the words it put in fixed memory from `address` on. Lines out of fixed memory have
+// no address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedLine {
+    pub file: usize,
+    pub line: usize,
+    pub expanded: Option<String>,
+    pub address: Option<u16>,
+    pub words: Vec<u16>,
+}
+
+// A symbol, its value, and the file and place it's defined and used in. Registers and constants from
+// outside the sources aren't defined anywhere, and variables are defined where they're first used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub name: String,
+    // None for constants
+    pub r#type: Option<SymbolType>,
+    pub value: i64,
+    // Whether the value is an address, as for every symbol and for constants like `LAST = TABLE+3`
+    pub address: bool,
+    pub defined: Option<(usize, Span)>,
+    pub used: Vec<(usize, Span)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BankCount {
+    pub name: String,
+    pub bank: u16,
+    pub words: u16,
+}
@@ -0,0 +1,85 @@
+use crate::constants::*;
+use crate::error::*;
+use crate::types::*;
+use agc_emulator::memory::{bank_sum, bank_sum_ok};
+use agc_emulator::predecode::{predecode, Op};
+
+impl Image {
+    // Disassembles every instruction with the emulator's decoder and checks it's the one of the source, on
+    // the same operand. Returns an error for each one that isn't
+    pub fn verify(&self) -> Vec<Error> {
+        let mut errors = vec![];
+        for instruction in &self.instructions {
+            let Some(&word) = self.fixed.get((instruction.address - FIXED_START) as usize) else {
+                continue;
+            };
+            let slot = predecode(word);
+            let op = if instruction.extended { slot.extended } else { slot.basic };
+
+            let expected = (instruction.operation.as_str(), instruction.operand);
+            let matches = match disassemble(op) {
+                Some(found) => found == expected,
+                // The emulator doesn't run these yet, but it does know the words
+                None => matches!(expected, ("DV", _) | ("EDRUPT", _) | ("INDEX", 0o17)),
+            };
+            if !matches {
+                let found = disassemble(op).map_or(format!("{word:05o}"), |(name, operand)| format!("{name} {operand}"));
+                let kind = ErrorKind::DecodesAs(format!("{} {}", expected.0, expected.1), found);
+                errors.push(Error::new(kind, instruction.span).in_file(instruction.file));
+            }
+        }
+        errors
+    }
+
+    // Banks that don't add up to plus or minus their number, with what they add up to
+    pub fn check_banks(&self) -> Vec<(u16, u16)> {
+        let words = self.banked();
+        let banks = words.chunks(BANK_SIZE as usize).zip(FIXED_START / BANK_SIZE..);
+        let sums = banks.map(|(words, bank)| (bank, bank_sum(words.iter().copied())));
+        sums.filter(|&(bank, sum)| self.banks.iter().any(|&(b, _)| b == bank) && !bank_sum_ok(bank, sum)).collect()
+    }
+}
+
+// Mnemonic and operand of the instruction, the way the source would have it. Double precision instructions
+// keep the address of the second word, the source has the first one
+fn disassemble(op: Op) -> Option<(&'static str, u16)> {
+    Some(match op {
+        Op::Tc(k) => ("TC", k),
+        Op::Return => ("TC", 2),
+        Op::Relint => ("TC", 3),
+        Op::Inhint => ("TC", 4),
+        Op::Extend => ("TC", 6),
+        Op::Ccs(k) => ("CCS", k),
+        Op::Tcf(k) => ("TCF", k),
+        Op::Das(k) => ("DAS", k.wrapping_sub(1)),
+        Op::Lxch(k) => ("LXCH", k),
+        Op::Incr(k) => ("INCR", k),
+        Op::Ads(k) => ("ADS", k),
+        Op::Ca(k) => ("CA", k),
+        Op::Cs(k) => ("CS", k),
+        Op::Index(k) | Op::IndexExtended(k) => ("INDEX", k),
+        Op::Dxch(k) => ("DXCH", k.wrapping_sub(1)),
+        Op::Ts(k) => ("TS", k),
+        Op::Xch(k) => ("XCH", k),
+        Op::Ad(k) => ("AD", k),
+        Op::Mask(k) => ("MASK", k),
+        Op::Bzf(k) => ("BZF", k),
+        Op::Msu(k) => ("MSU", k),
+        Op::Qxch(k) => ("QXCH", k),
+        Op::Aug(k) => ("AUG", k),
+        Op::Dim(k) => ("DIM", k),
+        Op::Dca(k) => ("DCA", k.wrapping_sub(1)),
+        Op::Dcs(k) => ("DCS", k.wrapping_sub(1)),
+        Op::Su(k) => ("SU", k),
+        Op::Bzmf(k) => ("BZMF", k),
+        Op::Mp(k) => ("MP", k),
+        Op::Read(k) => ("READ", k),
+        Op::Write(k) => ("WRITE", k),
+        Op::Rand(k) => ("RAND", k),
+        Op::Wand(k) => ("WAND", k),
+        Op::Ror(k) => ("ROR", k),
+        Op::Wor(k) => ("WOR", k),
+        Op::Rxor(k) => ("RXOR", k),
+        Op::Unimplemented => return None,
+    })
+}
@@ -0,0 +1,150 @@
+use crate::constants::*;
+use crate::error::*;
+use crate::numbers::parse_integer;
+use crate::parse::{finish, list, parse_line, State};
+use crate::types::*;
+
+// Operations yaYUL has other names for
+const ALIASES: [(&str, &str); 5] = [
+    ("CAF", "CA"),
+    ("CAE", "CA"),
+    ("NDX", "INDEX"),
+    ("TCR", "TC"),
+    ("MSK", "MASK"),
+];
+
+// Erasable and superbank settings, there are no switched banks to use them with
+const IGNORED: [&str; 2] = ["EBANK=", "SBANK="];
+
+// Reads a source file in yaYUL's syntax: a label starts in the first column, the operation and its operand
+// come after it, and everything from '#' on is a comment. Numbers in operands are octal unless they end
+// with D. It becomes the same Ast a file in our syntax would. yaYUL has no modules, so every label is
+// exported and every exported label can be used
+pub fn parse_yayul(source: &str) -> Result<Ast, Diagnostics> {
+    let mut ast = Ast { imports_all: true, ..Ast::default() };
+    let mut errors = vec![];
+    let mut state = State::default();
+
+    for (index, text) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let code = text.split('#').next().unwrap_or("");
+        let label = code.split_whitespace().next().filter(|_| !code.starts_with(char::is_whitespace));
+        let mut tokens = code.split_whitespace().skip(label.is_some() as usize);
+        let operation = tokens.next();
+
+        // Everything points at the operation, or at the label when there's none
+        let Some(first) = operation.or(label) else {
+            continue;
+        };
+        let origin = Span::of(first, text, line_number);
+        let lines = match translate(&mut state, label, operation, tokens.collect()) {
+            Ok(lines) => lines,
+            Err(kind) => {
+                errors.push(Error::new(kind, origin));
+                continue;
+            }
+        };
+        let before = (ast.code.len(), ast.data.len(), ast.labels.len());
+        for line in &lines {
+            // The line of a label points at it
+            let origin = match label {
+                Some(label) if line.strip_suffix(':') == Some(label) => Span::of(label, text, line_number),
+                _ => origin,
+            };
+            if let Err(error) = parse_line(&mut ast, &mut state, line, line_number, Some(origin)) {
+                errors.push(error);
+            }
+        }
+        list(&mut ast, state.section, line_number, None, before);
+    }
+
+    ast.exports = ast.labels.iter().map(|label| (label.name.clone(), label.span)).collect();
+    finish(ast, &mut state, errors)
+}
+
+// Lines in our syntax that do what the yaYUL line does. Erasable memory is given out in the erasable
+// section, and everything else goes in the code, numbers too, so the words stay in the order of the source
+fn translate(state: &mut State, label: Option<&str>, operation: Option<&str>, operand: Vec<&str>) -> Result<Vec<String>, ErrorKind> {
+    let Some(operation) = operation else {
+        state.section = Section::Code;
+        return Ok(vec![format!("{}:", label.unwrap_or(""))]);
+    };
+    let operation = ALIASES.iter().find(|(alias, _)| *alias == operation).map_or(operation, |&(_, name)| name);
+    let mut lines = vec![];
+
+    match operation {
+        _ if IGNORED.contains(&operation) => {}
+        "=" | "EQUALS" => {
+            let label = label.ok_or(ErrorKind::MissingName)?;
+            if state.section == Section::None {
+                state.section = Section::Config;
+            }
+            lines.push(format!("{label} = {}", expression(&operand)?));
+        }
+        // `ERASE +n` takes n words more than the first one
+        "ERASE" => {
+            state.section = Section::Erasable;
+            let len = match operand.concat().as_str() {
+                "" => 1,
+                more => octal(more.trim_start_matches('+'))? + 1,
+            };
+            lines.push(format!("{} ERASE {len}", label.unwrap_or("")));
+        }
+        // SETLOC goes to erasable or fixed memory, depending on the address
+        "SETLOC" => {
+            let address = octal(&operand.concat())?;
+            state.section = if address < BANK_SIZE as u64 { Section::Erasable } else { Section::Code };
+            lines.push(format!("SETLOC {address}"));
+        }
+        "BANK" | "BLOCK" => {
+            state.section = Section::Code;
+            lines.push(format!("{operation} {}", octal(&operand.concat())?));
+        }
+        "COUNT" | "COUNT*" => {
+            state.section = Section::Code;
+            lines.push(format!("{operation} {}", operand.concat()));
+        }
+        _ => {
+            state.section = Section::Code;
+            if let Some(label) = label {
+                lines.push(format!("{label}:"));
+            }
+            let number = matches!(operation, "DEC" | "2DEC" | "OCT" | "2OCT");
+            let operand = if number { operand.join(" ") } else { expression(&operand)? };
+            lines.push(format!("{operation} {operand}"));
+        }
+    }
+    Ok(lines)
+}
+
+fn octal(text: &str) -> Result<u64, ErrorKind> {
+    match parse_integer(&format!("0o{text}")) {
+        Some((false, n)) => Ok(n),
+        _ => Err(ErrorKind::InvalidNumber(text.to_string())),
+    }
+}
+
+// The operand in our syntax. yaYUL allows spaces in it, `FOO +1`, and writes numbers in octal, or in decimal
+// when they end with D. A is the accumulator. An operand that's only a number with a sign is relative to
+// where the instruction is, which isn't supported
+fn expression(tokens: &[&str]) -> Result<String, ErrorKind> {
+    let text = tokens.concat();
+    if text.starts_with(['+', '-']) && text[1..].chars().all(|c| c.is_ascii_digit() || c == 'D') {
+        return Err(ErrorKind::InvalidOperand(text));
+    }
+
+    let mut out = String::new();
+    for term in text.split_inclusive(['+', '-', '(', ')']) {
+        let (term, separator) = term.split_at(term.trim_end_matches(['+', '-', '(', ')']).len());
+        match term {
+            "A" => out.push_str("ACC"),
+            _ if !term.is_empty() && term.chars().all(|c| c.is_ascii_digit()) => out.push_str(&format!("0o{term}")),
+            _ => match term.strip_suffix('D').filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) {
+                Some(decimal) => out.push_str(decimal),
+                None => out.push_str(term),
+            },
+        }
+        out.push_str(separator);
+    }
+    Ok(out)
+}
@@ -1,10 +1,28 @@
 use agc_emulator as emu;
 
+use emu::board::*;
+use emu::faults::*;
 use emu::instructions::*;
 use emu::memory::*;
+use emu::pacing::*;
+use emu::restart::*;
+use emu::standby::*;
 
 use text_io::read;
 use core::ops::Deref;
+use std::time::{Duration, Instant};
+
+// Host clock for the pacer
+struct StdClock(Instant);
+impl Clock for StdClock {
+    fn now_us(&mut self) -> u64 {
+        self.0.elapsed().as_micros() as u64
+    }
+
+    fn sleep_us(&mut self, us: u64) {
+        std::thread::sleep(Duration::from_micros(us));
+    }
+}
 
 enum Command {
     ACC,
@@ -12,6 +30,12 @@ enum Command {
     MEM(ErasableAddress, ErasableAddress),
     RUN(u16),
     SHOW,
+    SPEED(Option<Speed>),
+    RESTART,
+    FRESH,
+    PRO,
+    PARITY,
+    FAULT(Fault),
     FAIL,   
     EXIT, 
 }
@@ -25,21 +49,42 @@ fn get_command() -> Command {
         "z" => return Command::Z,
         "run" => return Command::RUN(iter.next().unwrap_or("1").parse().unwrap()),
         "show" => return Command::SHOW,
+        // speed [real | max | <percent of the real speed>]
+        "speed" => return Command::SPEED(match iter.next() {
+            Some("real") => Some(Speed::Authentic),
+            Some("max") => Some(Speed::Unthrottled),
+            Some(n) => n.parse().ok().map(Speed::Scaled),
+            None => None,
+        }),
         "mem" => {
             let arg1 = iter.next();
             let arg2 = iter.next();
 
             if let Some(arg2) = arg2 {
-                let min = arg1.unwrap_or("256");
-                let min = min.parse().unwrap_or(256);
-                let max = arg2.parse().unwrap_or(511);
+                let min = arg1.and_then(|min| min.parse().ok()).unwrap_or(PERIPHERALS);
+                let max = arg2.parse().unwrap_or(ERASABLE_END - 1);
                 return Command::MEM(min, max);
             } else {
-                let max = arg1.unwrap_or("511");
-                let max = max.parse().unwrap_or(511);
-                return Command::MEM(256, max);
+                let max = arg1.and_then(|max| max.parse().ok()).unwrap_or(ERASABLE_END - 1);
+                return Command::MEM(PERIPHERALS, max);
             }
         },
+        "restart" => return Command::RESTART,
+        "fresh" => return Command::FRESH,
+        "pro" => return Command::PRO,
+        "parity" => return Command::PARITY,
+        // flip <address> <bit 1-16> | stick <address> <value> | drop <counter> <count>
+        kind @ ("flip" | "stick" | "drop") => {
+            let (Some(Ok(arg1)), Some(Ok(arg2))) = (iter.next().map(str::parse), iter.next().map(str::parse)) else {
+                return Command::FAIL;
+            };
+            return Command::FAULT(match kind {
+                "flip" if (1..=16).contains(&arg2) => Fault::FlipBit { address: arg1, bit: arg2 as u8 },
+                "stick" => Fault::Stuck { address: arg1, value: arg2 },
+                "drop" => Fault::DropIncrements { counter: arg1, count: arg2 },
+                _ => return Command::FAIL,
+            });
+        }
         "exit" => return Command::EXIT,
         _ => return Command::FAIL,
     }
@@ -49,18 +94,21 @@ fn main() {
     let col = 6;
     let mut show:bool = true;
     let mut cycles_executed = 0;
+    let mut pacer = Pacer::new(StdClock(Instant::now()), Speed::Authentic);
     MEMORY.write(271, 0);
     MEMORY.write(272, 0);
     MEMORY.write(273, 3);
+    power_on();
     loop {
         let command = get_command();
         match command {
             Command::ACC => println!("{}", MEMORY.read(ACC)),
             Command::Z => println!("{}", MEMORY.read(Z)),
             Command::RUN(cycles) => {
+                pacer.restart(); // Don't make up for the time spent waiting for the command
                 for n in 0..cycles {
                     let Instruction(ins, addr) = decode(MEMORY.read(MEMORY.read(Z)));
-                    execute(MEMORY.read(MEMORY.read(Z)));
+                    pacer.pace(execute(MEMORY.read(MEMORY.read(Z))));
                     cycles_executed += 1;
                     if show {
                         let name = MEMORY.get_address_name(addr.unwrap_or(513));
@@ -72,12 +120,23 @@ fn main() {
                     }
                 }
                 if show && (cycles-1) % col != col-1 {println!()} //Only adds newline if the loop didn't end in one already
+                if in_standby() {
+                    println!("STANDBY");
+                }
+                if restart_light() {
+                    println!("RESTART {:?}, channel 77: {:o}", last_restart(), MEMORY.read_channel(RESTART_CHANNEL));
+                    clear_restart_light();
+                }
             },
             Command::SHOW => show = !show,
+            Command::SPEED(speed) => match speed {
+                Some(speed) => pacer.set_speed(speed),
+                None => println!("{:?}", pacer.speed()),
+            },
             Command::MEM(mut min, mut max) => {
-                if min < 256 {min = 256}
-                if max > 511 {max = 511}
-                if max < min {max = 256}
+                if min < PERIPHERALS {min = PERIPHERALS}
+                if max > ERASABLE_END - 1 {max = ERASABLE_END - 1}
+                if max < min {max = PERIPHERALS}
                 for addr in min..=max {
                     if (addr-min) % col == col-1 {
                         println!("{:<3}: {:<10}", addr, (MEMORY.read(addr)));
@@ -87,6 +146,29 @@ fn main() {
                 }
                 if (max-min) % col != col-1 {println!()} //Only adds newline if the loop didn't end in one already
             }
+            Command::RESTART => gojam(Restart::Manual),
+            Command::FRESH => {
+                fresh_start();
+                MEMORY.write(CORTO, 0);
+                MEMORY.write(MEDIO, 0);
+                MEMORY.write(LARGO, 3);
+            }
+            // Toggles the PRO key between held and released
+            Command::PRO => {
+                press_pro(!pro_held());
+                println!("PRO {}", if pro_held() {"held"} else {"released"});
+            }
+            // Toggles parity checking, with the parity alarms enabled while it's on
+            Command::PARITY => {
+                let on = !MEMORY.parity();
+                MEMORY.set_parity(if on {Parity::Generated} else {Parity::Off});
+                enable_alarm(Alarm::FixedParity, on);
+                enable_alarm(Alarm::ErasableParity, on);
+                println!("Parity {}", if on {"on"} else {"off"});
+            }
+            Command::FAULT(fault) => if !inject_now(fault) {
+                println!("Too many faults");
+            }
             Command::FAIL => continue,
             Command::EXIT => break,
         }
@@ -20,9 +20,27 @@ use rp_pico::hal::pac;
 use rp_pico::hal;
 use hal::fugit::RateExtU32;
 use lcd_lcm1602_i2c;
+use agc_emulator::board::*;
+use agc_emulator::faults::*;
 use agc_emulator::memory::*;
 use agc_emulator::instructions::decode;
 use agc_emulator::instructions::execute;
+use agc_emulator::pacing::*;
+use agc_emulator::restart::*;
+use agc_emulator::rope::*;
+use agc_emulator::standby::*;
+
+// Host clock for the pacer, backed by the RP2040's microsecond timer
+struct PicoClock(hal::Timer);
+impl Clock for PicoClock {
+    fn now_us(&mut self) -> u64 {
+        self.0.get_counter().ticks()
+    }
+
+    fn sleep_us(&mut self, us: u64) {
+        self.0.delay_us(us as u32);
+    }
+}
 
 #[panic_handler]
 fn panic_handler(_info: &PanicInfo) -> ! {
@@ -73,25 +91,6 @@ const SCAN_LIMIT: u16 = 0xb00;
 const SHUTDOWN: u16 = 0xc00;
 const TEST: u16 = 0xf00;
 
-// Addresses of peripherals
-macro_rules! register {
-    ($name:ident, $value:literal) => {
-        const $name: ErasableAddress = $value;
-    };
-}
-register!(ACC, 0);
-register!(PANT, 256);
-register!(BTNUP, 264);
-register!(BTNRGT, 265);
-register!(BTNDWN, 266);
-register!(BTNLFT, 267);
-register!(BTN1, 268);
-register!(BTN2, 269);
-register!(POTE, 270);
-register!(CORTO, 271);
-register!(MEDIO, 272);
-register!(LARGO, 273);
-
 #[entry]
 fn entry() -> ! {
     let mut p = pac::Peripherals::take().unwrap();
@@ -111,6 +110,7 @@ fn entry() -> ! {
     let sio = hal::Sio::new(p.SIO);
     let pins = rp_pico::Pins::new(p.IO_BANK0, p.PADS_BANK0, sio.gpio_bank0, &mut p.RESETS);
     let mut timer = hal::Timer::new(p.TIMER, &mut p.RESETS, &clocks);
+    let mut pacer = Pacer::new(PicoClock(timer), Speed::Authentic);
     
 
     // Internal LED
@@ -185,9 +185,27 @@ fn entry() -> ! {
     let mut pulsedcfg: bool = false;
     let mut imp: bool = true;
     let mut executing: bool = false;
-    let mut address = 256;
+    let mut address = PERIPHERALS;
     let mut pulsedup: bool = false;
     let mut pulsedown: bool = false;
+    let mut sleeping: bool = false;
+    // The rope flashed with the assembler's HEX or UF2 output runs instead of the one built in. One that
+    // doesn't pass the checks isn't run at all, it has to be flashed again
+    let flash = unsafe { from_raw_parts(FLASH_ADDRESS as *const u8, FLASH_LEN) };
+    match Rope::read(flash) {
+        Ok(rope) => rope.load(),
+        Err(RopeError::NoRope) => (),
+        Err(_) => {
+            lcd.clear();
+            lcd.write_str("BAD ROPE");
+            loop {}
+        }
+    }
+    // Words carry parity like the real rope, a failed check restarts the program and lights the LED
+    MEMORY.set_parity(Parity::Generated);
+    enable_alarm(Alarm::FixedParity, true);
+    enable_alarm(Alarm::ErasableParity, true);
+    power_on();
     loop {
         macro_rules! update_btn {
             ($name:ident, $addr:expr) => {
@@ -207,6 +225,21 @@ fn entry() -> ! {
         let reading: u16 = adc.read(&mut potentiometer).unwrap();
         let reading = reading & 4095;
         MEMORY.write(POTE, reading);
+        // Holding both buttons works as the PRO key
+        press_pro(btn1.is_high().unwrap() && btn2.is_high().unwrap());
+
+        // In standby the board sleeps: the matrix is turned off and the emulator only keeps time, paced by
+        // the timer so the core spends most of it waiting. Memory is untouched until PRO wakes it up
+        if in_standby() != sleeping {
+            sleeping = in_standby();
+            sendto_matrix!(SHUTDOWN + if sleeping {0} else {1});
+            lcd.clear();
+            lcd.write_str(if sleeping {"STANDBY"} else {"GOJAM"});
+        }
+        if sleeping {
+            pacer.pace(execute(MEMORY.read(MEMORY.read(Z))));
+            continue;
+        }
         
         macro_rules! print_lcd {
             ($mode: literal) => {
@@ -236,6 +269,13 @@ fn entry() -> ! {
                 lcd.write_str(char(val));
             }
         }
+        // The internal LED is the RESTART light
+        if restart_light() {
+            led.set_high();
+        } else {
+            led.set_low();
+        }
+
         for i in 0..8 {   
            sendto_matrix!(16*16*(8-i) + (MEMORY.read(PANT+i) & SCREEN_MASK));
         }
@@ -313,6 +353,7 @@ fn entry() -> ! {
                 }
                 if btnclk.is_high().unwrap() && !pulsedclk{
                     executing = !executing;
+                    pacer.restart();
                     lcd.set_cursor(1, 0);
                     if executing {
                         lcd.write_str("    EJECUTANDO  ");
@@ -326,7 +367,7 @@ fn entry() -> ! {
                     pulsedclk = false;
                 }
                 if executing {
-                    execute(MEMORY.read(MEMORY.read(Z)));
+                    pacer.pace(execute(MEMORY.read(MEMORY.read(Z))));
                 }
                 
             },
@@ -337,11 +378,26 @@ fn entry() -> ! {
                     imp = false;
                 }
                 if btnclk.is_high().unwrap() && !pulsedclk{
-                    hal::reset();
+                    // Restarts the program keeping erasable memory, and works as ERROR RESET for the light
+                    gojam(Restart::Manual);
+                    clear_restart_light();
+                    lcd.set_cursor(1, 0);
+                    lcd.write_str("GOJAM");
+                    pulsedclk = true;
                 }
                 if btnclk.is_low().unwrap() {
                     pulsedclk = false;
                 }
+                // Corrupts the parity of the next instruction, to see the alarm the next time it runs
+                if btnup.is_high().unwrap() && !pulsedup {
+                    inject_now(Fault::FlipBit { address: MEMORY.read(Z), bit: 16 });
+                    lcd.set_cursor(1, 0);
+                    lcd.write_str("PARIDAD");
+                    pulsedup = true;
+                }
+                if btnup.is_low().unwrap() {
+                    pulsedup = false;
+                }
             },
             Modes::MEM => {
                 if btndwn.is_high().unwrap() && !pulsedown {
@@ -351,7 +407,7 @@ fn entry() -> ! {
                     pulsedown = true;
                 }
                 if btnup.is_high().unwrap() && !pulsedup{
-                    if address > 256{
+                    if address > PERIPHERALS{
                        address = address - 1;
                     }
                     pulsedup = true;